@@ -1,6 +1,6 @@
 use crate::models;
 use memcache::Client;
-use models::DataResponse;
+use models::{DataResponse, SqlQuery};
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, to_string};
 use sha2::{Digest, Sha256};
@@ -41,6 +41,19 @@ pub fn hash_query_to_unique_id(query: &str) -> String {
     }
 }
 
+// Bound values are hashed as well, otherwise the same statement with different
+// filter values would share a cache entry. The sql is hashed as it is, operators and
+// quoted literals are what tell `price > ?` from `price < ?` apart
+pub fn hash_sql_query_to_unique_id(query: &SqlQuery) -> String {
+    let params = to_string(&query.params).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    //the length keeps the end of the sql from running into the params
+    hasher.update((query.sql.len() as u64).to_le_bytes());
+    hasher.update(query.sql.as_bytes());
+    hasher.update(params.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
 // Function to clean the cache if the limit is reached
 //{TODO}
 pub fn clean_cache_if_needed(cache_client: &Client) {
@@ -79,3 +92,36 @@ pub fn clean_cache_if_needed(cache_client: &Client) {
     //     println!("Number of Keys : {}",keys.len());
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sql_query(sql: &str) -> SqlQuery {
+        SqlQuery {
+            sql: sql.to_string(),
+            params: Vec::new(),
+            columns: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn statements_differing_only_in_symbols_get_different_keys() {
+        let statements = [
+            "select a from t where price > ?",
+            "select a from t where price < ?",
+            "select a from t where price >= ?",
+            "select a from t where price = ?",
+            "select a from t where price <> ?",
+            "select revenue + cost from t",
+            "select revenue - cost from t",
+            "select a from t where b = 'a b'",
+            "select a from t where b = 'ab'",
+        ];
+        let keys: std::collections::HashSet<String> = statements
+            .iter()
+            .map(|sql| hash_sql_query_to_unique_id(&sql_query(sql)))
+            .collect();
+        assert_eq!(keys.len(), statements.len());
+    }
+}
@@ -10,6 +10,12 @@ pub struct AppConfig {
     pub caching: CachingConfig,
     pub schema: SchemaConfig,
     pub authentication: OpenIDAuthentication,
+    #[serde(default)]
+    pub batch: BatchConfig,
+    #[serde(default)]
+    pub export: ExportConfig,
+    #[serde(default)]
+    pub compare: CompareConfig,
 }
 
 // Database configurations
@@ -41,6 +47,55 @@ pub struct SchemaConfig {
     pub schema_file: String,
 }
 
+// Limits of the /api/batch endpoint
+#[derive(Deserialize,Clone)]
+#[serde(default)]
+pub struct BatchConfig {
+    // requests accepted in a single batch
+    pub max_queries: usize,
+    // requests of a batch running against the database at the same time
+    pub max_concurrency: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_queries: 50,
+            max_concurrency: 4,
+        }
+    }
+}
+
+// Limits of the /api/export endpoint
+#[derive(Deserialize,Clone)]
+#[serde(default)]
+pub struct ExportConfig {
+    // rows an export returns at most, the request's limit can only lower it. xlsx and parquet
+    // exports hold all of their rows in memory, so this also bounds their memory
+    pub max_rows: i32,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig { max_rows: 100_000 }
+    }
+}
+
+// Limits of compared requests
+#[derive(Deserialize,Clone)]
+#[serde(default)]
+pub struct CompareConfig {
+    // rows of the earlier period fetched at most, rows of the page beyond them get no previous
+    // values
+    pub max_rows: i32,
+}
+
+impl Default for CompareConfig {
+    fn default() -> Self {
+        CompareConfig { max_rows: 10_000 }
+    }
+}
+
 #[derive(Deserialize,Clone)]
 pub struct OpenIDAuthentication {
     pub authenticate: bool,
@@ -1,25 +1,86 @@
-use crate::db::DBPool;
-use crate::models::{AttributeValue,Column,DataResponse};
+use crate::db::{DBPool, RowHandler};
+use crate::models::{AttributeValue,Column,DataResponse,SqlParam,SqlQuery};
 use crate::db_utils::PersistenceError;
-use clickhouse_readonly::{ClickhouseResult, Pool, PoolConfigBuilder};
+use crate::query_engine::{base_datatype, temporal_kind, value_kind, TemporalKind, ValueKind};
+use clickhouse_readonly::{Block, ClickhouseResult, Pool, PoolConfigBuilder};
+use chrono::NaiveDate;
+use uuid::Uuid;
 
 
 use futures_util::StreamExt;
 use std::collections::HashMap;
-use std::borrow::Cow;
 
 pub static TABLE_QUERY: &str = "SELECT name
 FROM system.tables
 WHERE database = currentDatabase();
 ";
 
-pub fn column_query(table_name: &str)-> String {
-    format!(
-        "SELECT name AS column_name, type AS data_type
+pub fn column_query(table_name: &str)-> SqlQuery {
+    SqlQuery {
+        sql: "SELECT name AS column_name, type AS data_type
         FROM system.columns
-        WHERE database = currentDatabase() AND table = '{}';",
-        table_name)
+        WHERE database = currentDatabase() AND table = ?;".to_string(),
+        params: vec![SqlParam::String(table_name.to_string())],
+        columns: Vec::new(),
+    }
+}
+
+// The native protocol client has no server side parameters, so the `?` placeholders are
+// replaced by escaped literals here (the same way the official clickhouse client binds values).
+// Placeholders inside quoted strings or identifiers are left alone, there has to be exactly one
+// value per placeholder.
+pub fn bind_params(sql: &str, params: &[SqlParam]) -> Result<String, PersistenceError> {
+    let mut bound_sql = String::with_capacity(sql.len());
+    let mut remaining = params.iter();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+
+    for c in sql.chars() {
+        match quote {
+            Some(open) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == open {
+                    quote = None;
+                }
+                bound_sql.push(c);
+            }
+            None => match c {
+                '\'' | '"' | '`' => {
+                    quote = Some(c);
+                    bound_sql.push(c);
+                }
+                '?' => match remaining.next() {
+                    Some(param) => bound_sql.push_str(&param_to_literal(param)),
+                    None => return Err(param_count_error(sql, params)),
+                },
+                _ => bound_sql.push(c),
+            },
+        }
+    }
+    if remaining.next().is_some() {
+        return Err(param_count_error(sql, params));
+    }
+    Ok(bound_sql)
+}
 
+fn param_count_error(sql: &str, params: &[SqlParam]) -> PersistenceError {
+    let message = format!("{} values to bind to {}", params.len(), sql);
+    log::error!("{}", message);
+    PersistenceError::ParamCount(message)
+}
+
+fn param_to_literal(param: &SqlParam) -> String {
+    match param {
+        SqlParam::String(value) => format!(
+            "'{}'",
+            value.replace('\\', "\\\\").replace('\'', "\\'")
+        ),
+        SqlParam::Int(value) => value.to_string(),
+        SqlParam::Float(value) => value.to_string(),
+    }
 }
 
 pub fn clickhouse_pool_builder(db_user:&str,db_password:&str,db_host:&str,db_port:&u16,db_name:&str) -> Pool{
@@ -45,53 +106,209 @@ pub fn get_clickhouse_pool(dbpool: &DBPool) -> Option<&Pool> {
 
 pub async fn run_query_clickhouse(
     column_headers: &Vec<String>,
-    query: &String,
+    query: &SqlQuery,
     pool: DBPool,
 ) -> Result<DataResponse, PersistenceError>{
-    log::info!("Executing Clickhouse Query");
-    println!("{}",query);
-    let mut hash_map: HashMap<String, AttributeValue> = HashMap::new();
     let mut hash_maps: Vec<HashMap<String, AttributeValue>> = Vec::new();
+    for_each_row_clickhouse(column_headers, query, pool, &mut |hash_map| {
+        hash_maps.push(hash_map);
+        Ok(())
+    })
+    .await?;
+    Ok(DataResponse::new(hash_maps))
+}
+
+// the rows are handed over block by block as the server sends them
+pub async fn for_each_row_clickhouse(
+    column_headers: &Vec<String>,
+    query: &SqlQuery,
+    pool: DBPool,
+    on_row: &mut RowHandler<'_>,
+) -> Result<(), PersistenceError>{
+    log::info!("Executing Clickhouse Query");
+    let sql = bind_params(&query.sql, &query.params)?;
+    log::debug!("{}", sql);
     if let Some(clickhouse_pool) = get_clickhouse_pool(&pool) {
         let mut handle = clickhouse_pool.get_handle().await?;
-        let mut stream = handle.query(query).stream();
-    
-        while let Some(row) = stream.next().await {
-            
-            let row = row?;
-            for index in 0..row.len(){
-                if let Some(key) = column_headers.get(index) {
-                    let col_name = row.name(index)?;
-                    let col_type = row.sql_type(index)?;
-                    match col_type.to_string(){
-                        Cow::Borrowed("String") => {
-                            let val: String = row.get(index)?;
-                            hash_map.insert(
-                                key.to_string(),
-                                AttributeValue::String(val)
-                            );
-                        },
-                        
-                        Cow::Borrowed("Int8") | Cow::Borrowed("Int16") | Cow::Borrowed("Int32") | Cow::Borrowed("Int64") => {
-                            let val: i64 = row.get(index)?;
-                            hash_map.insert(
-                                key.to_string(),
-                                AttributeValue::Float(val as f32),
-                            );
-                        },
-                        _ => {let _ = String::new();}
-                        
-                    }
-
-                }    
-                
+        let mut blocks = handle.query(sql).stream_blocks();
+
+        while let Some(block) = blocks.next().await {
+            let block = block?;
+            for row in 0..block.row_count() {
+                let mut hash_map: HashMap<String, AttributeValue> = HashMap::new();
+                for (index, key) in column_headers
+                    .iter()
+                    .enumerate()
+                    .take(block.column_count())
+                {
+                    let datatype = query
+                        .columns
+                        .get(index)
+                        .map(|column| column.datatype.as_str());
+                    hash_map.insert(
+                        key.to_string(),
+                        clickhouse_value_to_attribute(&block, row, index, datatype)?,
+                    );
+                }
+                on_row(hash_map)?;
             }
-            hash_maps.push(hash_map.clone());
         }
-    
-        return Ok(DataResponse { data: hash_maps });
+
+        return Ok(());
     }
     else{
         Err(PersistenceError::Unknown)
     }
-}
\ No newline at end of file
+}
+
+//the cell as `$type`, None for the nulls of Nullable(..) columns
+macro_rules! read_cell {
+    ($block:expr, $row:expr, $column:expr, $nullable:expr, $type:ty) => {
+        if $nullable {
+            $block.get::<Option<$type>, _>($row, $column)?
+        } else {
+            Some($block.get::<$type, _>($row, $column)?)
+        }
+    };
+}
+
+// the types the native client reads. Everything else was converted to text by the query
+// (see Dialect::readable_column) and gets its type back from the column's `datatype`
+fn clickhouse_value_to_attribute(
+    block: &Block,
+    row: usize,
+    column: usize,
+    datatype: Option<&str>,
+) -> ClickhouseResult<AttributeValue> {
+    let sql_type = block.get_column(column)?.sql_type().to_string();
+    let (nullable, sql_type) = match sql_type
+        .strip_prefix("Nullable(")
+        .and_then(|inner| inner.strip_suffix(')'))
+    {
+        Some(inner) => (true, inner.to_string()),
+        None => (false, sql_type.to_string()),
+    };
+    let value = match sql_type.as_str() {
+        "UInt8" => read_cell!(block, row, column, nullable, u8)
+            .map(|value| AttributeValue::Int(value.into())),
+        "UInt16" => read_cell!(block, row, column, nullable, u16)
+            .map(|value| AttributeValue::Int(value.into())),
+        "UInt32" => read_cell!(block, row, column, nullable, u32)
+            .map(|value| AttributeValue::Int(value.into())),
+        "UInt64" => {
+            read_cell!(block, row, column, nullable, u64).map(|value| match i64::try_from(value) {
+                Ok(value) => AttributeValue::Int(value),
+                Err(_) => AttributeValue::Decimal(value.to_string()),
+            })
+        }
+        "Int8" => read_cell!(block, row, column, nullable, i8)
+            .map(|value| AttributeValue::Int(value.into())),
+        "Int16" => read_cell!(block, row, column, nullable, i16)
+            .map(|value| AttributeValue::Int(value.into())),
+        "Int32" => read_cell!(block, row, column, nullable, i32)
+            .map(|value| AttributeValue::Int(value.into())),
+        "Int64" => read_cell!(block, row, column, nullable, i64).map(AttributeValue::Int),
+        "Float32" => read_cell!(block, row, column, nullable, f32)
+            .map(|value| AttributeValue::Float(value.into())),
+        "Float64" => read_cell!(block, row, column, nullable, f64).map(AttributeValue::Float),
+        "String" => read_cell!(block, row, column, nullable, &[u8])
+            .map(|bytes| text_to_attribute(String::from_utf8_lossy(bytes).into_owned(), datatype)),
+        //padded with zero bytes up to its length
+        fixed if fixed.starts_with("FixedString(") => {
+            read_cell!(block, row, column, nullable, &[u8]).map(|bytes| {
+                AttributeValue::String(
+                    String::from_utf8_lossy(bytes)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                )
+            })
+        }
+        "Array(UInt8)" => Some(json_array(block.get::<Vec<u8>, _>(row, column)?)),
+        "Array(UInt16)" => Some(json_array(block.get::<Vec<u16>, _>(row, column)?)),
+        "Array(UInt32)" => Some(json_array(block.get::<Vec<u32>, _>(row, column)?)),
+        "Array(UInt64)" => Some(json_array(block.get::<Vec<u64>, _>(row, column)?)),
+        "Array(Int8)" => Some(json_array(block.get::<Vec<i8>, _>(row, column)?)),
+        "Array(Int16)" => Some(json_array(block.get::<Vec<i16>, _>(row, column)?)),
+        "Array(Int32)" => Some(json_array(block.get::<Vec<i32>, _>(row, column)?)),
+        "Array(Int64)" => Some(json_array(block.get::<Vec<i64>, _>(row, column)?)),
+        "Array(Float32)" => Some(json_array(block.get::<Vec<f32>, _>(row, column)?)),
+        "Array(Float64)" => Some(json_array(block.get::<Vec<f64>, _>(row, column)?)),
+        "Array(String)" => Some(json_array(block.get::<Vec<String>, _>(row, column)?)),
+        other => {
+            log::error!("Unsupported clickhouse type {}", other);
+            None
+        }
+    };
+    Ok(value.unwrap_or(AttributeValue::NULL))
+}
+
+fn json_array<T: serde::Serialize>(values: Vec<T>) -> AttributeValue {
+    AttributeValue::Json(serde_json::to_value(values).unwrap_or_default())
+}
+
+//text read from a String column, typed by the datatype of the result column
+fn text_to_attribute(text: String, datatype: Option<&str>) -> AttributeValue {
+    let datatype = match datatype {
+        Some(datatype) => datatype,
+        None => return AttributeValue::String(text),
+    };
+    match temporal_kind(datatype) {
+        Some(TemporalKind::Date) => {
+            return match NaiveDate::parse_from_str(&text, "%Y-%m-%d") {
+                Ok(date) => AttributeValue::Date(date),
+                Err(_) => AttributeValue::String(text),
+            }
+        }
+        Some(TemporalKind::Timestamp | TemporalKind::TimestampTz) => {
+            return AttributeValue::Timestamp(text)
+        }
+        None => {}
+    }
+    match (value_kind(datatype), base_datatype(datatype).as_str()) {
+        (_, "decimal" | "numeric") => AttributeValue::Decimal(text),
+        //128 and 256 bit integers that don't fit are kept as their digits
+        (ValueKind::Integer, _) | (_, "int128" | "int256" | "uint128" | "uint256") => {
+            match text.parse() {
+                Ok(value) => AttributeValue::Int(value),
+                Err(_) => AttributeValue::Decimal(text),
+            }
+        }
+        (ValueKind::Float, _) => match text.parse() {
+            Ok(value) => AttributeValue::Float(value),
+            Err(_) => AttributeValue::String(text),
+        },
+        (ValueKind::Boolean, _) => AttributeValue::Bool(text == "true" || text == "1"),
+        (_, "uuid") => match Uuid::parse_str(&text) {
+            Ok(uuid) => AttributeValue::Uuid(uuid),
+            Err(_) => AttributeValue::String(text),
+        },
+        _ => AttributeValue::String(text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_inside_literals_are_left_alone() {
+        let sql = "select 'a ? b', `c?` from t where x = ? and y = 'O\\'Reilly ?' and z = ?";
+        let params = vec![
+            SqlParam::String("O'Reilly \\' ? -- ;".to_string()),
+            SqlParam::Int(2),
+        ];
+        assert_eq!(
+            bind_params(sql, &params).unwrap(),
+            "select 'a ? b', `c?` from t where x = 'O\\'Reilly \\\\\\' ? -- ;' and y = 'O\\'Reilly ?' and z = 2"
+        );
+    }
+
+    #[test]
+    fn param_counts_have_to_match_the_placeholders() {
+        let too_few = bind_params("select ? + ?", &[SqlParam::Int(1)]);
+        assert!(matches!(too_few, Err(PersistenceError::ParamCount(_))));
+        let too_many = bind_params("select ?", &[SqlParam::Int(1), SqlParam::Int(2)]);
+        assert!(matches!(too_many, Err(PersistenceError::ParamCount(_))));
+        assert!(bind_params("select '?'", &[]).is_ok());
+    }
+}
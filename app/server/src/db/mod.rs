@@ -4,7 +4,7 @@ mod postgres;
 mod mysql_db;
 mod clickhouse_db;
 
-use crate::{models::{AttributeValue, Column, DataRequest, DataResponse, Table}};
+use crate::{models::{AttributeValue, Column, DataRequest, DataResponse, SqlQuery, Table}};
 use mysql::prelude::Queryable;
 use memcache::Client;
 use crate::db_utils::PersistenceError;
@@ -13,9 +13,9 @@ use log::error;
 use std::fmt;
 
 // use clickhouse::{Client as ClickhouseClient};
-use postgres::{postgres_pool_builder,run_query_postgres};
-use mysql_db::{mysql_pool_builder,run_query_mysql};
-use clickhouse_db::{clickhouse_pool_builder,run_query_clickhouse};
+use postgres::{for_each_row_postgres,postgres_pool_builder,run_query_postgres};
+use mysql_db::{for_each_row_mysql,mysql_pool_builder,named_time_zones_loaded,run_query_mysql};
+use clickhouse_db::{clickhouse_pool_builder,for_each_row_clickhouse,run_query_clickhouse};
 
 // use tokio::runtime;
 
@@ -54,6 +54,14 @@ impl DBPool{
     }
 }
 
+// whether the database can convert timestamps into named zones, see Dialect::to_timezone
+pub fn supports_named_time_zones(pool: &DBPool) -> bool {
+    match pool {
+        DBPool::mysql(mysql_pool) => named_time_zones_loaded(mysql_pool),
+        _ => true,
+    }
+}
+
 pub fn pool_builder(db_type:&str,db_user:&str,db_password:&str,db_host:&str,db_port:&u16,db_name:&str) -> Result<DBPool, String>{
     match db_type {
         "postgres" => {
@@ -78,7 +86,7 @@ pub fn pool_builder(db_type:&str,db_user:&str,db_password:&str,db_host:&str,db_p
 
 pub async fn run_query(
     column_headers: &Vec<String>,
-    query: &String,
+    query: &SqlQuery,
     pool: DBPool,
     db_type: &str,
 ) -> Result<DataResponse, PersistenceError> {
@@ -99,31 +107,47 @@ pub async fn run_query(
         _ => {
             error!("Unsupported database type: {}", db_type);
             let response_data = Vec::<HashMap<String, AttributeValue>>::new();
-            Ok(DataResponse { data: response_data })
+            Ok(DataResponse::new(response_data))
         }
     }
     // Err(PersistenceError::Unknown)
 }
 
+// takes the rows of a query one at a time, an error stops the query
+pub type RowHandler<'a> =
+    dyn FnMut(HashMap<String, AttributeValue>) -> Result<(), PersistenceError> + 'a;
 
-pub async fn fetch_all_tables(pool: &DBPool, db_type: &str) -> Result<Vec<String>, PersistenceError> {
-    let mut query = String::new();
+// runs the query and hands every row to `on_row` as the driver reads it, the rows aren't
+// collected
+pub async fn for_each_row(
+    column_headers: &Vec<String>,
+    query: &SqlQuery,
+    pool: DBPool,
+    db_type: &str,
+    on_row: &mut RowHandler<'_>,
+) -> Result<(), PersistenceError> {
     match db_type {
-        "mysql" => {
-            query = mysql_db::TABLE_QUERY.to_string();
-        }
-        "postgres" => {
-            query = postgres::TABLE_QUERY.to_string();
-        }
-        "clickhouse" => {
-            query = clickhouse_db::TABLE_QUERY.to_string();
+        "mysql" => for_each_row_mysql(column_headers, query, pool, on_row).await,
+        "postgres" => for_each_row_postgres(column_headers, query, pool, on_row).await,
+        "clickhouse" => for_each_row_clickhouse(column_headers, query, pool, on_row).await,
+        _ => {
+            error!("Unsupported database type: {}", db_type);
+            Ok(())
         }
-        _ => return Err(PersistenceError::Unknown),
     }
+}
+
+
+pub async fn fetch_all_tables(pool: &DBPool, db_type: &str) -> Result<Vec<String>, PersistenceError> {
+    let query = match db_type {
+        "mysql" => SqlQuery::new(mysql_db::TABLE_QUERY),
+        "postgres" => SqlQuery::new(postgres::TABLE_QUERY),
+        "clickhouse" => SqlQuery::new(clickhouse_db::TABLE_QUERY),
+        _ => return Err(PersistenceError::Unknown),
+    };
 
-    let sample_query: &String = &String::from(query);
     let column_headers: Vec<String> = vec![String::from("table_name")];
-    let table_data_response = run_query(&column_headers,sample_query,pool.clone(),db_type).await?;
+    let table_data_response = run_query(&column_headers,&query,pool.clone(),db_type).await?;
     let table_names: Vec<String> = table_data_response.data
     .iter()
     .filter_map(|hash_map| hash_map.get("table_name"))
@@ -138,23 +162,15 @@ pub async fn fetch_all_tables(pool: &DBPool, db_type: &str) -> Result<Vec<String
 
 
 pub async fn fetch_columns_for_table(pool: &DBPool, table_name: &str,db_type:&str) -> Result<Vec<Column>, PersistenceError> {
-    let mut query = String::new();
-    match db_type {
-        "mysql" => {
-            query = mysql_db::column_query(table_name);
-        }
-        "postgres" => {
-            query = postgres::column_query(table_name);
-        }
-        "clickhouse" => {
-            query = clickhouse_db::column_query(table_name);
-        }
+    let query = match db_type {
+        "mysql" => mysql_db::column_query(table_name),
+        "postgres" => postgres::column_query(table_name),
+        "clickhouse" => clickhouse_db::column_query(table_name),
         _ => return Err(PersistenceError::Unknown),
-    }
+    };
 
-        let sample_query: &String = &String::from(query);
     let column_headers: Vec<String> = vec![String::from("column_name"),String::from("column_type")];
-    let column_data_response = run_query(&column_headers,sample_query,pool.clone(),db_type).await?;
+    let column_data_response = run_query(&column_headers,&query,pool.clone(),db_type).await?;
     let columns: Vec<Column> = column_data_response
         .data
         .iter()
@@ -1,16 +1,20 @@
 use std::collections::HashMap;
-use crate::models::{AttributeValue,Column,DataResponse};
-use crate::db::DBPool;
+use crate::models::{AttributeValue,Column,DataResponse,SqlParam,SqlQuery};
+use crate::db::{DBPool, RowHandler};
 use crate::db::PersistenceError;
 use mysql::prelude::Queryable;
-use mysql::from_value_opt;
+use mysql::consts::ColumnType;
+use mysql::Value;
+use chrono::NaiveDate;
 
 pub static TABLE_QUERY: &str = "SELECT table_name FROM information_schema.tables WHERE table_schema = DATABASE()";
 
-pub fn column_query(table_name: &str)-> String {
-    format!(
-        "SELECT column_name, data_type FROM information_schema.columns WHERE table_schema = DATABASE() AND table_name = '{}'",
-        table_name)
+pub fn column_query(table_name: &str)-> SqlQuery {
+    SqlQuery {
+        sql: "SELECT column_name, data_type FROM information_schema.columns WHERE table_schema = DATABASE() AND table_name = ?".to_string(),
+        params: vec![SqlParam::String(table_name.to_string())],
+        columns: Vec::new(),
+    }
 }
 
 pub fn mysql_pool_builder(db_user:&str,db_password:&str,db_host:&str,db_port:&u16,db_name:&str) -> mysql::Pool{
@@ -24,6 +28,25 @@ pub fn mysql_pool_builder(db_user:&str,db_password:&str,db_host:&str,db_port:&u1
     let pool = mysql::Pool::new(builder).unwrap();
     pool
 }
+// CONVERT_TZ returns null for named zones until the time zone tables are loaded into the server
+// (mysql_tzinfo_to_sql), which leaves every timestamp of a request with a timezone null
+pub fn named_time_zones_loaded(pool: &mysql::Pool) -> bool {
+    let converted = pool.get_conn().and_then(|mut conn| {
+        conn.query_first::<Option<String>, _>(
+            "SELECT CAST(CONVERT_TZ('2000-01-01 00:00:00', '+00:00', 'Europe/Berlin') AS CHAR)",
+        )
+    });
+    match converted {
+        Ok(Some(Some(_))) => true,
+        Ok(_) => false,
+        //the server can't be reached yet, requests will fail on their own
+        Err(err) => {
+            log::error!("Could not check the MySQL time zone tables: {}", err);
+            true
+        }
+    }
+}
+
 pub fn get_mysql_pool(dbpool: &DBPool) -> Option<&mysql::Pool> {
     match dbpool {
         DBPool::mysql(mysql_pool) => Some(mysql_pool),
@@ -38,58 +61,123 @@ pub fn sql_row_to_hash_map(
     let mut hash_map: HashMap<String, AttributeValue> = HashMap::new();
 
     for (index, column) in row.columns_ref().iter().enumerate() {
-        if let Some(Ok(value)) = row.get_opt(index) {
+        if let Some(Ok(value)) = row.get_opt::<Value, _>(index) {
             if let Some(key) = column_headers.get(index) {
-                let value_as_float = from_value_opt::<f32>(value);
-
-                match value_as_float {
-                    Ok(float_value) => {
-                        hash_map.insert(
-                            key.to_string(),
-                            AttributeValue::Float(round_float_decimals(&float_value)),
-                        );
-                    }
-                    Err(error) => {
-                        hash_map.insert(
-                            key.to_string(),
-                            AttributeValue::String(
-                                from_value_opt::<String>(error.0)
-                                    .unwrap_or_else(|_| "NULL".to_string()),
-                            ),
-                        );
-                    }
-                }
+                hash_map.insert(key.to_string(), mysql_value_to_attribute(column, value));
             }
-        } else {
         }
     }
 
     hash_map
 }
 
-fn round_float_decimals(value: &f32) -> f32 {
-    (value * 100.0).round() / 100.0
+//prepared statements return typed values, decimals and json still arrive as their text
+fn mysql_value_to_attribute(column: &mysql::Column, value: Value) -> AttributeValue {
+    let column_type = column.column_type();
+    match value {
+        Value::NULL => AttributeValue::NULL,
+        //BOOL is an alias of TINYINT(1)
+        Value::Int(int_value)
+            if column_type == ColumnType::MYSQL_TYPE_TINY && column.column_length() == 1 =>
+        {
+            AttributeValue::Bool(int_value != 0)
+        }
+        Value::Int(int_value) => AttributeValue::Int(int_value),
+        Value::UInt(uint_value) => match i64::try_from(uint_value) {
+            Ok(int_value) => AttributeValue::Int(int_value),
+            Err(_) => AttributeValue::Decimal(uint_value.to_string()),
+        },
+        Value::Float(float_value) => AttributeValue::Float(f64::from(float_value)),
+        Value::Double(double_value) => AttributeValue::Float(double_value),
+        Value::Bytes(bytes) => {
+            let text = String::from_utf8_lossy(&bytes).into_owned();
+            match column_type {
+                ColumnType::MYSQL_TYPE_DECIMAL | ColumnType::MYSQL_TYPE_NEWDECIMAL => {
+                    AttributeValue::Decimal(text)
+                }
+                ColumnType::MYSQL_TYPE_JSON => match serde_json::from_str(&text) {
+                    Ok(json) => AttributeValue::Json(json),
+                    Err(_) => AttributeValue::String(text),
+                },
+                _ => AttributeValue::String(text),
+            }
+        }
+        Value::Date(year, month, day, _, _, _, _)
+            if column_type == ColumnType::MYSQL_TYPE_DATE =>
+        {
+            match NaiveDate::from_ymd_opt(i32::from(year), u32::from(month), u32::from(day)) {
+                Some(date) => AttributeValue::Date(date),
+                //zero dates such as 0000-00-00
+                None => AttributeValue::NULL,
+            }
+        }
+        Value::Date(year, month, day, hour, minute, second, micros) => {
+            let mut timestamp = format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                year, month, day, hour, minute, second
+            );
+            if micros > 0 {
+                timestamp += &format!(".{:06}", micros);
+            }
+            AttributeValue::Timestamp(timestamp)
+        }
+        Value::Time(negative, days, hours, minutes, seconds, _) => AttributeValue::String(format!(
+            "{}{:02}:{:02}:{:02}",
+            if negative { "-" } else { "" },
+            days * 24 + hours as u32,
+            minutes,
+            seconds
+        )),
+    }
 }
 
 pub async fn run_query_mysql(
     column_headers: &Vec<String>,
-    query: &String,
+    query: &SqlQuery,
     pool: DBPool,
 ) -> Result<DataResponse, PersistenceError>{
+    let mut response_data = Vec::new();
+    for_each_row_mysql(column_headers, query, pool, &mut |hash_map| {
+        response_data.push(hash_map);
+        Ok(())
+    })
+    .await?;
+    Ok(DataResponse::new(response_data))
+}
+
+// the rows are read from the connection one at a time
+pub async fn for_each_row_mysql(
+    column_headers: &Vec<String>,
+    query: &SqlQuery,
+    pool: DBPool,
+    on_row: &mut RowHandler<'_>,
+) -> Result<(), PersistenceError>{
     if let Some(mysql_pool) = get_mysql_pool(&pool) {
         let mut conn = match mysql_pool.get_conn() {
             Ok(conn) => conn,
             Err(err) => return Err(PersistenceError::MysqlError(err)),
         };
 
-        let response_data = match conn.query_map(query, |row: mysql::Row| {
-            sql_row_to_hash_map(column_headers, &row)
-        }) {
-            Ok(response_data) => response_data,
+        let params: Vec<Value> = query
+            .params
+            .iter()
+            .map(|param| match param {
+                SqlParam::String(value) => Value::from(value),
+                SqlParam::Int(value) => Value::from(value),
+                SqlParam::Float(value) => Value::from(value),
+            })
+            .collect();
+
+        let rows = match conn.exec_iter(&query.sql, params) {
+            Ok(rows) => rows,
             Err(err) => return Err(PersistenceError::MysqlError(err)),
         };
+        for row in rows {
+            let row = row.map_err(PersistenceError::MysqlError)?;
+            on_row(sql_row_to_hash_map(column_headers, &row))?;
+        }
 
-        return Ok(DataResponse { data: response_data });
+        return Ok(());
     }
     else{
         Err(PersistenceError::Unknown)
@@ -1,13 +1,18 @@
-use crate::db::DBPool;
-use crate::models::{AttributeValue,Column,DataResponse};
+use crate::db::{DBPool, RowHandler};
+use crate::models::{AttributeValue,Column,DataResponse,SqlParam,SqlQuery};
 use crate::db_utils::PersistenceError;
 
 use std::collections::HashMap;
+use std::error::Error;
 use uuid::Uuid;
 
 use deadpool_postgres::{Config, Manager, ManagerConfig, Pool, RecyclingMethod, Runtime};
 use tokio_postgres::{NoTls,Row};
-use chrono::NaiveDateTime;
+use tokio_postgres::types::{to_sql_checked, Format, FromSql, IsNull, Kind, ToSql, Type};
+use bytes::BytesMut;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use std::net::IpAddr;
+use futures_util::StreamExt;
 
 pub static TABLE_QUERY: &str = "SELECT table_name
 FROM information_schema.tables
@@ -17,11 +22,301 @@ WHERE table_schema = current_schema()
   AND table_name NOT IN ('geography_columns', 'geometry_columns');
 ";
 
-pub fn column_query(table_name: &str)-> String {
-    format!(
-        "SELECT column_name, data_type  FROM information_schema.columns WHERE table_schema = current_schema() and table_name = '{}'",
-        table_name)
+pub fn column_query(table_name: &str)-> SqlQuery {
+    SqlQuery {
+        sql: "SELECT column_name, data_type  FROM information_schema.columns WHERE table_schema = current_schema() and table_name = $1".to_string(),
+        params: vec![SqlParam::String(table_name.to_string())],
+        columns: Vec::new(),
+    }
+}
+
+// Bound values are sent in the text format so that postgres parses them as whatever
+// type it inferred for the placeholder (int4, timestamp, uuid, ...)
+#[derive(Debug)]
+struct TextParam(String);
+
+impl ToSql for TextParam {
+    fn to_sql(&self, _ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.extend_from_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    fn encode_format(&self, _ty: &Type) -> Format {
+        Format::Text
+    }
+
+    to_sql_checked!();
+}
+
+// every column is read through PgValue, nulls included. Types without a mapping of their own
+// come back as their text
+struct PgValue(AttributeValue);
+
+type DecodeError = Box<dyn Error + Sync + Send>;
+
+impl<'a> FromSql<'a> for PgValue {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, DecodeError> {
+        pg_value_to_attribute(ty, raw).map(PgValue)
+    }
+
+    fn from_sql_null(_ty: &Type) -> Result<Self, DecodeError> {
+        Ok(PgValue(AttributeValue::NULL))
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+}
+
+fn pg_value_to_attribute(ty: &Type, raw: &[u8]) -> Result<AttributeValue, DecodeError> {
+    match ty.kind() {
+        Kind::Domain(base) => return pg_value_to_attribute(base, raw),
+        Kind::Array(element) => return array_to_attribute(element, raw),
+        _ => {}
+    }
+    let value = match *ty {
+        Type::BOOL => AttributeValue::Bool(bool::from_sql(ty, raw)?),
+        Type::INT2 => AttributeValue::Int(i64::from(i16::from_sql(ty, raw)?)),
+        Type::INT4 => AttributeValue::Int(i64::from(i32::from_sql(ty, raw)?)),
+        Type::INT8 => AttributeValue::Int(i64::from_sql(ty, raw)?),
+        Type::OID => AttributeValue::Int(i64::from(u32::from_sql(ty, raw)?)),
+        Type::FLOAT4 => AttributeValue::Float(f64::from(f32::from_sql(ty, raw)?)),
+        Type::FLOAT8 => AttributeValue::Float(f64::from_sql(ty, raw)?),
+        Type::NUMERIC => AttributeValue::Decimal(numeric_to_text(raw)?),
+        //cents, with the two decimals of the usual lc_monetary
+        Type::MONEY => {
+            let cents = i64::from_sql(&Type::INT8, raw)?;
+            let sign = if cents < 0 { "-" } else { "" };
+            let cents = cents.unsigned_abs();
+            AttributeValue::Decimal(format!("{}{}.{:02}", sign, cents / 100, cents % 100))
+        }
+        Type::UUID => AttributeValue::Uuid(Uuid::from_sql(ty, raw)?),
+        Type::DATE => match i32::from_sql(&Type::INT4, raw)? {
+            i32::MAX => AttributeValue::String("infinity".to_string()),
+            i32::MIN => AttributeValue::String("-infinity".to_string()),
+            _ => AttributeValue::Date(NaiveDate::from_sql(ty, raw)?),
+        },
+        Type::TIMESTAMP | Type::TIMESTAMPTZ => match i64::from_sql(&Type::INT8, raw)? {
+            i64::MAX => AttributeValue::String("infinity".to_string()),
+            i64::MIN => AttributeValue::String("-infinity".to_string()),
+            //ISO-8601 with the utc offset, shifted to the request's zone afterwards
+            _ if *ty == Type::TIMESTAMPTZ => {
+                AttributeValue::Timestamp(DateTime::<Utc>::from_sql(ty, raw)?.to_rfc3339())
+            }
+            _ => AttributeValue::Timestamp(NaiveDateTime::from_sql(ty, raw)?.to_string()),
+        },
+        Type::TIME => AttributeValue::String(NaiveTime::from_sql(ty, raw)?.to_string()),
+        Type::TIMETZ => AttributeValue::String(timetz_to_text(raw)?),
+        Type::INTERVAL => AttributeValue::String(interval_to_text(raw)?),
+        Type::JSON => AttributeValue::Json(serde_json::from_slice(raw)?),
+        //jsonb starts with a format version
+        Type::JSONB => match raw.split_first() {
+            Some((1, json)) => AttributeValue::Json(serde_json::from_slice(json)?),
+            _ => return Err("unsupported jsonb version".into()),
+        },
+        Type::CHAR => AttributeValue::String(char::from(i8::from_sql(ty, raw)? as u8).to_string()),
+        Type::INET | Type::CIDR => AttributeValue::String(inet_to_text(raw)?),
+        //text, varchar, bpchar, name, enums and extension types such as citext are sent as text
+        _ => match std::str::from_utf8(raw) {
+            Ok(text) if *ty != Type::BYTEA => AttributeValue::String(text.to_string()),
+            _ => AttributeValue::String(format!("\\x{}", hex::encode(raw))),
+        },
+    };
+    Ok(value)
+}
+
+//arrays become json arrays, nested once per dimension
+fn array_to_attribute(element: &Type, raw: &[u8]) -> Result<AttributeValue, DecodeError> {
+    let mut raw = raw;
+    let dimensions = read_i32(&mut raw)?;
+    //null flag and element oid
+    read_i32(&mut raw)?;
+    read_i32(&mut raw)?;
+    let mut lengths = Vec::new();
+    for _ in 0..dimensions {
+        lengths.push(read_i32(&mut raw)?.max(1) as usize);
+        //lower bound
+        read_i32(&mut raw)?;
+    }
+    let mut values = Vec::new();
+    while !raw.is_empty() {
+        let length = read_i32(&mut raw)?;
+        let value = match usize::try_from(length) {
+            Ok(length) if length <= raw.len() => {
+                let (value, rest) = raw.split_at(length);
+                raw = rest;
+                pg_value_to_attribute(element, value)?
+            }
+            Ok(_) => return Err("invalid array value".into()),
+            Err(_) => AttributeValue::NULL,
+        };
+        values.push(serde_json::to_value(&value)?);
+    }
+    //elements are listed with the last dimension varying fastest
+    for length in lengths.iter().skip(1).rev() {
+        values = values
+            .chunks(*length)
+            .map(|chunk| serde_json::Value::Array(chunk.to_vec()))
+            .collect();
+    }
+    Ok(AttributeValue::Json(serde_json::Value::Array(values)))
+}
+
+fn read_i32(raw: &mut &[u8]) -> Result<i32, DecodeError> {
+    if raw.len() < 4 {
+        return Err("invalid array value".into());
+    }
+    let (value, rest) = raw.split_at(4);
+    *raw = rest;
+    Ok(i32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+}
+
+//microseconds since midnight and the zone in seconds west of utc, as `10:30:00+02:00`
+fn timetz_to_text(raw: &[u8]) -> Result<String, DecodeError> {
+    if raw.len() != 12 {
+        return Err("invalid timetz value".into());
+    }
+    let micros = i64::from_sql(&Type::INT8, &raw[..8])?;
+    let zone = i32::from_sql(&Type::INT4, &raw[8..])?;
+    let time = NaiveTime::from_num_seconds_from_midnight_opt(
+        u32::try_from(micros / 1_000_000)?,
+        u32::try_from(micros % 1_000_000 * 1000)?,
+    )
+    .ok_or("invalid timetz value")?;
+    let offset = FixedOffset::west_opt(zone).ok_or("invalid timetz value")?;
+    Ok(format!("{}{}", time, offset))
+}
+
+//the way postgres prints intervals, `1 year 2 mons 3 days 04:05:06`. Only 1 is singular and a
+//part following a negative one is marked with `+`, `-1 days +02:00:00`
+fn interval_to_text(raw: &[u8]) -> Result<String, DecodeError> {
+    if raw.len() != 16 {
+        return Err("invalid interval value".into());
+    }
+    let micros = i64::from_sql(&Type::INT8, &raw[..8])?;
+    let days = i32::from_sql(&Type::INT4, &raw[8..12])?;
+    let months = i32::from_sql(&Type::INT4, &raw[12..])?;
+    let mut parts = Vec::new();
+    let mut after_negative = false;
+    for (count, name) in [(months / 12, "year"), (months % 12, "mon"), (days, "day")] {
+        if count == 0 {
+            continue;
+        }
+        let sign = if after_negative && count > 0 { "+" } else { "" };
+        let plural = if count == 1 { "" } else { "s" };
+        parts.push(format!("{}{} {}{}", sign, count, name, plural));
+        after_negative = count < 0;
+    }
+    if micros != 0 || parts.is_empty() {
+        let sign = match micros < 0 {
+            true => "-",
+            false if after_negative => "+",
+            false => "",
+        };
+        let micros = micros.unsigned_abs();
+        let seconds = micros / 1_000_000;
+        let mut time = format!(
+            "{}{:02}:{:02}:{:02}",
+            sign,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        );
+        if micros % 1_000_000 != 0 {
+            let fraction = format!("{:06}", micros % 1_000_000);
+            time = format!("{}.{}", time, fraction.trim_end_matches('0'));
+        }
+        parts.push(time);
+    }
+    Ok(parts.join(" "))
+}
 
+//family, netmask bits, cidr flag, address length and the address
+fn inet_to_text(raw: &[u8]) -> Result<String, DecodeError> {
+    let (header, address) = raw.split_at(raw.len().min(4));
+    let (bits, full_bits) = match (header, address.len()) {
+        ([_, bits, _, 4], 4) => (*bits, 32),
+        ([_, bits, _, 16], 16) => (*bits, 128),
+        _ => return Err("invalid inet value".into()),
+    };
+    let address = match <[u8; 4]>::try_from(address) {
+        Ok(octets) => IpAddr::from(octets),
+        Err(_) => IpAddr::from(<[u8; 16]>::try_from(address)?),
+    };
+    //cidr values always show their netmask, inet ones only when it isn't a single host
+    if header[2] == 1 || bits != full_bits {
+        Ok(format!("{}/{}", address, bits))
+    } else {
+        Ok(address.to_string())
+    }
+}
+
+// numeric columns read as their exact decimal text, there is no rust type without a
+// precision limit to read them into
+fn numeric_to_text(raw: &[u8]) -> Result<String, DecodeError> {
+    //binary format: digit count, weight of the first digit, sign, scale and then the
+    //digits in base 10000
+    let header = |position: usize| -> Result<u16, DecodeError> {
+        raw.get(position..position + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(|| "invalid numeric value".into())
+    };
+    let digit_count = header(0)? as usize;
+    let weight = header(2)? as i16 as i32;
+    let sign = header(4)?;
+    let scale = header(6)? as usize;
+    let digits = (0..digit_count)
+        .map(|position| header(8 + position * 2))
+        .collect::<Result<Vec<u16>, _>>()?;
+    match sign {
+        0xC000 => return Ok("NaN".to_string()),
+        0xD000 => return Ok("Infinity".to_string()),
+        0xF000 => return Ok("-Infinity".to_string()),
+        _ => {}
+    }
+    let digit = |position: i32| {
+        usize::try_from(position)
+            .ok()
+            .and_then(|position| digits.get(position))
+            .copied()
+            .unwrap_or(0)
+    };
+    let mut text = String::new();
+    if sign == 0x4000 {
+        text.push('-');
+    }
+    if weight < 0 {
+        text.push('0');
+    } else {
+        text += &digit(0).to_string();
+        for position in 1..=weight {
+            text += &format!("{:04}", digit(position));
+        }
+    }
+    if scale > 0 {
+        let mut fraction = String::new();
+        let mut position = weight + 1;
+        while fraction.len() < scale {
+            fraction += &format!("{:04}", digit(position));
+            position += 1;
+        }
+        fraction.truncate(scale);
+        text.push('.');
+        text += &fraction;
+    }
+    Ok(text)
+}
+
+fn to_text_param(param: &SqlParam) -> TextParam {
+    match param {
+        SqlParam::String(value) => TextParam(value.clone()),
+        SqlParam::Int(value) => TextParam(value.to_string()),
+        SqlParam::Float(value) => TextParam(value.to_string()),
+    }
 }
 
 
@@ -47,24 +342,38 @@ pub fn get_postgres_pool(dbpool: &DBPool) -> Option<&Pool> {
 
 pub async fn run_query_postgres(
     column_headers: &Vec<String>,
-    query: &String,
+    query: &SqlQuery,
     pool: DBPool,
 ) -> Result<DataResponse, PersistenceError>{
+    let mut hash_maps = Vec::new();
+    for_each_row_postgres(column_headers, query, pool, &mut |hash_map| {
+        hash_maps.push(hash_map);
+        Ok(())
+    })
+    .await?;
+    Ok(DataResponse::new(hash_maps))
+}
+
+// the rows are handed over as they arrive from the server
+pub async fn for_each_row_postgres(
+    column_headers: &Vec<String>,
+    query: &SqlQuery,
+    pool: DBPool,
+    on_row: &mut RowHandler<'_>,
+) -> Result<(), PersistenceError>{
     log::info!("Executing PostGres Query");
 
     if let Some(postgres_pool) = get_postgres_pool(&pool) {
 
-        let client = postgres_pool.get().await.unwrap();
-        let stmt = client.prepare_cached(query).await.unwrap();
-        let rows = client.query(&stmt, &[]).await.unwrap();
-        // let column_head: Vec<String> = vec!["id".to_string(), "title".to_string()];
-
-        let hash_maps: Vec<HashMap<String, AttributeValue>> = rows
-        .iter()
-        .map(|row| postgres_row_to_hash_map(&column_headers, row))
-        .collect();
-        let response_data = hash_maps;
-        return Ok(DataResponse { data: response_data });
+        let client = postgres_pool.get().await?;
+        let stmt = client.prepare_cached(&query.sql).await?;
+        let params: Vec<TextParam> = query.params.iter().map(to_text_param).collect();
+        let rows = client.query_raw(&stmt, params.iter()).await?;
+        futures_util::pin_mut!(rows);
+        while let Some(row) = rows.next().await {
+            on_row(postgres_row_to_hash_map(&column_headers, &row?))?;
+        }
+        return Ok(());
     }
     else{
         Err(PersistenceError::Unknown)
@@ -76,58 +385,125 @@ pub fn postgres_row_to_hash_map(
     row: &Row,
 ) -> HashMap<String, AttributeValue> {
     let mut hash_map: HashMap<String, AttributeValue> = HashMap::new();
-    let mut col_type = String::new();
-    let mut index = 0;
-    for column in row.columns().into_iter(){
-        if let Some(key) = column_names.get(index) {
-            index = index + 1;
-            col_type = column.type_().to_string();
-            match col_type.as_str(){
-                "uuid" => {
-                    hash_map.insert(
-                        key.to_string(),
-                        AttributeValue::String(row.get::<_,Uuid>(column.name()).to_string()),
-                    );
-                },
-                "text" | "varchar" => {
-                    hash_map.insert(
-                        key.to_string(),
-                        AttributeValue::String(row.get::<_,String>(column.name()).to_string()),
-                    );
-                },
-                "timestamp" => {
-                    let timestamp_value: NaiveDateTime = row.get::<_, NaiveDateTime>(column.name());
-                    let formatted_timestamp: String = timestamp_value.to_string();
-                
-                    hash_map.insert(
-                        key.to_string(),
-                        AttributeValue::String(formatted_timestamp),
-                    );
-                },
-                "int2" | "int4" => {
-                    let value: i32 = row.get::<_, i32>(column.name());
-                    hash_map.insert(
-                        key.to_string(),
-                        AttributeValue::Float(value as f32),
-                    );
-                },
-                "int8" => {
-                    let value: i64 = row.get::<_, i64>(column.name());
-                    hash_map.insert(
-                        key.to_string(),
-                        AttributeValue::Float(value as f32),
-                    );
-                },
-                "numeric" | "float4" | "float8" => {
-                    hash_map.insert(
-                        key.to_string(),
-                        AttributeValue::Float(row.get::<_, f32>(column.name())),
-                    );
-                },
-                _ => {let _ = String::new();}
-            };
+    for (index, key) in column_names.iter().enumerate().take(row.len()) {
+        //a value that can't be read is logged and left null rather than failing the request
+        let value = match row.try_get::<_, PgValue>(index) {
+            Ok(value) => value.0,
+            Err(err) => {
+                log::error!("Could not read column {}: {}", key, err);
+                AttributeValue::NULL
+            }
+        };
+        hash_map.insert(key.to_string(), value);
+    }
+    hash_map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(ty: &Type, raw: &[u8]) -> serde_json::Value {
+        serde_json::to_value(pg_value_to_attribute(ty, raw).unwrap()).unwrap()
+    }
+
+    //interval wire format: microseconds, days, months
+    fn interval(micros: i64, days: i32, months: i32) -> Vec<u8> {
+        [&micros.to_be_bytes()[..], &days.to_be_bytes(), &months.to_be_bytes()].concat()
+    }
+
+    #[test]
+    fn numerics_keep_their_exact_text() {
+        //digit count, weight, sign, scale, base 10000 digits
+        let cases: [(&[u8], &str); 7] = [
+            (&[0, 1, 0xff, 0xff, 0, 0, 0, 4, 0, 1], "0.0001"),
+            (&[0, 1, 0xff, 0xfe, 0, 0, 0, 8, 0, 1], "0.00000001"),
+            (&[0, 3, 0, 1, 0x40, 0, 0, 3, 0, 1, 0x09, 0x29, 0x1a, 0x2c], "-12345.670"),
+            (&[0, 1, 0, 1, 0, 0, 0, 0, 0x03, 0xe8], "10000000"),
+            (&[0, 0, 0, 0, 0, 0, 0, 2], "0.00"),
+            (&[0, 0, 0, 0, 0xc0, 0, 0, 0], "NaN"),
+            (&[0, 0, 0, 0, 0xf0, 0, 0, 0], "-Infinity"),
+        ];
+        for (raw, text) in cases {
+            assert_eq!(numeric_to_text(raw).unwrap(), text);
+            assert_eq!(decode(&Type::NUMERIC, raw), serde_json::json!(text));
         }
+        assert!(numeric_to_text(&[0, 2, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn arrays_nest_once_per_dimension() {
+        //dimensions, null flag, element oid, then length and lower bound of every dimension
+        let mut raw = Vec::new();
+        for header in [2, 1, 23, 2, 1, 3, 1] {
+            raw.extend_from_slice(&i32::to_be_bytes(header));
+        }
+        for value in [Some(1), Some(2), None, Some(4), Some(5), Some(6)] {
+            match value {
+                Some(value) => {
+                    raw.extend_from_slice(&4i32.to_be_bytes());
+                    raw.extend_from_slice(&i32::to_be_bytes(value));
+                }
+                None => raw.extend_from_slice(&(-1i32).to_be_bytes()),
+            }
+        }
+        assert_eq!(
+            decode(&Type::INT4_ARRAY, &raw),
+            serde_json::json!([[1, 2, null], [4, 5, 6]])
+        );
+
+        let empty = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 23];
+        assert_eq!(decode(&Type::INT4_ARRAY, &empty), serde_json::json!([]));
 
+        let text = [
+            &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 25, 0, 0, 0, 1, 0, 0, 0, 1][..],
+            &[0, 0, 0, 2],
+            b"ab",
+        ]
+        .concat();
+        assert_eq!(decode(&Type::TEXT_ARRAY, &text), serde_json::json!(["ab"]));
+        assert!(pg_value_to_attribute(&Type::TEXT_ARRAY, &text[..text.len() - 1]).is_err());
     }
-    hash_map
-}
\ No newline at end of file
+
+    #[test]
+    fn intervals_read_like_postgres_prints_them() {
+        let cases = [
+            (interval(0, 0, 0), "00:00:00"),
+            (interval(14_706_000_000, 3, 14), "1 year 2 mons 3 days 04:05:06"),
+            (interval(1_500_000, 1, 1), "1 mon 1 day 00:00:01.5"),
+            (interval(-7_200_000_000, 0, 0), "-02:00:00"),
+            (interval(7_200_000_000, -1, 0), "-1 days +02:00:00"),
+            (interval(-1, -2, -13), "-1 years -1 mons -2 days -00:00:00.000001"),
+            (interval(0, 3, -1), "-1 mons +3 days"),
+        ];
+        for (raw, text) in cases {
+            assert_eq!(interval_to_text(&raw).unwrap(), text);
+        }
+        assert!(interval_to_text(&[0; 12]).is_err());
+    }
+
+    #[test]
+    fn network_addresses_show_their_netmask_when_needed() {
+        //family, netmask bits, cidr flag, address length, address
+        let cases: [(&[u8], &str); 4] = [
+            (&[2, 32, 0, 4, 192, 168, 0, 1], "192.168.0.1"),
+            (&[2, 24, 0, 4, 192, 168, 0, 1], "192.168.0.1/24"),
+            (&[2, 32, 1, 4, 10, 0, 0, 1], "10.0.0.1/32"),
+            (
+                &[3, 128, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+                "::1",
+            ),
+        ];
+        for (raw, text) in cases {
+            assert_eq!(inet_to_text(raw).unwrap(), text);
+        }
+        assert!(inet_to_text(&[2, 32, 0, 4, 192, 168]).is_err());
+    }
+
+    #[test]
+    fn timetz_keeps_its_offset() {
+        //microseconds since midnight, seconds west of utc
+        let raw = [&37_800_000_000i64.to_be_bytes()[..], &(-7200i32).to_be_bytes()].concat();
+        assert_eq!(timetz_to_text(&raw).unwrap(), "10:30:00+02:00");
+    }
+}
@@ -6,7 +6,12 @@ use actix_web::http::StatusCode;
 use derive_more::{Display, Error, From};
 use log;
 use memcache::Client;
-use models::{DataRequest, DataResponse, Table};
+use crate::query_engine;
+use query_engine::CompareQuery;
+use models::{AttributeValue, DataRequest, DataResponse, ExportFormat, RowFormat, SqlQuery, Table};
+use crate::export::ChunkEncoder;
+use bytes::Bytes;
+use std::sync::mpsc::{sync_channel, Receiver};
 use mysql::prelude::Queryable;
 use serde::{Deserialize, Serialize};
 use serde_json::{from_str, to_string};
@@ -14,19 +19,21 @@ use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
-use db::{fetch_all_tables,fetch_columns_for_table,run_query};
+use db::{fetch_all_tables,fetch_columns_for_table,for_each_row,run_query};
 use db::{DBPool};
 use actix_web::rt::Runtime;
 use clickhouse_readonly::{ClickhouseError};
 
 // use mysql::prelude::*;
 use cache::{
-    clean_cache_if_needed, deserialize_data, hash_query_to_unique_id, sanitize_query,
+    clean_cache_if_needed, deserialize_data, hash_sql_query_to_unique_id, sanitize_query,
     serialize_data,
 };
 use mysql::Pool;
 
 const MAX_CACHE_SIZE: usize = 50;
+//chunks of a streamed export encoded ahead of the client at most
+const CHUNKS_IN_FLIGHT: usize = 4;
 
 #[derive(Debug, Display, Error, From)]
 pub enum PersistenceError {
@@ -37,6 +44,9 @@ pub enum PersistenceError {
     EmptyTellerName,
     EmptyCustomerName,
     MysqlError(mysql::Error),
+    //the sql has a different number of placeholders than values to bind
+    #[from(ignore)]
+    ParamCount(#[error(ignore)] String),
     Unknown,
 }
 
@@ -46,6 +56,22 @@ impl From<ClickhouseError> for PersistenceError {
         PersistenceError::Unknown
     }
 }
+
+// failed postgres queries, such as a filter value the column's type can't parse
+impl From<tokio_postgres::Error> for PersistenceError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        log::error!("Postgres query failed: {}", error);
+        PersistenceError::Unknown
+    }
+}
+
+impl From<deadpool_postgres::PoolError> for PersistenceError {
+    fn from(error: deadpool_postgres::PoolError) -> Self {
+        log::error!("Postgres connection failed: {}", error);
+        PersistenceError::Unknown
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Relationship {
     pub parent_table: String,
@@ -64,7 +90,9 @@ impl actix_web::ResponseError for PersistenceError {
             | PersistenceError::EmptyTellerName
             | PersistenceError::EmptyCustomerName => StatusCode::BAD_REQUEST,
 
-            PersistenceError::MysqlError(_) | PersistenceError::Unknown => {
+            PersistenceError::MysqlError(_)
+            | PersistenceError::ParamCount(_)
+            | PersistenceError::Unknown => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
         }
@@ -161,7 +189,9 @@ fn update_relationship(
 
 pub fn execute_query(
     json_query: &DataRequest,
-    query: &String,
+    query: &SqlQuery,
+    count_query: Option<&SqlQuery>,
+    compare_query: Option<&CompareQuery>,
     db_connection_pool: &DBPool,
     app_config: &AppConfig,
     cache_client: &Option<Client>,
@@ -170,7 +200,32 @@ pub fn execute_query(
     let is_caching = &app_config.caching.cache_enabled;
     let caching_expiry = &app_config.caching.cache_expiry;
     // Check if the result is already in the cache
-    let cache_key = format!("{}", hash_query_to_unique_id(query));
+    //relative date filters are bound as dates, a new day changes the params and so the key
+    let mut cache_key = hash_sql_query_to_unique_id(query);
+    if count_query.is_some() {
+        //responses with total_rows are cached separately
+        cache_key += "_total";
+    }
+    if let Some(compare_query) = compare_query {
+        cache_key += "_compare_";
+        cache_key += &hash_sql_query_to_unique_id(&compare_query.sql_query);
+    }
+    if let Some(pivot) = &json_query.pivot {
+        //the same rows are cached once per pivoted layout
+        cache_key += "_pivot_";
+        cache_key += &sanitize_query(&pivot.dimension);
+        if let Some(max_columns) = pivot.max_columns {
+            cache_key += &format!("_{}", max_columns);
+        }
+    }
+    if json_query.row_format == Some(RowFormat::Arrays) {
+        cache_key += "_arrays";
+    }
+    if let Some(timezone) = &json_query.timezone {
+        //timestamps are formatted with the zone's offset after the query ran
+        cache_key += "_";
+        cache_key += &sanitize_query(timezone);
+    }
 
     log::info!("Caching : {}", is_caching);
     if *is_caching {
@@ -188,10 +243,40 @@ pub fn execute_query(
     //if not found then run the query and add it to cache server
     let column_headers: Vec<String> = get_column_headers(&json_query);
     let rt = Runtime::new().unwrap();
-    let response: Result<DataResponse, PersistenceError> = match rt.block_on(run_query(&column_headers, &query, db_connection_pool.clone(), db_type)) {
-        Ok(res) => Ok(res),
-        Err(err) => Err(PersistenceError::Unknown),
+    let mut response: Result<DataResponse, PersistenceError> = match rt.block_on(run_query(&column_headers, &query, db_connection_pool.clone(), db_type)) {
+        Ok(res) => {
+            let res = query_engine::localize_timestamps(json_query, &query.columns, res);
+            Ok(query_engine::paginate(json_query, res))
+        }
+        //the driver's message stays in the log, it can hold the sql and the bound values
+        Err(err) => {
+            log::error!("Query failed: {}", err);
+            Err(PersistenceError::Unknown)
+        }
     };
+    if let (Ok(data_response), Some(count_query)) = (&mut response, count_query) {
+        let count_headers = vec!["total_rows".to_string()];
+        let count_response = rt.block_on(run_query(&count_headers, count_query, db_connection_pool.clone(), db_type))?;
+        data_response.total_rows = count_response
+            .data
+            .first()
+            .and_then(|row| row.get("total_rows"))
+            .and_then(attribute_to_count);
+    }
+    if let (Ok(data_response), Some(compare_query)) = (&mut response, compare_query) {
+        let previous_query = &compare_query.sql_query;
+        let previous_response = rt.block_on(run_query(&column_headers, previous_query, db_connection_pool.clone(), db_type))?;
+        if previous_response.data.len() >= compare_query.max_rows as usize {
+            log::warn!("Compared period reached its row limit, later rows get no previous values");
+        }
+        let previous_response = query_engine::localize_timestamps(json_query, &previous_query.columns, previous_response);
+        query_engine::compare_rows(json_query, data_response, &previous_response, compare_query);
+    }
+    if let Ok(data_response) = &mut response {
+        query_engine::describe_columns(json_query, &query.columns, data_response);
+        query_engine::pivot_rows(json_query, data_response);
+        query_engine::rows_to_arrays(json_query, data_response);
+    }
     if *is_caching {
     
         if let Some(client) = cache_client {
@@ -223,6 +308,95 @@ pub fn execute_query(
     }
 }
 
+// runs the query of an export, its rows are too many to be worth caching. They are all read
+// into memory, `export.max_rows` keeps their number in check
+pub fn export_rows(
+    json_query: &DataRequest,
+    query: &SqlQuery,
+    db_connection_pool: &DBPool,
+    app_config: &AppConfig,
+) -> Result<DataResponse, PersistenceError> {
+    let column_headers: Vec<String> = get_column_headers(json_query);
+    let rt = Runtime::new().unwrap();
+    let response = rt.block_on(run_query(&column_headers, query, db_connection_pool.clone(), &app_config.database.db_type))?;
+    let mut response = query_engine::localize_timestamps(json_query, &query.columns, response);
+    query_engine::describe_columns(json_query, &query.columns, &mut response);
+    Ok(response)
+}
+
+// chunks of a streamed export, or the error that ended it
+pub type ChunkReceiver = Receiver<Result<Bytes, PersistenceError>>;
+
+// runs an export query on a thread of its own and encodes the rows into csv or jsonl chunks as
+// the driver reads them. A row is dropped once it is encoded, at most CHUNKS_IN_FLIGHT chunks
+// wait for the client and the query is stopped when the client is gone
+pub fn stream_export(
+    json_query: DataRequest,
+    query: SqlQuery,
+    format: ExportFormat,
+    db_connection_pool: DBPool,
+    app_config: AppConfig,
+) -> ChunkReceiver {
+    let (sender, receiver) = sync_channel(CHUNKS_IN_FLIGHT);
+    actix_web::rt::task::spawn_blocking(move || {
+        let column_headers: Vec<String> = get_column_headers(&json_query);
+        let localize_row = query_engine::row_localizer(&json_query, &query.columns);
+        let mut encoder = ChunkEncoder::new(format, column_headers.clone());
+        let mut cancelled = false;
+        let rt = Runtime::new().unwrap();
+        let result = rt.block_on(for_each_row(&column_headers, &query, db_connection_pool, &app_config.database.db_type, &mut |mut row| {
+            if let Some(localize_row) = &localize_row {
+                localize_row(&mut row);
+            }
+            let Some(chunk) = encoder.push(&row) else {
+                return Ok(());
+            };
+            //the receiver is dropped with the response when the client is gone
+            sender.send(Ok(chunk)).map_err(|_| {
+                cancelled = true;
+                PersistenceError::Unknown
+            })
+        }));
+        let last = match result {
+            Ok(()) => encoder.finish().map(Ok),
+            Err(_) if cancelled => None,
+            //the driver's message stays in the log like in execute_query
+            Err(err) => {
+                log::error!("Export failed: {}", err);
+                Some(Err(PersistenceError::Unknown))
+            }
+        };
+        if let Some(last) = last {
+            let _ = sender.send(last);
+        }
+    });
+    receiver
+}
+
+// the next chunk of a streamed export, None once it is complete. The receiver is handed back
+// for the chunk after it
+pub async fn receive_chunk(
+    chunks: ChunkReceiver,
+) -> Option<(Result<Bytes, PersistenceError>, ChunkReceiver)> {
+    actix_web::rt::task::spawn_blocking(move || {
+        let chunk = chunks.recv().ok()?;
+        Some((chunk, chunks))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+//the count comes back as whatever numeric type the database uses for count(*)
+fn attribute_to_count(value: &AttributeValue) -> Option<i64> {
+    match value {
+        AttributeValue::Int(count) => Some(*count),
+        AttributeValue::Float(count) => Some(*count as i64),
+        AttributeValue::Decimal(count) | AttributeValue::String(count) => count.parse().ok(),
+        _ => None,
+    }
+}
+
 fn extract_data_response(response: &Result<DataResponse, PersistenceError>) -> Option<&DataResponse> {
     match response {
         Ok(data_response) => Some(data_response),
@@ -0,0 +1,175 @@
+use crate::models::{AttributeValue, ExportFormat};
+use bytes::Bytes;
+use std::collections::HashMap;
+
+pub mod parquet;
+pub mod xlsx;
+
+type Row = HashMap<String, AttributeValue>;
+
+//rows encoded at a time when the file is sent in chunks
+const ROWS_PER_CHUNK: usize = 1000;
+
+pub fn content_type(format: ExportFormat) -> &'static str {
+    match format {
+        ExportFormat::Csv => "text/csv; charset=utf-8",
+        ExportFormat::Xlsx => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        ExportFormat::Jsonl => "application/x-ndjson",
+        ExportFormat::Parquet => "application/vnd.apache.parquet",
+    }
+}
+
+// name of the downloaded file, only letters, digits, `-` and `_` of the requested name are kept
+pub fn file_name(requested: Option<&str>, format: ExportFormat) -> String {
+    let mut name: String = requested
+        .unwrap_or_default()
+        .chars()
+        .map(|c| match c {
+            ' ' => '_',
+            c => c,
+        })
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .take(100)
+        .collect();
+    if name.is_empty() {
+        name = "export".to_string();
+    }
+    let extension = match format {
+        ExportFormat::Csv => "csv",
+        ExportFormat::Xlsx => "xlsx",
+        ExportFormat::Jsonl => "jsonl",
+        ExportFormat::Parquet => "parquet",
+    };
+    format!("{}.{}", name, extension)
+}
+
+// encodes the rows of a csv or jsonl file as they are pushed, ROWS_PER_CHUNK of them per chunk.
+// csv files start with a header line with the column names, jsonl files have one object per
+// row with its keys in the order of the columns
+pub struct ChunkEncoder {
+    format: ExportFormat,
+    headers: Vec<String>,
+    chunk: String,
+    rows: usize,
+}
+
+impl ChunkEncoder {
+    pub fn new(format: ExportFormat, headers: Vec<String>) -> ChunkEncoder {
+        let mut chunk = String::new();
+        if format == ExportFormat::Csv {
+            csv_line(headers.iter().map(String::as_str), &mut chunk);
+        }
+        ChunkEncoder {
+            format,
+            headers,
+            chunk,
+            rows: 0,
+        }
+    }
+
+    // the chunk once it is full
+    pub fn push(&mut self, row: &Row) -> Option<Bytes> {
+        match self.format {
+            ExportFormat::Csv => {
+                let values: Vec<String> = self
+                    .headers
+                    .iter()
+                    .map(|header| row.get(header).map(value_text).unwrap_or_default())
+                    .collect();
+                csv_line(values.iter().map(String::as_str), &mut self.chunk);
+            }
+            _ => {
+                let fields: Vec<String> = self
+                    .headers
+                    .iter()
+                    .map(|header| {
+                        let value = row.get(header).unwrap_or(&AttributeValue::NULL);
+                        format!(
+                            "{}:{}",
+                            serde_json::to_string(header).unwrap_or_default(),
+                            serde_json::to_string(value).unwrap_or_else(|_| "null".to_string())
+                        )
+                    })
+                    .collect();
+                self.chunk.push('{');
+                self.chunk.push_str(&fields.join(","));
+                self.chunk.push_str("}\n");
+            }
+        }
+        self.rows += 1;
+        self.rows.is_multiple_of(ROWS_PER_CHUNK).then(|| Bytes::from(std::mem::take(&mut self.chunk)))
+    }
+
+    // what is left after the last row, None when the file ends with a full chunk
+    pub fn finish(self) -> Option<Bytes> {
+        (!self.chunk.is_empty()).then(|| Bytes::from(self.chunk))
+    }
+}
+
+//fields with separators, quotes or line breaks are quoted, rfc 4180
+fn csv_line<'a>(fields: impl Iterator<Item = &'a str>, line: &mut String) {
+    for (position, field) in fields.enumerate() {
+        if position > 0 {
+            line.push(',');
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            line.push('"');
+            line.push_str(&field.replace('"', "\"\""));
+            line.push('"');
+        } else {
+            line.push_str(field);
+        }
+    }
+    line.push_str("\r\n");
+}
+
+// the value as it reads in the json response, without quotes. Nulls are empty
+pub(crate) fn value_text(value: &AttributeValue) -> String {
+    match value {
+        AttributeValue::NULL => String::new(),
+        AttributeValue::String(text)
+        | AttributeValue::Decimal(text)
+        | AttributeValue::Timestamp(text) => text.clone(),
+        AttributeValue::Int(number) => number.to_string(),
+        AttributeValue::Float(number) => number.to_string(),
+        AttributeValue::Bool(value) => value.to_string(),
+        AttributeValue::Date(date) => date.format("%Y-%m-%d").to_string(),
+        AttributeValue::Uuid(uuid) => uuid.to_string(),
+        AttributeValue::Json(json) => json.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(id: i64) -> Row {
+        [("id".to_string(), AttributeValue::Int(id))]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn rows_are_sent_in_full_chunks_and_a_last_one() {
+        let mut encoder = ChunkEncoder::new(ExportFormat::Csv, vec!["id".to_string()]);
+        let chunks: Vec<Bytes> = (0..ROWS_PER_CHUNK as i64 + 1)
+            .filter_map(|id| encoder.push(&row(id)))
+            .collect();
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].starts_with(b"id\r\n0\r\n1\r\n"));
+        assert_eq!(
+            encoder.finish(),
+            Some(Bytes::from(format!("{}\r\n", ROWS_PER_CHUNK)))
+        );
+    }
+
+    #[test]
+    fn empty_jsonl_files_have_no_chunks() {
+        let encoder = ChunkEncoder::new(ExportFormat::Jsonl, vec!["id".to_string()]);
+        assert_eq!(encoder.finish(), None);
+
+        let mut encoder = ChunkEncoder::new(ExportFormat::Jsonl, vec!["id".to_string()]);
+        assert_eq!(encoder.push(&row(7)), None);
+        assert_eq!(encoder.finish(), Some(Bytes::from("{\"id\":7}\n")));
+    }
+}
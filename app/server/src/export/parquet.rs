@@ -0,0 +1,321 @@
+use super::{value_text, Row};
+use crate::models::{AttributeValue, ColumnType, ResponseColumn};
+use chrono::NaiveDate;
+use parquet::basic::{Compression, LogicalType, Repetition, Type as PhysicalType};
+use parquet::data_type::{BoolType, ByteArrayType, DataType, DoubleType, Int32Type, Int64Type};
+use parquet::errors::Result;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::types::Type;
+use std::sync::Arc;
+
+//rows of a row group, the rows are dropped once their group is written
+const ROWS_PER_GROUP: usize = 10_000;
+
+// parquet type of a column, from the type the query engine gives the column. Decimals are
+// written as text so that no digits are lost, timestamps as text with the offset they were
+// formatted with
+#[derive(Clone, Copy, PartialEq)]
+enum ColumnKind {
+    Bool,
+    Int,
+    Double,
+    Date,
+    Text,
+}
+
+// a file with one optional column per response column, in the order of the columns
+pub fn parquet_file(columns: &[ResponseColumn], rows: Vec<Row>) -> Result<Vec<u8>> {
+    let kinds: Vec<ColumnKind> = columns
+        .iter()
+        .map(|column| column_kind(column, &rows))
+        .collect();
+    let fields = columns
+        .iter()
+        .zip(&kinds)
+        .map(|(column, kind)| field(&column.name, *kind).map(Arc::new))
+        .collect::<Result<Vec<_>>>()?;
+    let schema = Type::group_type_builder("export")
+        .with_fields(fields)
+        .build()?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = SerializedFileWriter::new(Vec::new(), Arc::new(schema), Arc::new(properties))?;
+
+    let mut rows = rows.into_iter();
+    loop {
+        let group: Vec<Row> = rows.by_ref().take(ROWS_PER_GROUP).collect();
+        if group.is_empty() {
+            break;
+        }
+        let mut group_writer = writer.next_row_group()?;
+        let mut columns = columns.iter().zip(&kinds);
+        while let Some(mut column) = group_writer.next_column()? {
+            let Some((ResponseColumn { name: header, .. }, kind)) = columns.next() else {
+                break;
+            };
+            match kind {
+                ColumnKind::Bool => write_column::<BoolType>(&mut column, &group, header, boolean)?,
+                ColumnKind::Int => write_column::<Int64Type>(&mut column, &group, header, integer)?,
+                ColumnKind::Double => {
+                    write_column::<DoubleType>(&mut column, &group, header, double)?
+                }
+                ColumnKind::Date => write_column::<Int32Type>(&mut column, &group, header, date)?,
+                ColumnKind::Text => write_column::<ByteArrayType>(
+                    &mut column,
+                    &group,
+                    header,
+                    |value| match value {
+                        AttributeValue::NULL => None,
+                        value => Some(value_text(value).into_bytes().into()),
+                    },
+                )?,
+            }
+            column.close()?;
+        }
+        group_writer.close()?;
+    }
+    writer.into_inner()
+}
+
+// the kind of the column's type. A column with a value that doesn't convert to it, e.g. a
+// driver returning a number as text, is written as text instead of losing the value
+fn column_kind(column: &ResponseColumn, rows: &[Row]) -> ColumnKind {
+    let kind = match column.datatype {
+        ColumnType::Boolean => ColumnKind::Bool,
+        ColumnType::Integer => ColumnKind::Int,
+        ColumnType::Float => ColumnKind::Double,
+        ColumnType::Date => ColumnKind::Date,
+        ColumnType::Decimal | ColumnType::Timestamp | ColumnType::Text => ColumnKind::Text,
+    };
+    let converts = |value: &AttributeValue| match kind {
+        ColumnKind::Bool => boolean(value).is_some(),
+        ColumnKind::Int => integer(value).is_some(),
+        ColumnKind::Double => double(value).is_some(),
+        ColumnKind::Date => date(value).is_some(),
+        ColumnKind::Text => true,
+    };
+    let all_convert = rows
+        .iter()
+        .filter_map(|row| row.get(&column.name))
+        .all(|value| matches!(value, AttributeValue::NULL) || converts(value));
+    match all_convert {
+        true => kind,
+        false => ColumnKind::Text,
+    }
+}
+
+fn field(name: &str, kind: ColumnKind) -> Result<Type> {
+    let (physical_type, logical_type) = match kind {
+        ColumnKind::Bool => (PhysicalType::BOOLEAN, None),
+        ColumnKind::Int => (PhysicalType::INT64, None),
+        ColumnKind::Double => (PhysicalType::DOUBLE, None),
+        ColumnKind::Date => (PhysicalType::INT32, Some(LogicalType::Date)),
+        ColumnKind::Text => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+    };
+    Type::primitive_type_builder(name, physical_type)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_logical_type(logical_type)
+        .build()
+}
+
+fn boolean(value: &AttributeValue) -> Option<bool> {
+    match value {
+        AttributeValue::Bool(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn integer(value: &AttributeValue) -> Option<i64> {
+    match value {
+        AttributeValue::Int(number) => Some(*number),
+        AttributeValue::Decimal(text) | AttributeValue::String(text) => text.parse().ok(),
+        _ => None,
+    }
+}
+
+fn double(value: &AttributeValue) -> Option<f64> {
+    match value {
+        AttributeValue::Int(number) => Some(*number as f64),
+        AttributeValue::Float(number) => Some(*number),
+        //drivers return some floating point results as decimals
+        AttributeValue::Decimal(text) => text.parse().ok(),
+        _ => None,
+    }
+}
+
+fn date(value: &AttributeValue) -> Option<i32> {
+    match value {
+        AttributeValue::Date(date) => Some(date.to_epoch_days()),
+        AttributeValue::String(text) => NaiveDate::parse_from_str(text, "%Y-%m-%d")
+            .ok()
+            .map(|date| date.to_epoch_days()),
+        _ => None,
+    }
+}
+
+// the values of a column, the ones `convert` maps to None are written as nulls
+fn write_column<T: DataType>(
+    column: &mut SerializedColumnWriter,
+    rows: &[Row],
+    header: &str,
+    convert: impl Fn(&AttributeValue) -> Option<T::T>,
+) -> Result<()> {
+    let mut values = Vec::new();
+    //definition level 1 is a value, 0 a null
+    let mut levels = Vec::with_capacity(rows.len());
+    for row in rows {
+        match row.get(header).and_then(&convert) {
+            Some(value) => {
+                values.push(value);
+                levels.push(1);
+            }
+            None => levels.push(0),
+        }
+    }
+    column
+        .typed::<T>()
+        .write_batch(&values, Some(&levels), None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ColumnRole;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    fn column(name: &str, datatype: ColumnType) -> ResponseColumn {
+        ResponseColumn {
+            name: name.to_string(),
+            field: name.to_string(),
+            role: ColumnRole::Dimension,
+            datatype,
+            format: None,
+        }
+    }
+
+    fn read(file: Vec<u8>) -> (Vec<PhysicalType>, Vec<String>) {
+        let reader = SerializedFileReader::new(bytes::Bytes::from(file)).unwrap();
+        let types = reader
+            .metadata()
+            .file_metadata()
+            .schema_descr()
+            .columns()
+            .iter()
+            .map(|column| column.physical_type())
+            .collect();
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().to_string())
+            .collect();
+        (types, rows)
+    }
+
+    #[test]
+    fn columns_get_the_type_of_their_response_column() {
+        let columns = vec![
+            column("id", ColumnType::Integer),
+            column("total", ColumnType::Float),
+            column("amount", ColumnType::Decimal),
+            column("day", ColumnType::Date),
+            column("paid", ColumnType::Boolean),
+            column("note", ColumnType::Text),
+        ];
+        let row = |values: [AttributeValue; 6]| -> Row {
+            columns
+                .iter()
+                .map(|column| column.name.clone())
+                .zip(values)
+                .collect()
+        };
+        let rows = vec![
+            row([
+                AttributeValue::Int(9_007_199_254_740_993),
+                AttributeValue::Float(12.5),
+                AttributeValue::Decimal("12345678901234567890.0123456789".to_string()),
+                AttributeValue::Date(NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()),
+                AttributeValue::Bool(true),
+                AttributeValue::String("first".to_string()),
+            ]),
+            //integers and decimals of a column aren't mixed into doubles
+            row([
+                AttributeValue::Int(2),
+                AttributeValue::Int(3),
+                AttributeValue::Int(7),
+                AttributeValue::NULL,
+                AttributeValue::NULL,
+                AttributeValue::NULL,
+            ]),
+        ];
+        let (types, rows) = read(parquet_file(&columns, rows).unwrap());
+        assert_eq!(
+            types,
+            [
+                PhysicalType::INT64,
+                PhysicalType::DOUBLE,
+                PhysicalType::BYTE_ARRAY,
+                PhysicalType::INT32,
+                PhysicalType::BOOLEAN,
+                PhysicalType::BYTE_ARRAY,
+            ]
+        );
+        assert_eq!(
+            rows,
+            [
+                r#"{id: 9007199254740993, total: 12.5, amount: "12345678901234567890.0123456789", day: 2024-02-29, paid: true, note: "first"}"#,
+                r#"{id: 2, total: 3.0, amount: "7", day: null, paid: null, note: null}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn null_columns_keep_their_type() {
+        let columns = vec![
+            column("id", ColumnType::Integer),
+            column("day", ColumnType::Date),
+        ];
+        let row: Row = [
+            ("id".to_string(), AttributeValue::NULL),
+            ("day".to_string(), AttributeValue::NULL),
+        ]
+        .into_iter()
+        .collect();
+        let (types, _) = read(parquet_file(&columns, vec![row]).unwrap());
+        assert_eq!(types, [PhysicalType::INT64, PhysicalType::INT32]);
+    }
+
+    #[test]
+    fn values_not_of_the_column_type_make_it_text() {
+        let columns = vec![column("id", ColumnType::Integer)];
+        let rows = ["1", "x"]
+            .iter()
+            .map(|id| {
+                [("id".to_string(), AttributeValue::String(id.to_string()))]
+                    .into_iter()
+                    .collect()
+            })
+            .collect();
+        let (types, rows) = read(parquet_file(&columns, rows).unwrap());
+        assert_eq!(types, [PhysicalType::BYTE_ARRAY]);
+        assert_eq!(rows, [r#"{id: "1"}"#, r#"{id: "x"}"#]);
+    }
+
+    #[test]
+    fn empty_results_keep_their_columns() {
+        let columns = vec![column("id", ColumnType::Integer)];
+        let file = parquet_file(&columns, Vec::new()).unwrap();
+        let reader = SerializedFileReader::new(bytes::Bytes::from(file)).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+        assert_eq!(
+            reader
+                .metadata()
+                .file_metadata()
+                .schema_descr()
+                .num_columns(),
+            1
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use super::{value_text, Row};
+use crate::models::AttributeValue;
+use rust_xlsxwriter::{ColNum, RowNum, Workbook, XlsxError};
+
+//characters a cell holds at most, longer texts are cut
+const MAX_CELL_CHARS: usize = 32_767;
+
+// a workbook with a single sheet, the header row and then the rows in the order of the headers.
+// Numbers and booleans become numeric and boolean cells, everything else text
+pub fn xlsx_file(headers: &[String], rows: Vec<Row>) -> Result<Vec<u8>, XlsxError> {
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    sheet.set_name("Export")?;
+    for (column, header) in headers.iter().enumerate() {
+        sheet.write_string(0, column_number(column)?, cell_text(header))?;
+    }
+    for (position, row) in rows.into_iter().enumerate() {
+        let row_number =
+            RowNum::try_from(position + 1).map_err(|_| XlsxError::RowColumnLimitError)?;
+        for (column, header) in headers.iter().enumerate() {
+            let column = column_number(column)?;
+            let value = row.get(header).unwrap_or(&AttributeValue::NULL);
+            match (value, cell_number(value)) {
+                (AttributeValue::NULL, _) => {}
+                (_, Some(number)) => {
+                    sheet.write_number(row_number, column, number)?;
+                }
+                (AttributeValue::Bool(value), _) => {
+                    sheet.write_boolean(row_number, column, *value)?;
+                }
+                (value, None) => {
+                    sheet.write_string(row_number, column, cell_text(&value_text(value)))?;
+                }
+            }
+        }
+    }
+    workbook.save_to_buffer()
+}
+
+fn cell_number(value: &AttributeValue) -> Option<f64> {
+    match value {
+        //excel keeps 15 significant digits, larger integers would be rounded
+        AttributeValue::Int(number) if number.unsigned_abs() < 1_000_000_000_000_000 => {
+            Some(*number as f64)
+        }
+        AttributeValue::Float(number) => Some(*number),
+        AttributeValue::Decimal(text) => text.parse().ok(),
+        _ => None,
+    }
+    .filter(|number: &f64| number.is_finite())
+}
+
+fn column_number(column: usize) -> Result<ColNum, XlsxError> {
+    ColNum::try_from(column).map_err(|_| XlsxError::RowColumnLimitError)
+}
+
+fn cell_text(text: &str) -> String {
+    text.chars().take(MAX_CELL_CHARS).collect()
+}
@@ -2,6 +2,7 @@ mod cache;
 mod config;
 mod db;
 mod db_utils;
+mod export;
 mod middlewares;
 mod models;
 mod query_engine;
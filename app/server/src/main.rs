@@ -1,14 +1,19 @@
 use actix_cors::Cors;
 use actix_web::{
-    dev::ServiceRequest, get, post, web, App, Error, HttpResponse, HttpServer, Responder, Result,
+    dev::ServiceRequest, error, get, post, web, App, Error, HttpResponse, HttpServer, Responder,
+    Result,
 };
 use env_logger;
 use log;
-use models::{AppState, DataRequest, Table};
+use models::{
+    AppState, BatchError, BatchRequest, BatchResponse, BatchResult, DataRequest, DataResponse,
+    ExportFormat, ExportRequest, Table,
+};
 mod cache;
 mod config;
 mod db;
 mod db_utils;
+mod export;
 mod models;
 mod query_engine;
 use actix_web::middleware::Logger;
@@ -17,12 +22,19 @@ use actix_web_httpauth::extractors::AuthenticationError;
 mod middlewares;
 use actix_web_httpauth::middleware::HttpAuthentication;
 use db::pool_builder;
-use db_utils::{execute_query, fetch_schema};
+use actix_web::http::header::ContentDisposition;
+use db_utils::{
+    execute_query, export_rows, fetch_schema, get_column_headers, receive_chunk, stream_export,
+};
+use futures_util::{stream, StreamExt};
 use memcache::Client;
+use query_engine::QueryBuildError;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::pin::Pin;
+use chrono::Utc;
 
 #[post("/api")]
 async fn rest_api(
@@ -32,11 +44,125 @@ async fn rest_api(
     memcache_connection_client: web::Data<Option<Client>>,
     app_state: web::Data<AppState>,
 ) -> actix_web::Result<impl Responder> {
-    let sql_query = query_engine::get_query(&json_query, &app_state.tables);
+    let response_data = run_data_request(
+        json_query.into_inner(),
+        db_shared_data,
+        memcache_connection_client,
+        app_state,
+    )
+    .await?;
+
+    Ok(web::Json(response_data))
+}
+
+// runs the requests of a dashboard page at once, at most `batch.max_concurrency` of them
+// against the database at the same time. Each one is cached and fails on its own
+#[post("/api/batch")]
+async fn batch_api(
+    batch_request: web::Json<BatchRequest>,
+    db_shared_data: web::Data<db::DBPool>,
+    memcache_connection_client: web::Data<Option<Client>>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let queries = batch_request.into_inner().queries;
+    let limits = app_state.app_config.batch.clone();
+    if queries.len() > limits.max_queries {
+        return Err(error::ErrorBadRequest(format!(
+            "A batch takes at most {} queries",
+            limits.max_queries
+        )));
+    }
+    let mut ids = HashSet::new();
+    if let Some(query) = queries.iter().find(|query| !ids.insert(&query.id)) {
+        return Err(error::ErrorBadRequest(format!(
+            "Query id '{}' is used more than once",
+            query.id
+        )));
+    }
+
+    let results: HashMap<String, BatchResult> = stream::iter(queries)
+        .map(|batch_query| {
+            let db_shared_data = db_shared_data.clone();
+            let memcache_connection_client = memcache_connection_client.clone();
+            let app_state = app_state.clone();
+            async move {
+                let response = run_data_request(
+                    batch_query.query,
+                    db_shared_data,
+                    memcache_connection_client,
+                    app_state,
+                )
+                .await;
+                (batch_query.id, batch_result(response))
+            }
+        })
+        .buffer_unordered(limits.max_concurrency.max(1))
+        .collect()
+        .await;
+
+    Ok(web::Json(BatchResponse { results }))
+}
+
+// what /api answers to a single request, either its data or the error with its status
+fn batch_result(response: actix_web::Result<DataResponse>) -> BatchResult {
+    match response {
+        Ok(data) => BatchResult {
+            status: 200,
+            data: Some(data),
+            error: None,
+        },
+        Err(err) => {
+            let code = match err.as_error::<QueryBuildError>() {
+                Some(build_error) => build_error.code(),
+                None => "query_failed",
+            };
+            BatchResult {
+                status: err.as_response_error().status_code().as_u16(),
+                data: None,
+                error: Some(BatchError {
+                    code: code.to_string(),
+                    message: err.to_string(),
+                }),
+            }
+        }
+    }
+}
+
+// builds the sql of a data request and runs it through the cache
+async fn run_data_request(
+    json_query: DataRequest,
+    db_shared_data: web::Data<db::DBPool>,
+    memcache_connection_client: web::Data<Option<Client>>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<DataResponse> {
+    check_timezone(&json_query, &app_state)?;
+    //relative dates of all the queries are resolved against the same moment
+    let now = Utc::now();
+    let sql_query = query_engine::get_query_at(
+        &json_query,
+        &app_state.tables,
+        &app_state.app_config.database.db_type,
+        now,
+    )?;
+    let count_query = query_engine::get_count_query(
+        &json_query,
+        &app_state.tables,
+        &app_state.app_config.database.db_type,
+        now,
+    )?;
+    let compare_query = query_engine::get_compare_query(
+        &json_query,
+        &app_state.tables,
+        &app_state.app_config.database.db_type,
+        app_state.app_config.compare.max_rows,
+        now,
+    )?;
     let response_data = web::block(move || {
         execute_query(
             &json_query,
             &sql_query,
+            count_query.as_ref(),
+            compare_query.as_ref(),
             &db_shared_data,
             &app_state.app_config,
             &memcache_connection_client,
@@ -44,7 +170,85 @@ async fn rest_api(
     })
     .await??;
 
-    Ok(web::Json(response_data))
+    Ok(response_data)
+}
+
+// downloads the rows of a data request as a file, at most `export.max_rows` of them. Exports
+// skip the cache. csv and jsonl files are streamed, their rows are encoded and sent in chunks as
+// the database returns them. xlsx and parquet files are only readable once complete, their rows
+// are held in memory while the file is written and the row cap is what bounds it
+#[post("/api/export")]
+async fn export_api(
+    export_request: web::Json<ExportRequest>,
+    db_shared_data: web::Data<db::DBPool>,
+    app_state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let export_request = export_request.into_inner();
+    let format = export_request.format;
+    check_timezone(&export_request.query, &app_state)?;
+    let json_query = query_engine::export::export_request(
+        &export_request.query,
+        app_state.app_config.export.max_rows,
+    )?;
+    let sql_query = query_engine::get_query(
+        &json_query,
+        &app_state.tables,
+        &app_state.app_config.database.db_type,
+    )?;
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(export::content_type(format))
+        .insert_header(ContentDisposition::attachment(export::file_name(
+            export_request.file_name.as_deref(),
+            format,
+        )));
+    if matches!(format, ExportFormat::Csv | ExportFormat::Jsonl) {
+        let chunks = stream_export(
+            json_query,
+            sql_query,
+            format,
+            db_shared_data.get_ref().clone(),
+            app_state.app_config.clone(),
+        );
+        //the first chunk is waited for, so that a failing query still gets its error status
+        let Some((first, chunks)) = receive_chunk(chunks).await else {
+            return Ok(response.finish());
+        };
+        let first = first?;
+        let chunks = stream::once(async { Ok(first) }).chain(stream::unfold(chunks, receive_chunk));
+        return Ok(response.streaming(chunks.map(|chunk| chunk.map_err(Error::from))));
+    }
+
+    let headers = get_column_headers(&json_query);
+    let response_data = web::block(move || {
+        export_rows(
+            &json_query,
+            &sql_query,
+            &db_shared_data,
+            &app_state.app_config,
+        )
+    })
+    .await??;
+    let file = web::block(move || match format {
+        ExportFormat::Xlsx => {
+            export::xlsx::xlsx_file(&headers, response_data.data).map_err(|err| err.to_string())
+        }
+        _ => export::parquet::parquet_file(&response_data.columns, response_data.data)
+            .map_err(|err| err.to_string()),
+    })
+    .await?
+    .map_err(error::ErrorInternalServerError)?;
+    Ok(response.body(file))
+}
+
+// without its time zone tables mysql converts every timestamp of the request to null
+fn check_timezone(json_query: &DataRequest, app_state: &AppState) -> Result<(), QueryBuildError> {
+    match &json_query.timezone {
+        Some(timezone) if !app_state.named_time_zones => {
+            Err(QueryBuildError::TimezoneNotLoaded(timezone.clone()))
+        }
+        _ => Ok(()),
+    }
 }
 
 #[post("/get_query")]
@@ -52,8 +256,16 @@ async fn get_query(
     json_query: web::Json<DataRequest>,
     app_state: web::Data<AppState>,
 ) -> Result<String> {
-    let sql_query = query_engine::get_query(&json_query, &app_state.tables);
-    Ok(format!("SQL:\n{}!", sql_query))
+    let sql_query = query_engine::get_query(
+        &json_query,
+        &app_state.tables,
+        &app_state.app_config.database.db_type,
+    )?;
+    Ok(format!(
+        "SQL:\n{}!\nParams:\n{}",
+        sql_query.sql,
+        serde_json::to_string(&sql_query.params)?
+    ))
 }
 
 #[get("/")]
@@ -146,6 +358,13 @@ async fn main() -> std::io::Result<()> {
         }
     };
     let db_shared_data = web::Data::new(db_pool.clone());
+    let named_time_zones = db::supports_named_time_zones(&db_pool);
+    if !named_time_zones {
+        log::error!(
+            "The time zone tables of the MySQL server are not loaded, requests with a timezone \
+             will be refused. Load them with mysql_tzinfo_to_sql, see the README"
+        );
+    }
     // let result = execute_query(postgres_pool.clone()).await;
     //setup cache server client
     let cache_server =
@@ -202,14 +421,62 @@ async fn main() -> std::io::Result<()> {
                 is_caching: config.caching.cache_enabled.clone(),
                 caching_expiry: config.caching.cache_expiry.clone(),
                 app_config: config.clone(),
+                named_time_zones,
             }))
             .service(hello)
             .service(echo)
             .service(get_query)
             .service(rest_api)
+            .service(batch_api)
+            .service(export_api)
         // .service(fetch_schema)
     })
     .bind(("0.0.0.0", 8080))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn batch_results_carry_the_data_of_a_query() {
+        let result = batch_result(Ok(DataResponse::new(Vec::new())));
+        assert_eq!(result.status, 200);
+        assert!(result.data.is_some());
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["status"], 200);
+        assert!(json.get("error").is_none());
+    }
+
+    #[test]
+    fn batch_results_carry_the_error_of_a_query_that_doesnt_build() {
+        let error = QueryBuildError::UnknownColumn("orders.missing".to_string());
+        let result = batch_result(Err(error.into()));
+        assert!(result.data.is_none());
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            json!({
+                "status": 400,
+                "error": {
+                    "code": "unknown_column",
+                    "message": "Column 'orders.missing' not found or invalid input format"
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn batch_results_carry_the_status_of_a_failing_query() {
+        let result = batch_result(Err(error::ErrorInternalServerError("connection refused")));
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            json!({
+                "status": 500,
+                "error": {"code": "query_failed", "message": "connection refused"}
+            })
+        );
+    }
+}
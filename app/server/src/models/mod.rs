@@ -1,65 +1,500 @@
 use crate::config::AppConfig;
+use chrono::NaiveDate;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
+use uuid::Uuid;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct DataRequest {
     pub metrics: Option<Vec<Metric>>,
     pub dimensions: Option<Vec<Dimension>>,
-    pub filters: Option<Vec<Filter>>,
-    pub orderby: Option<OrderBy>,
+    pub filters: Option<FilterExpression>,
+    pub having: Option<FilterExpression<HavingFilter>>,
+    pub orderby: Option<Vec<OrderBy>>,
     pub limit: Option<i32>,
+    pub offset: Option<i64>,
+    //1 based, page_size rows per page
+    pub page: Option<i64>,
+    pub page_size: Option<i32>,
+    //keyset pagination on the orderby fields and then the dimensions they leave out, only for
+    //requests with dimensions. An empty cursor asks for the first page
+    pub cursor: Option<String>,
+    //adds total_rows to the response, counted by a separate query
+    pub include_total: Option<bool>,
+    //first day of the week for week and day_of_week transformations, monday if not given
+    pub week_start: Option<Weekday>,
+    //IANA name like `Europe/Berlin`, dates are bucketed and filtered in this zone and
+    //timestamps come back with its offset. Timestamps without a zone are taken as utc
+    pub timezone: Option<String>,
+    //adds the metrics of an earlier period to every row, see Compare
+    pub compare: Option<Compare>,
+    //returns the metrics as a matrix with one of the dimensions as its columns, see Pivot
+    pub pivot: Option<Pivot>,
+    //`arrays` returns the rows as lists of values in the order of DataResponse.columns
+    pub row_format: Option<RowFormat>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Metric {
+    //empty for formula metrics
+    #[serde(default)]
     pub field: String,
     pub aggregate_operator: Option<String>,
     pub name: Option<String>,
     pub distinct: Option<bool>,
+    //computes the metric across the rows of the result instead of within each row
+    pub window: Option<Window>,
+    //arithmetic over the other metrics of the request by name, `revenue / orders`.
+    //Takes the place of field and aggregate_operator
+    pub formula: Option<String>,
+    //only the rows matching these filters are aggregated, `sum of total where status = 'refunded'`
+    pub filters: Option<FilterExpression>,
+}
+
+// `{"type": "moving_average", "size": 7, "order_by": [{"field": "day"}]}`, partition_by and
+// order_by name dimensions or metrics of the request. rank orders by the metric itself,
+// descending, unless order_by is given
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Window {
+    #[serde(rename = "type")]
+    pub window_type: WindowType,
+    #[serde(default)]
+    pub partition_by: Vec<String>,
+    #[serde(default)]
+    pub order_by: Vec<OrderBy>,
+    //rows averaged by moving_average, the current one included
+    pub size: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowType {
+    RunningTotal,
+    Rank,
+    DenseRank,
+    MovingAverage,
+    PercentOfTotal,
 }
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct OrderBy {
-    pub field: Vec<String>,
+    //alias or field of a requested metric/dimension, or a `table.column`
+    pub field: String,
+    #[serde(default)]
     pub order: Order,
+    pub nulls: Option<Nulls>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
 pub enum Order {
+    #[default]
     asc,
     desc,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    #[default]
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    pub fn days_from_monday(self) -> u32 {
+        self as u32
+    }
+}
+
+// `{"field": "orders.created_at", "period": "previous_year"}`, the filters on `field` are
+// shifted to the earlier period and every metric gets `<metric>_previous`, `<metric>_change`
+// and `<metric>_change_pct` next to it. Rows are matched on the dimensions, a dimension on
+// `field` itself is matched after moving the earlier period's buckets onto the requested one
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Compare {
+    pub field: String,
+    #[serde(default)]
+    pub period: ComparePeriod,
+}
+
+// `{"dimension": "month", "max_columns": 24}`, the values of `dimension` (header of a requested
+// dimension) become the columns and the other dimensions the rows of DataResponse.pivot
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Pivot {
+    pub dimension: String,
+    //columns kept, in the order they first show up in the rows. 50 if not given
+    pub max_columns: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RowFormat {
+    #[default]
+    Objects,
+    Arrays,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ComparePeriod {
+    //the same length of time right before the filtered range
+    #[default]
+    PreviousPeriod,
+    PreviousYear,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Nulls {
+    First,
+    Last,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Dimension {
     pub field: String,
     pub transformation: Option<String>,
     pub name: Option<String>,
+    //groups a numeric field into ranges, takes the place of transformation
+    pub bins: Option<Bins>,
+    //keeps the values ranking highest by a metric, the others are put together
+    pub top_n: Option<TopN>,
+}
+
+// `{"count": 10, "by": "revenue"}`, `by` names a metric of the request. Values outside the
+// top `count` are grouped under `other_label`, null is ranked like any other value. A top value
+// equal to `other_label` keeps a row of its own, both rows then carry the same label, so the
+// label should be one the field doesn't hold
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TopN {
+    pub count: u32,
+    pub by: String,
+    #[serde(default = "default_other_label")]
+    pub other_label: String,
+}
+
+fn default_other_label() -> String {
+    "Other".to_string()
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// `{"type": "fixed_width", "width": 50}`, `{"type": "boundaries", "boundaries": [0, 10, 100]}`
+// or `{"type": "quantiles", "count": 4}`. The dimension's value becomes a label such as
+// `50 - 100` and ordering by it follows the ranges rather than the labels
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Bins {
+    //ranges of `width` starting at `origin`
+    FixedWidth {
+        width: f64,
+        #[serde(default)]
+        origin: f64,
+    },
+    //increasing edges, values below the first or from the last on get a range of their own
+    Boundaries { boundaries: Vec<f64> },
+    //`count` ranges holding about as many rows each, the edges come from the filtered rows
+    Quantiles { count: u32 },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Filter {
     pub dimension: Dimension,
     pub filter_operator: String,
-    pub filter_value: String,
+    #[serde(default)]
+    pub filter_value: Option<FilterValue>,
+}
+
+// Filters can be nested into and/or/not groups, e.g.
+// `{"and": [{"or": [<filter>, <filter>]}, {"not": <filter>}]}`.
+// A plain list of filters is an implicit and.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum FilterExpression<T = Filter> {
+    And { and: Vec<FilterExpression<T>> },
+    Or { or: Vec<FilterExpression<T>> },
+    Not { not: Box<FilterExpression<T>> },
+    List(Vec<FilterExpression<T>>),
+    Filter(T),
+}
+
+impl<T> FilterExpression<T> {
+    // every filter in the tree, in the order they appear
+    pub fn filters(&self) -> Vec<&T> {
+        match self {
+            FilterExpression::Filter(filter) => vec![filter],
+            FilterExpression::Not { not } => not.filters(),
+            FilterExpression::And { and: expressions }
+            | FilterExpression::Or { or: expressions }
+            | FilterExpression::List(expressions) => expressions
+                .iter()
+                .flat_map(|expression| expression.filters())
+                .collect(),
+        }
+    }
+}
+
+// Condition on an aggregated metric, which is either one of the request's metrics
+// referenced by its name or an aggregate given inline
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HavingFilter {
+    pub metric: MetricReference,
+    pub filter_operator: String,
+    #[serde(default)]
+    pub filter_value: Option<FilterValue>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum MetricReference {
+    Name(String),
+    Metric(Box<Metric>),
+}
+
+// `"EU"`, `["EU", "UK"]` or `{"from": "2023-01-01", "to": "2023-02-01"}`,
+// which shapes are accepted depends on the filter operator
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum FilterValue {
+    Scalar(ScalarValue),
+    List(Vec<ScalarValue>),
+    Range { from: ScalarValue, to: ScalarValue },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum ScalarValue {
+    String(String),
+    Number(serde_json::Number),
+    Bool(bool),
+}
+
+impl std::fmt::Display for ScalarValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ScalarValue::String(value) => write!(f, "{}", value),
+            ScalarValue::Number(value) => write!(f, "{}", value),
+            ScalarValue::Bool(value) => write!(f, "{}", value),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct DataResponse {
     pub data: Vec<HashMap<String, AttributeValue>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_rows: Option<i64>,
+    //set for pivot requests, `data` is empty then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pivot: Option<PivotTable>,
+    //the columns of the rows in select order, compare columns follow the requested ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub columns: Vec<ResponseColumn>,
+    //the rows of an `arrays` row_format request, `data` is empty then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<Vec<Vec<AttributeValue>>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseColumn {
+    pub name: String,
+    //requested field, empty for formula metrics
+    pub field: String,
+    pub role: ColumnRole,
+    pub datatype: ColumnType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<ColumnFormat>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnRole {
+    Dimension,
+    Metric,
+}
+
+// type of the values of a column as they come back in json. Decimals are strings so that no
+// digits are lost
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnType {
+    Integer,
+    Float,
+    Decimal,
+    Boolean,
+    Date,
+    Timestamp,
+    Text,
+}
+
+// how values are meant to be shown: percentages (already multiplied by 100) or the grain
+// timestamps were truncated to
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnFormat {
+    Percent,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+// the metrics of a pivot request, `values[metric][row][column]` is null where the combination
+// of row and column keys has no data
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PivotTable {
+    //headers of the dimensions a row key is made of, in the order of the request
+    pub row_dimensions: Vec<String>,
+    pub column_dimension: String,
+    pub rows: Vec<Vec<AttributeValue>>,
+    pub columns: Vec<AttributeValue>,
+    pub values: HashMap<String, Vec<Vec<AttributeValue>>>,
+    //more column keys than max_columns came back, the rest is left out
+    pub truncated: bool,
+}
+
+impl DataResponse {
+    pub fn new(data: Vec<HashMap<String, AttributeValue>>) -> Self {
+        DataResponse {
+            data,
+            next_cursor: None,
+            total_rows: None,
+            pivot: None,
+            columns: Vec::new(),
+            rows: None,
+        }
+    }
+}
+
+// several data requests of a dashboard page, keyed by the id of the widget they belong to
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BatchRequest {
+    pub queries: Vec<BatchQuery>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BatchQuery {
+    pub id: String,
+    pub query: DataRequest,
 }
 
+// the outcome of every query of a batch by widget id, a failing query doesn't fail the others
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BatchResponse {
+    pub results: HashMap<String, BatchResult>,
+}
+
+// the response the query would have gotten from /api, with its http status
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BatchResult {
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<DataResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<BatchError>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BatchError {
+    pub code: String,
+    pub message: String,
+}
+
+// a data request downloaded as a file, `{"format": "csv", "query": {...}}`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExportRequest {
+    pub query: DataRequest,
+    pub format: ExportFormat,
+    //name of the downloaded file without its extension, `export` if not given
+    pub file_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Csv,
+    Xlsx,
+    //one json object per line
+    Jsonl,
+    Parquet,
+}
+
+// SQL generated by the query engine along with the values bound to its placeholders
+#[derive(Debug, Serialize, Clone)]
+pub struct SqlQuery {
+    pub sql: String,
+    pub params: Vec<SqlParam>,
+    //what the query returns, empty for queries that don't come from the query engine
+    #[serde(skip)]
+    pub columns: Vec<ResultColumn>,
+}
+
+// a column of the result set, in select order
+#[derive(Debug, Serialize, Clone)]
+pub struct ResultColumn {
+    pub name: String,
+    pub field: String,
+    //type of the returned values, the column's type unless a transformation changes it
+    pub datatype: String,
+    //timestamps already shifted into the request's time zone
+    pub local_time: bool,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum SqlParam {
+    String(String),
+    Int(i64),
+    Float(f64),
+}
+
+impl SqlQuery {
+    pub fn new(sql: &str) -> Self {
+        SqlQuery {
+            sql: sql.to_string(),
+            params: Vec::new(),
+            columns: Vec::new(),
+        }
+    }
+}
+
+// a value of a result row, as exact as the database returned it
 #[derive(Debug, Clone)]
 pub enum AttributeValue {
     NULL,
     String(String),
-    Float(f32),
+    Int(i64),
+    Float(f64),
+    //numeric/decimal columns, kept as their text so no digits are lost on the way to json
+    Decimal(String),
+    Bool(bool),
+    Date(NaiveDate),
+    //ISO-8601, with the offset when the database knows it. localize_timestamps rewrites it
+    Timestamp(String),
+    Uuid(Uuid),
+    Json(serde_json::Value),
 }
 
 impl Serialize for AttributeValue {
@@ -70,11 +505,20 @@ impl Serialize for AttributeValue {
         match *self {
             AttributeValue::NULL => serializer.serialize_unit(),
             AttributeValue::String(ref s) => serializer.serialize_str(s),
-            AttributeValue::Float(f) => serializer.serialize_f32(f),
+            AttributeValue::Int(i) => serializer.serialize_i64(i),
+            AttributeValue::Float(f) => serializer.serialize_f64(f),
+            AttributeValue::Decimal(ref d) => serializer.serialize_str(d),
+            AttributeValue::Bool(b) => serializer.serialize_bool(b),
+            AttributeValue::Date(ref d) => serializer.collect_str(&d.format("%Y-%m-%d")),
+            AttributeValue::Timestamp(ref t) => serializer.serialize_str(t),
+            AttributeValue::Uuid(ref u) => serializer.collect_str(u),
+            AttributeValue::Json(ref j) => j.serialize(serializer),
         }
     }
 }
 
+// used for cached responses. Decimals, dates, timestamps and uuids come back as strings, which
+// serialize the same way
 impl<'de> Deserialize<'de> for AttributeValue {
     fn deserialize<D>(deserializer: D) -> Result<AttributeValue, D::Error>
     where
@@ -84,15 +528,21 @@ impl<'de> Deserialize<'de> for AttributeValue {
         #[serde(untagged)]
         enum ValueHelper {
             Null,
+            Bool(bool),
+            Int(i64),
+            Float(f64),
             String(String),
-            Float(f32),
+            Json(serde_json::Value),
         }
 
         let value_helper = ValueHelper::deserialize(deserializer)?;
         match value_helper {
             ValueHelper::Null => Ok(AttributeValue::NULL),
-            ValueHelper::String(s) => Ok(AttributeValue::String(s)),
+            ValueHelper::Bool(b) => Ok(AttributeValue::Bool(b)),
+            ValueHelper::Int(i) => Ok(AttributeValue::Int(i)),
             ValueHelper::Float(f) => Ok(AttributeValue::Float(f)),
+            ValueHelper::String(s) => Ok(AttributeValue::String(s)),
+            ValueHelper::Json(j) => Ok(AttributeValue::Json(j)),
         }
     }
 }
@@ -116,6 +566,8 @@ pub struct AppState {
     pub is_caching: bool,
     pub caching_expiry: u32,
     pub app_config: AppConfig,
+    //false on mysql servers without time zone tables, requests with a timezone are refused
+    pub named_time_zones: bool,
 }
 
 impl Table {
@@ -0,0 +1,189 @@
+use super::dialect::{Aggregate, CastType, Dialect};
+use super::{value_kind, QueryBuildError, ValueKind};
+use crate::models::{Bins, Dimension};
+
+// the label and the sort key of a binned dimension. Quantile edges are read from the
+// `edges_table` joined by quantiles_join_sql
+pub(super) fn bins_to_sql(
+    dimension: &Dimension,
+    bins: &Bins,
+    field_datatype: &str,
+    edges_table: &str,
+    dialect: &dyn Dialect,
+) -> Result<(String, String), QueryBuildError> {
+    check_bins(dimension, bins, field_datatype)?;
+    let field_sql = dialect.quote_field(&dimension.field);
+    match bins {
+        Bins::FixedWidth { width, origin } => {
+            //integer columns would otherwise be divided as integers
+            let value_sql = dialect.cast(&field_sql, CastType::Float);
+            let start_sql = if *origin == 0.0 {
+                format!("(FLOOR({} / {}) * {})", value_sql, width, width)
+            } else {
+                format!(
+                    "(FLOOR(({} - {}) / {}) * {} + {})",
+                    value_sql, origin, width, width, origin
+                )
+            };
+            let end_sql = format!("({} + {})", start_sql, width);
+            let label_sql = format!(
+                "CASE WHEN {} IS NOT NULL THEN {} END",
+                field_sql,
+                range_label(&start_sql, &end_sql, dialect)
+            );
+            Ok((label_sql, start_sql))
+        }
+        Bins::Boundaries { boundaries } => {
+            let edges: Vec<String> = boundaries.iter().map(f64::to_string).collect();
+            let mut labels = vec![format!("< {}", edges[0])];
+            labels.extend(
+                edges
+                    .windows(2)
+                    .map(|pair| format!("{} - {}", pair[0], pair[1])),
+            );
+            labels.push(format!(">= {}", edges[edges.len() - 1]));
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|label| dialect.quote_literal(label))
+                .collect();
+            Ok((
+                bin_case(&field_sql, &edges, &labels),
+                bin_case(&field_sql, &edges, &bin_numbers(labels.len())),
+            ))
+        }
+        Bins::Quantiles { count } => {
+            //q_0 and q_count are the smallest and the biggest value
+            let quantiles: Vec<String> = (0..=*count)
+                .map(|position| {
+                    format!(
+                        "{}.{}",
+                        dialect.quote_identifier(edges_table),
+                        dialect.quote_identifier(&format!("q_{}", position))
+                    )
+                })
+                .collect();
+            let edges = &quantiles[1..quantiles.len() - 1];
+            let labels: Vec<String> = quantiles
+                .windows(2)
+                .map(|pair| range_label(&pair[0], &pair[1], dialect))
+                .collect();
+            Ok((
+                bin_case(&field_sql, edges, &labels),
+                bin_case(&field_sql, edges, &bin_numbers(labels.len())),
+            ))
+        }
+    }
+}
+
+// `CROSS JOIN (select MIN(x) as q_0, <quantile> as q_1, .., MAX(x) as q_n from ..) as <edges_table>`,
+// the edges are taken over the rows the query filters, `where_sql` has its values inlined
+pub(super) fn quantiles_join_sql(
+    dimension: &Dimension,
+    count: u32,
+    edges_table: &str,
+    from_sql: &str,
+    where_sql: &str,
+    dialect: &dyn Dialect,
+) -> Result<String, QueryBuildError> {
+    let field_sql = dialect.quote_field(&dimension.field);
+    let mut edges = vec![format!(
+        "MIN({}) as {}",
+        field_sql,
+        dialect.quote_identifier("q_0")
+    )];
+    for position in 1..count {
+        let fraction = f64::from(position) / f64::from(count);
+        let quantile_sql = dialect
+            .aggregate(Aggregate::Percentile(fraction), &field_sql)
+            .ok_or_else(|| QueryBuildError::AggregationNotSupported {
+                field: dimension.field.clone(),
+                aggregation: "quantiles".to_string(),
+                database: dialect.name().to_string(),
+            })?;
+        edges.push(format!(
+            "{} as {}",
+            quantile_sql,
+            dialect.quote_identifier(&format!("q_{}", position))
+        ));
+    }
+    edges.push(format!(
+        "MAX({}) as {}",
+        field_sql,
+        dialect.quote_identifier(&format!("q_{}", count))
+    ));
+    Ok(format!(
+        "CROSS JOIN (select {} from {} {}) as {}",
+        edges.join(", "),
+        from_sql,
+        where_sql,
+        dialect.quote_identifier(edges_table)
+    ))
+}
+
+fn check_bins(
+    dimension: &Dimension,
+    bins: &Bins,
+    field_datatype: &str,
+) -> Result<(), QueryBuildError> {
+    let invalid = |message: &str| {
+        Err(QueryBuildError::InvalidBins(format!(
+            "{} (dimension '{}')",
+            message, dimension.field
+        )))
+    };
+    if dimension.transformation.is_some() {
+        return invalid("bins take the place of the transformation, it can't have both");
+    }
+    if !matches!(
+        value_kind(field_datatype),
+        ValueKind::Integer | ValueKind::Float
+    ) {
+        return invalid("only numeric fields can be binned");
+    }
+    match bins {
+        Bins::FixedWidth { width, origin } => {
+            if !width.is_finite() || *width <= 0.0 || !origin.is_finite() {
+                return invalid("width has to be a positive number");
+            }
+        }
+        Bins::Boundaries { boundaries } => {
+            let increasing = boundaries.windows(2).all(|pair| pair[0] < pair[1]);
+            if boundaries.is_empty()
+                || !increasing
+                || !boundaries.iter().all(|edge| edge.is_finite())
+            {
+                return invalid("boundaries have to be increasing numbers");
+            }
+        }
+        Bins::Quantiles { count } => {
+            if !(2..=100).contains(count) {
+                return invalid("count has to be between 2 and 100");
+            }
+        }
+    }
+    Ok(())
+}
+
+//`CASE WHEN x < e1 THEN v0 WHEN x < e2 THEN v1 .. ELSE vn END`, nulls stay null
+fn bin_case(field_sql: &str, edges: &[String], values: &[String]) -> String {
+    let mut sql = format!("CASE WHEN {} IS NULL THEN NULL", field_sql);
+    for (edge, value) in edges.iter().zip(values) {
+        sql += &format!(" WHEN {} < {} THEN {}", field_sql, edge, value);
+    }
+    format!("{} ELSE {} END", sql, values[edges.len()])
+}
+
+//the position of each bin, it orders the bins
+fn bin_numbers(count: usize) -> Vec<String> {
+    (0..count).map(|position| position.to_string()).collect()
+}
+
+//`<start> - <end>` of two numeric expressions
+fn range_label(start_sql: &str, end_sql: &str, dialect: &dyn Dialect) -> String {
+    format!(
+        "CONCAT({}, {}, {})",
+        dialect.cast(start_sql, CastType::Text),
+        dialect.quote_literal(" - "),
+        dialect.cast(end_sql, CastType::Text)
+    )
+}
@@ -0,0 +1,163 @@
+use super::compare::compare_columns;
+use super::dialect::TimeGrain;
+use super::{
+    base_datatype, parse_transformation, temporal_kind, value_kind, DateSettings, TemporalKind,
+    Transformation, ValueKind,
+};
+use crate::models::{
+    AttributeValue, ColumnFormat, ColumnRole, ColumnType, DataRequest, DataResponse, Dimension,
+    Metric, ResponseColumn, ResultColumn, RowFormat, WindowType,
+};
+
+// sets DataResponse.columns from the columns of the request's SqlQuery and converts the values
+// to the type of their column. Databases return sums and averages as decimals, floats or
+// integers depending on the column, this way a column's values all have the same json type
+pub fn describe_columns(
+    query: &DataRequest,
+    columns: &[ResultColumn],
+    response: &mut DataResponse,
+) {
+    //the select list has the dimensions first, then the metrics
+    let dimension_count = query.dimensions.as_ref().map_or(0, Vec::len);
+    let formats: Vec<Option<ColumnFormat>> = query
+        .dimensions
+        .iter()
+        .flatten()
+        .map(dimension_format)
+        .chain(query.metrics.iter().flatten().map(metric_format))
+        .collect();
+    let mut response_columns: Vec<ResponseColumn> = columns
+        .iter()
+        .enumerate()
+        .map(|(position, column)| ResponseColumn {
+            name: column.name.clone(),
+            field: column.field.clone(),
+            role: match position < dimension_count {
+                true => ColumnRole::Dimension,
+                false => ColumnRole::Metric,
+            },
+            datatype: column_type(&column.datatype),
+            format: formats.get(position).copied().flatten(),
+        })
+        .collect();
+    if query.compare.is_some() {
+        let compared: Vec<ResponseColumn> = response_columns
+            .iter()
+            .filter(|column| column.role == ColumnRole::Metric)
+            .flat_map(compare_columns)
+            .collect();
+        response_columns.extend(compared);
+    }
+
+    for row in response.data.iter_mut() {
+        for column in &response_columns {
+            if let Some(value) = row.get_mut(&column.name) {
+                *value = conform(
+                    std::mem::replace(value, AttributeValue::NULL),
+                    column.datatype,
+                );
+            }
+        }
+    }
+    response.columns = response_columns;
+}
+
+// `arrays` row_format: every row becomes the list of its values in the order of the columns
+pub fn rows_to_arrays(query: &DataRequest, response: &mut DataResponse) {
+    if query.row_format != Some(RowFormat::Arrays) || response.pivot.is_some() {
+        return;
+    }
+    let columns = &response.columns;
+    let rows = response
+        .data
+        .drain(..)
+        .map(|mut row| {
+            columns
+                .iter()
+                .map(|column| row.remove(&column.name).unwrap_or(AttributeValue::NULL))
+                .collect()
+        })
+        .collect();
+    response.rows = Some(rows);
+}
+
+fn column_type(datatype: &str) -> ColumnType {
+    match temporal_kind(datatype) {
+        Some(TemporalKind::Date) => return ColumnType::Date,
+        Some(TemporalKind::Timestamp | TemporalKind::TimestampTz) => return ColumnType::Timestamp,
+        None => {}
+    }
+    if matches!(base_datatype(datatype).as_str(), "decimal" | "numeric") {
+        return ColumnType::Decimal;
+    }
+    match value_kind(datatype) {
+        ValueKind::Integer => ColumnType::Integer,
+        ValueKind::Float => ColumnType::Float,
+        ValueKind::Boolean => ColumnType::Boolean,
+        ValueKind::Text => ColumnType::Text,
+    }
+}
+
+//the grain of truncated timestamps
+fn dimension_format(dimension: &Dimension) -> Option<ColumnFormat> {
+    if dimension.bins.is_some() || dimension.top_n.is_some() {
+        return None;
+    }
+    let transformation = dimension
+        .transformation
+        .as_deref()
+        .and_then(|name| parse_transformation(name, &DateSettings::default()));
+    match transformation {
+        Some(Transformation::Trunc(grain)) => Some(match grain {
+            TimeGrain::Hour => ColumnFormat::Hour,
+            TimeGrain::Day => ColumnFormat::Day,
+            TimeGrain::Week { .. } => ColumnFormat::Week,
+            TimeGrain::Month => ColumnFormat::Month,
+            TimeGrain::Quarter => ColumnFormat::Quarter,
+            TimeGrain::Year => ColumnFormat::Year,
+        }),
+        _ => None,
+    }
+}
+
+fn metric_format(metric: &Metric) -> Option<ColumnFormat> {
+    match &metric.window {
+        Some(window) if window.window_type == WindowType::PercentOfTotal => {
+            Some(ColumnFormat::Percent)
+        }
+        _ => None,
+    }
+}
+
+//values that don't fit the type, such as text in a numeric column, are left as they are
+fn conform(value: AttributeValue, datatype: ColumnType) -> AttributeValue {
+    match (datatype, value) {
+        (ColumnType::Integer, AttributeValue::Float(number))
+            if number.fract() == 0.0 && number.abs() < 9.0e15 =>
+        {
+            AttributeValue::Int(number as i64)
+        }
+        (ColumnType::Integer, AttributeValue::Decimal(text) | AttributeValue::String(text))
+            if text.parse::<i64>().is_ok() =>
+        {
+            AttributeValue::Int(text.parse().unwrap_or_default())
+        }
+        (ColumnType::Float, AttributeValue::Int(number)) => AttributeValue::Float(number as f64),
+        (ColumnType::Float, AttributeValue::Decimal(text) | AttributeValue::String(text))
+            if text.parse::<f64>().is_ok() =>
+        {
+            AttributeValue::Float(text.parse().unwrap_or_default())
+        }
+        (ColumnType::Decimal, AttributeValue::Int(number)) => {
+            AttributeValue::Decimal(number.to_string())
+        }
+        (ColumnType::Decimal, AttributeValue::Float(number)) if number.is_finite() => {
+            AttributeValue::Decimal(number.to_string())
+        }
+        (ColumnType::Decimal, AttributeValue::String(text)) if text.parse::<f64>().is_ok() => {
+            AttributeValue::Decimal(text)
+        }
+        (ColumnType::Boolean, AttributeValue::Int(number)) => AttributeValue::Bool(number != 0),
+        (_, value) => value,
+    }
+}
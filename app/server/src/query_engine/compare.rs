@@ -0,0 +1,531 @@
+use super::relative_dates::{day_start_text, relative_days};
+use super::timezone::parse_naive;
+use super::{parse_transformation, DateSettings, QueryBuildError, Transformation};
+use crate::models::{
+    AttributeValue, ColumnFormat, ColumnType, Compare, ComparePeriod, DataRequest, DataResponse,
+    Filter, FilterExpression, FilterValue, ResponseColumn, ScalarValue, SqlQuery,
+};
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use std::collections::HashMap;
+
+//columns added next to every metric of a compared request
+const SUFFIXES: [&str; 3] = ["_previous", "_change", "_change_pct"];
+
+// how far the compared period lies before the requested one
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Shift {
+    //whole months keep calendar periods aligned, march is compared with february
+    Months(u32),
+    Duration(Duration),
+}
+
+// the query over the earlier period of a compared request. Its rows are matched with the shift
+// and the dates its filters were moved with, they aren't computed again once the rows are back
+pub struct CompareQuery {
+    pub sql_query: SqlQuery,
+    //rows the query returns at most
+    pub max_rows: i32,
+    pub(super) shift: Shift,
+    pub(super) dates: DateSettings,
+}
+
+// the request moved to the earlier period of `compare`, along with the shift and the dates it
+// was moved with. `field_datatype` is the type of the compared field
+pub(super) fn previous_request(
+    query: &DataRequest,
+    compare: &Compare,
+    field_datatype: &str,
+    max_rows: i32,
+    now: DateTime<Utc>,
+) -> Result<(DataRequest, Shift, DateSettings), QueryBuildError> {
+    check_columns(query)?;
+    let dates = DateSettings::from_request(query, now)?;
+    let shift = request_shift(query, compare, &dates)?;
+    let filters = match &query.filters {
+        Some(filters) => filters,
+        None => return Err(missing_filter(&compare.field)),
+    };
+    let shifted_filters = shift_expression(&compare.field, filters, shift, &dates, field_datatype)?;
+
+    //the earlier period isn't paged, its rows are matched to the requested page afterwards. In
+    //the order of the request so that the rows past max_rows are the least relevant ones
+    let previous = DataRequest {
+        filters: Some(shifted_filters),
+        having: None,
+        limit: Some(max_rows),
+        offset: None,
+        page: None,
+        page_size: None,
+        cursor: None,
+        include_total: None,
+        compare: None,
+        pivot: None,
+        ..query.clone()
+    };
+    Ok((previous, shift, dates))
+}
+
+fn request_shift(
+    query: &DataRequest,
+    compare: &Compare,
+    dates: &DateSettings,
+) -> Result<Shift, QueryBuildError> {
+    let filters = match &query.filters {
+        Some(filters) => filters,
+        None => return Err(missing_filter(&compare.field)),
+    };
+    let compare_filters = compare_filters(&compare.field, filters)?;
+    if compare_filters.is_empty() {
+        return Err(missing_filter(&compare.field));
+    }
+    match compare.period {
+        ComparePeriod::PreviousYear => Ok(Shift::Months(12)),
+        ComparePeriod::PreviousPeriod => period_length(&compare.field, &compare_filters, dates),
+    }
+}
+
+// adds the previous value, the change and the change in percent of every metric to the rows,
+// the previous rows are matched on the values of all dimensions. Time buckets of the compared
+// field are moved forward by the shift first, march of last year is matched with this march
+pub fn compare_rows(
+    query: &DataRequest,
+    response: &mut DataResponse,
+    previous: &DataResponse,
+    compare_query: &CompareQuery,
+) {
+    let dates = compare_query.dates;
+    //date parts like the month number are the same in both periods, they are matched as they are
+    let dimension_headers: Vec<(String, bool)> = query
+        .dimensions
+        .iter()
+        .flatten()
+        .map(|dimension| {
+            let time_bucket = query
+                .compare
+                .as_ref()
+                .is_some_and(|compare| compare.field == dimension.field)
+                && dimension.bins.is_none()
+                && dimension.top_n.is_none()
+                && !matches!(
+                    dimension
+                        .transformation
+                        .as_deref()
+                        .and_then(|name| parse_transformation(name, &dates)),
+                    Some(Transformation::Part(_))
+                );
+            let header = dimension.name.clone().unwrap_or(dimension.field.clone());
+            (header, time_bucket)
+        })
+        .collect();
+    let metric_headers: Vec<String> = query
+        .metrics
+        .iter()
+        .flatten()
+        .map(|metric| metric.name.clone().unwrap_or(metric.field.clone()))
+        .collect();
+    let row_key = |row: &HashMap<String, AttributeValue>, shift: Option<Shift>| {
+        let values: Vec<String> = dimension_headers
+            .iter()
+            .map(|(header, time_bucket)| {
+                let value = row.get(header).unwrap_or(&AttributeValue::NULL);
+                time_bucket
+                    .then(|| bucket_key(value, shift))
+                    .flatten()
+                    .unwrap_or_else(|| serde_json::to_string(value).unwrap_or_default())
+            })
+            .collect();
+        serde_json::to_string(&values).unwrap_or_default()
+    };
+    let previous_rows: HashMap<String, &HashMap<String, AttributeValue>> = previous
+        .data
+        .iter()
+        .map(|row| (row_key(row, Some(compare_query.shift)), row))
+        .collect();
+
+    for row in response.data.iter_mut() {
+        let previous_row = previous_rows.get(&row_key(row, None));
+        for header in &metric_headers {
+            let previous_value = previous_row
+                .and_then(|previous_row| previous_row.get(header))
+                .cloned()
+                .unwrap_or(AttributeValue::NULL);
+            let values = row
+                .get(header)
+                .and_then(attribute_to_number)
+                .zip(attribute_to_number(&previous_value));
+            let change = values.map(|(current, previous)| current - previous);
+            //no percentage against an empty previous period
+            let change_pct = values
+                .filter(|(_, previous)| *previous != 0.0)
+                .map(|(current, previous)| (current - previous) / previous.abs() * 100.0);
+
+            row.insert(format!("{}_previous", header), previous_value);
+            row.insert(format!("{}_change", header), number_to_attribute(change));
+            row.insert(
+                format!("{}_change_pct", header),
+                number_to_attribute(change_pct),
+            );
+        }
+    }
+}
+
+// the columns compare_rows adds for a metric's column, the changes are computed as floats
+pub(super) fn compare_columns(metric: &ResponseColumn) -> Vec<ResponseColumn> {
+    vec![
+        ResponseColumn {
+            name: format!("{}_previous", metric.name),
+            ..metric.clone()
+        },
+        ResponseColumn {
+            name: format!("{}_change", metric.name),
+            datatype: ColumnType::Float,
+            ..metric.clone()
+        },
+        ResponseColumn {
+            name: format!("{}_change_pct", metric.name),
+            datatype: ColumnType::Float,
+            format: Some(ColumnFormat::Percent),
+            ..metric.clone()
+        },
+    ]
+}
+
+// the moment a time bucket starts, moved forward by `shift` for the rows of the earlier period.
+// Month shifts end on the last day of shorter months, so the 29th to 31st of a day series may
+// meet on the same day
+fn bucket_key(value: &AttributeValue, shift: Option<Shift>) -> Option<String> {
+    let moment = match value {
+        AttributeValue::Date(date) => date.and_time(NaiveTime::MIN),
+        AttributeValue::String(text) | AttributeValue::Timestamp(text) => text_to_naive(text)?,
+        _ => return None,
+    };
+    let moment = match shift {
+        Some(Shift::Months(months)) => moment.checked_add_months(Months::new(months))?,
+        Some(Shift::Duration(duration)) => moment.checked_add_signed(duration)?,
+        None => moment,
+    };
+    Some(moment.format("%Y-%m-%d %H:%M:%S%.f").to_string())
+}
+
+fn attribute_to_number(value: &AttributeValue) -> Option<f64> {
+    match value {
+        AttributeValue::Int(number) => Some(*number as f64),
+        AttributeValue::Float(number) => Some(*number),
+        AttributeValue::Decimal(number) | AttributeValue::String(number) => number.parse().ok(),
+        _ => None,
+    }
+}
+
+fn number_to_attribute(number: Option<f64>) -> AttributeValue {
+    match number {
+        Some(number) => AttributeValue::Float(number),
+        None => AttributeValue::NULL,
+    }
+}
+
+fn check_columns(query: &DataRequest) -> Result<(), QueryBuildError> {
+    let metrics = query.metrics.as_deref().unwrap_or(&[]);
+    if metrics.is_empty() {
+        return Err(QueryBuildError::InvalidCompare(
+            "compare needs metrics".to_string(),
+        ));
+    }
+    let dimensions = query.dimensions.as_deref().unwrap_or(&[]);
+    let headers: Vec<&String> = dimensions
+        .iter()
+        .map(|dimension| dimension.name.as_ref().unwrap_or(&dimension.field))
+        .chain(
+            metrics
+                .iter()
+                .map(|metric| metric.name.as_ref().unwrap_or(&metric.field)),
+        )
+        .collect();
+    for metric in metrics {
+        let header = metric.name.as_ref().unwrap_or(&metric.field);
+        for suffix in SUFFIXES {
+            let added = format!("{}{}", header, suffix);
+            if headers.iter().any(|header| **header == added) {
+                return Err(QueryBuildError::InvalidCompare(format!(
+                    "'{}' is already a column of the request",
+                    added
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn missing_filter(field: &str) -> QueryBuildError {
+    QueryBuildError::InvalidCompare(format!("compare needs a filter on '{}'", field))
+}
+
+fn cant_shift(filter: &Filter) -> QueryBuildError {
+    QueryBuildError::InvalidCompare(format!(
+        "filter '{}' on '{}' can't be moved to an earlier period",
+        filter.filter_operator, filter.dimension.field
+    ))
+}
+
+// the filters on the compared field, they have to apply to every row so only and groups may hold them
+fn compare_filters<'a>(
+    field: &str,
+    filters: &'a FilterExpression,
+) -> Result<Vec<&'a Filter>, QueryBuildError> {
+    match filters {
+        FilterExpression::Filter(filter) if filter.dimension.field == field => Ok(vec![filter]),
+        FilterExpression::Filter(_) => Ok(Vec::new()),
+        FilterExpression::And { and: expressions } | FilterExpression::List(expressions) => {
+            let mut found = Vec::new();
+            for expression in expressions {
+                found.extend(compare_filters(field, expression)?);
+            }
+            Ok(found)
+        }
+        FilterExpression::Or { .. } | FilterExpression::Not { .. } => {
+            if filters
+                .filters()
+                .iter()
+                .any(|filter| filter.dimension.field == field)
+            {
+                return Err(QueryBuildError::InvalidCompare(format!(
+                    "filters on '{}' can only be combined with and",
+                    field
+                )));
+            }
+            Ok(Vec::new())
+        }
+    }
+}
+
+// length of the filtered range, whole months when it starts and ends on the first of a month
+fn period_length(
+    field: &str,
+    filters: &[&Filter],
+    dates: &DateSettings,
+) -> Result<Shift, QueryBuildError> {
+    let mut lower: Option<NaiveDateTime> = None;
+    let mut upper: Option<NaiveDateTime> = None;
+    for filter in filters {
+        let (from, to) = filter_bounds(filter, dates)?;
+        if let Some(from) = from {
+            lower = Some(lower.map_or(from, |lower| lower.max(from)));
+        }
+        if let Some(to) = to {
+            upper = Some(upper.map_or(to, |upper| upper.min(to)));
+        }
+    }
+    let (lower, upper) = match (lower, upper) {
+        (Some(lower), Some(upper)) if upper > lower => (lower, upper),
+        (Some(_), Some(_)) => {
+            return Err(QueryBuildError::InvalidCompare(format!(
+                "the filtered range of '{}' is empty",
+                field
+            )))
+        }
+        _ => {
+            return Err(QueryBuildError::InvalidCompare(format!(
+                "previous_period needs a start and an end filter on '{}'",
+                field
+            )))
+        }
+    };
+    let month_start = |moment: NaiveDateTime| moment.day() == 1 && moment.time() == NaiveTime::MIN;
+    if month_start(lower) && month_start(upper) {
+        let months =
+            (upper.year() - lower.year()) * 12 + upper.month() as i32 - lower.month() as i32;
+        if let Ok(months) = u32::try_from(months) {
+            return Ok(Shift::Months(months));
+        }
+    }
+    Ok(Shift::Duration(upper - lower))
+}
+
+// start and end of the range a filter keeps, an inclusive end on a date covers the whole day
+fn filter_bounds(
+    filter: &Filter,
+    dates: &DateSettings,
+) -> Result<(Option<NaiveDateTime>, Option<NaiveDateTime>), QueryBuildError> {
+    let field = &filter.dimension.field;
+    if let Some((start, end)) =
+        relative_days(field, &filter.filter_operator, &filter.filter_value, dates)?
+    {
+        return Ok((
+            Some(start.and_time(NaiveTime::MIN)),
+            Some(end.and_time(NaiveTime::MIN)),
+        ));
+    }
+    let start = |value: &ScalarValue| value_to_naive(value).ok_or_else(|| cant_shift(filter));
+    let end = |value: &ScalarValue| {
+        let moment = start(value)?;
+        match value {
+            ScalarValue::String(text) if is_date(text) => Ok(moment + Duration::days(1)),
+            _ => Ok(moment),
+        }
+    };
+    let operator = filter.filter_operator.to_lowercase();
+    let bounds = match (operator.as_str(), &filter.filter_value) {
+        (">=" | ">", Some(FilterValue::Scalar(value))) => (Some(start(value)?), None),
+        ("<", Some(FilterValue::Scalar(value))) => (None, Some(start(value)?)),
+        ("<=", Some(FilterValue::Scalar(value))) => (None, Some(end(value)?)),
+        ("=", Some(FilterValue::Scalar(value))) => (Some(start(value)?), Some(end(value)?)),
+        ("between", Some(FilterValue::Range { from, to })) => (Some(start(from)?), Some(end(to)?)),
+        ("between", Some(FilterValue::List(values))) if values.len() == 2 => {
+            (Some(start(&values[0])?), Some(end(&values[1])?))
+        }
+        _ => (None, None),
+    };
+    Ok(bounds)
+}
+
+// the filters with every filter on the compared field moved back by the shift
+fn shift_expression(
+    field: &str,
+    expression: &FilterExpression,
+    shift: Shift,
+    dates: &DateSettings,
+    field_datatype: &str,
+) -> Result<FilterExpression, QueryBuildError> {
+    let shift_all = |expressions: &Vec<FilterExpression>| {
+        expressions
+            .iter()
+            .map(|expression| shift_expression(field, expression, shift, dates, field_datatype))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let shifted = match expression {
+        FilterExpression::Filter(filter) if filter.dimension.field == field => {
+            shift_filter(filter, shift, dates, field_datatype)?
+        }
+        FilterExpression::And { and } => FilterExpression::And {
+            and: shift_all(and)?,
+        },
+        FilterExpression::List(expressions) => FilterExpression::List(shift_all(expressions)?),
+        //or and not groups don't hold the compared field, compare_filters checked that
+        expression => expression.clone(),
+    };
+    Ok(shifted)
+}
+
+fn shift_filter(
+    filter: &Filter,
+    shift: Shift,
+    dates: &DateSettings,
+    field_datatype: &str,
+) -> Result<FilterExpression, QueryBuildError> {
+    //date parts like the month number repeat every year, they can't be moved
+    if let Some(name) = &filter.dimension.transformation {
+        if let Some(Transformation::Part(_)) = parse_transformation(name, dates) {
+            return Err(cant_shift(filter));
+        }
+    }
+    let field = &filter.dimension.field;
+    //relative ranges are resolved here, the earlier period is a fixed range bound the same way
+    //as the requested one
+    if let Some((start, end)) =
+        relative_days(field, &filter.filter_operator, &filter.filter_value, dates)?
+    {
+        let bound = |operator: &str, day: NaiveDate| {
+            let moment = shift_naive(day.and_time(NaiveTime::MIN), shift)
+                .ok_or_else(|| cant_shift(filter))?;
+            Ok(FilterExpression::Filter(Filter {
+                dimension: filter.dimension.clone(),
+                filter_operator: operator.to_string(),
+                filter_value: Some(FilterValue::Scalar(ScalarValue::String(day_start_text(
+                    moment.date(),
+                    field_datatype,
+                )))),
+            }))
+        };
+        return Ok(FilterExpression::And {
+            and: vec![bound(">=", start)?, bound("<", end)?],
+        });
+    }
+
+    let shift_value = |value: &ScalarValue, inclusive_end: bool| {
+        shift_scalar(value, shift, inclusive_end).ok_or_else(|| cant_shift(filter))
+    };
+    let operator = filter.filter_operator.to_lowercase();
+    let filter_value = match &filter.filter_value {
+        Some(FilterValue::Scalar(value)) => {
+            Some(FilterValue::Scalar(shift_value(value, operator == "<=")?))
+        }
+        Some(FilterValue::List(values)) => {
+            let between = operator == "between";
+            Some(FilterValue::List(
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(position, value)| shift_value(value, between && position == 1))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ))
+        }
+        Some(FilterValue::Range { from, to }) => Some(FilterValue::Range {
+            from: shift_value(from, false)?,
+            to: shift_value(to, true)?,
+        }),
+        None => None,
+    };
+    Ok(FilterExpression::Filter(Filter {
+        filter_value,
+        ..filter.clone()
+    }))
+}
+
+//values with an offset keep it, dates stay dates. An inclusive end date is moved as the start of
+//the next day, so the end of february becomes the end of january
+fn shift_scalar(value: &ScalarValue, shift: Shift, inclusive_end: bool) -> Option<ScalarValue> {
+    let text = match value {
+        ScalarValue::String(text) => text.trim(),
+        _ => return None,
+    };
+    if let Ok(instant) = DateTime::parse_from_rfc3339(text) {
+        let shifted = match shift {
+            Shift::Months(months) => instant.checked_sub_months(Months::new(months)),
+            Shift::Duration(duration) => instant.checked_sub_signed(duration),
+        };
+        return shifted.map(|instant| ScalarValue::String(instant.to_rfc3339()));
+    }
+    let date = is_date(text);
+    let moment = match parse_naive(text)? {
+        moment if date && inclusive_end => {
+            shift_naive(moment + Duration::days(1), shift)? - Duration::days(1)
+        }
+        moment => shift_naive(moment, shift)?,
+    };
+    Some(ScalarValue::String(format_naive(moment, date)))
+}
+
+fn shift_naive(moment: NaiveDateTime, shift: Shift) -> Option<NaiveDateTime> {
+    match shift {
+        Shift::Months(months) => moment.checked_sub_months(Months::new(months)),
+        Shift::Duration(duration) => moment.checked_sub_signed(duration),
+    }
+}
+
+fn value_to_naive(value: &ScalarValue) -> Option<NaiveDateTime> {
+    match value {
+        ScalarValue::String(text) => text_to_naive(text),
+        _ => None,
+    }
+}
+
+//the wall clock time of values with an offset
+fn text_to_naive(text: &str) -> Option<NaiveDateTime> {
+    let text = text.trim();
+    match DateTime::parse_from_rfc3339(text) {
+        Ok(instant) => Some(instant.naive_local()),
+        Err(_) => parse_naive(text),
+    }
+}
+
+fn is_date(text: &str) -> bool {
+    NaiveDate::parse_from_str(text.trim(), "%Y-%m-%d").is_ok()
+}
+
+//a date when it was given as one and still is one after the shift
+fn format_naive(moment: NaiveDateTime, date: bool) -> String {
+    if date && moment.time() == NaiveTime::MIN {
+        moment.format("%Y-%m-%d").to_string()
+    } else {
+        moment.format("%Y-%m-%d %H:%M:%S%.f").to_string()
+    }
+}
@@ -0,0 +1,169 @@
+use super::{standard_aggregate, Aggregate, CastType, DatePart, Dialect, TimeGrain};
+
+pub struct ClickhouseDialect;
+
+impl Dialect for ClickhouseDialect {
+    fn name(&self) -> &'static str {
+        "ClickHouse"
+    }
+
+    fn quote_identifier(&self, identifier: &str) -> String {
+        format!("`{}`", identifier.replace('\\', "\\\\").replace('`', "\\`"))
+    }
+
+    fn quote_literal(&self, value: &str) -> String {
+        format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+    }
+
+    fn placeholder(&self, _index: usize) -> String {
+        "?".to_string()
+    }
+
+    fn date_part(&self, part: DatePart, expr: &str) -> String {
+        match part {
+            DatePart::Year => format!("toYear({})", expr),
+            DatePart::Month => format!("toMonth({})", expr),
+            DatePart::HourOfDay => format!("toHour({})", expr),
+            //toDayOfWeek is 1 for monday
+            DatePart::DayOfWeek { first_day } => {
+                format!("modulo(toDayOfWeek({}) + {}, 7) + 1", expr, 6 - first_day)
+            }
+        }
+    }
+
+    fn date_trunc(&self, grain: TimeGrain, expr: &str) -> String {
+        match grain {
+            TimeGrain::Hour => format!("toStartOfHour({})", expr),
+            TimeGrain::Day => format!("toStartOfDay({})", expr),
+            //toStartOfWeek only knows sunday and monday starts
+            TimeGrain::Week { first_day } => format!(
+                "toDateTime(subtractDays(toDate({}), modulo(toDayOfWeek({}) + {}, 7)))",
+                expr,
+                expr,
+                6 - first_day
+            ),
+            TimeGrain::Month => format!("toDateTime(toStartOfMonth({}))", expr),
+            TimeGrain::Quarter => format!("toDateTime(toStartOfQuarter({}))", expr),
+            TimeGrain::Year => format!("toDateTime(toStartOfYear({}))", expr),
+        }
+    }
+
+    //the date functions applied afterwards work in the zone of the converted value
+    fn to_timezone(&self, expr: &str, timezone: &str, _with_time_zone: bool) -> String {
+        format!("toTimeZone({}, {})", expr, self.quote_literal(timezone))
+    }
+
+    fn utc_timestamp(&self, value: &str, _with_time_zone: bool) -> String {
+        format!("toDateTime({}, 'UTC')", value)
+    }
+
+    fn limit_offset(&self, limit: Option<i64>, offset: Option<i64>) -> String {
+        match (limit, offset) {
+            (Some(limit), Some(offset)) => format!("LIMIT {} OFFSET {}", limit, offset),
+            (Some(limit), None) => format!("LIMIT {}", limit),
+            (None, Some(offset)) => format!("LIMIT 18446744073709551615 OFFSET {}", offset),
+            (None, None) => String::new(),
+        }
+    }
+
+    fn cast(&self, expr: &str, to: CastType) -> String {
+        let function = match to {
+            CastType::Integer => "toInt64",
+            CastType::Float => "toFloat64",
+            //46 digits before the point and 30 after
+            CastType::Decimal => return format!("toDecimal256({}, 30)", expr),
+            CastType::Text => "toString",
+            CastType::Date => "toDate",
+            CastType::Timestamp => "toDateTime",
+        };
+        format!("{}({})", function, expr)
+    }
+
+    //the native client only reads integers, floats and strings, also nullable or in arrays.
+    //anything else is sent as text and typed again by the row mapper
+    fn readable_column(&self, expr: &str, datatype: &str) -> Option<String> {
+        if native_datatype(datatype) {
+            return None;
+        }
+        Some(format!("CAST(toString({}) AS Nullable(String))", expr))
+    }
+
+    //only supported in the ON clause of joins, which is the one place it is used
+    fn null_safe_equal(&self, left: &str, right: &str) -> String {
+        format!("isNotDistinctFrom({}, {})", left, right)
+    }
+
+    fn ilike(&self, expr: &str, pattern: &str) -> String {
+        format!("{} ILIKE {}", self.cast(expr, CastType::Text), pattern)
+    }
+
+    fn aggregate(&self, aggregate: Aggregate, expr: &str) -> Option<String> {
+        let sql = match aggregate {
+            //quantile interpolates between values like percentile_cont
+            Aggregate::Median => format!("quantile(0.5)({})", expr),
+            Aggregate::Percentile(fraction) => format!("quantile({})({})", fraction, expr),
+            Aggregate::Stddev => format!("stddevSamp({})", expr),
+            Aggregate::Variance => format!("varSamp({})", expr),
+            Aggregate::CountDistinct => format!("uniqExact({})", expr),
+            Aggregate::ApproxCountDistinct => format!("uniq({})", expr),
+            aggregate => return standard_aggregate(aggregate, expr),
+        };
+        Some(sql)
+    }
+
+    //the -If combinator, distinct aggregates fall back to a case expression
+    fn filtered_aggregate(
+        &self,
+        aggregate: Aggregate,
+        expr: &str,
+        distinct: bool,
+        condition: &str,
+    ) -> Option<String> {
+        if distinct {
+            let expr = format!("DISTINCT CASE WHEN {} THEN {} END", condition, expr);
+            return self.aggregate(aggregate, &expr);
+        }
+        let sql = match aggregate {
+            Aggregate::Sum => format!("sumIf({}, {})", expr, condition),
+            Aggregate::Avg => format!("avgIf({}, {})", expr, condition),
+            Aggregate::Count => format!("countIf({} IS NOT NULL AND {})", expr, condition),
+            Aggregate::Max => format!("maxIf({}, {})", expr, condition),
+            Aggregate::Min => format!("minIf({}, {})", expr, condition),
+            Aggregate::Median => format!("quantileIf(0.5)({}, {})", expr, condition),
+            Aggregate::Percentile(fraction) => {
+                format!("quantileIf({})({}, {})", fraction, expr, condition)
+            }
+            Aggregate::Stddev => format!("stddevSampIf({}, {})", expr, condition),
+            Aggregate::Variance => format!("varSampIf({}, {})", expr, condition),
+            Aggregate::CountDistinct => format!("uniqExactIf({}, {})", expr, condition),
+            Aggregate::ApproxCountDistinct => format!("uniqIf({}, {})", expr, condition),
+        };
+        Some(sql)
+    }
+}
+
+//clickhouse types and the ones the query engine derives (int, bigint, text). Double is left
+//out as sums and formulas over decimal columns stay decimals in clickhouse
+fn native_datatype(datatype: &str) -> bool {
+    let datatype = datatype.trim().to_lowercase();
+    let scalar = |datatype: &str| {
+        datatype.starts_with("fixedstring(")
+            || matches!(
+                datatype,
+                "uint8" | "uint16" | "uint32" | "uint64" | "int8" | "int16" | "int32" | "int64"
+                    | "float32" | "float64" | "string" | "int" | "bigint" | "text"
+            )
+    };
+    let unwrap = |datatype: &str, wrapper: &str| {
+        datatype
+            .strip_prefix(wrapper)
+            .and_then(|inner| inner.strip_suffix(')'))
+            .map(str::to_string)
+    };
+    match (unwrap(&datatype, "nullable("), unwrap(&datatype, "array(")) {
+        (Some(inner), _) => scalar(&inner),
+        //arrays of nullable values can't be read
+        (_, Some(element)) => scalar(&element) && !element.starts_with("fixedstring("),
+        _ => scalar(&datatype),
+    }
+}
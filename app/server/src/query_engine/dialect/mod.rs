@@ -0,0 +1,201 @@
+// SQL flavour differences between the supported databases. The query engine only
+// talks to this trait, the implementation is picked from `DatabaseConfig.db_type`.
+mod clickhouse;
+mod mysql;
+mod postgres;
+
+pub use clickhouse::ClickhouseDialect;
+pub use mysql::MySqlDialect;
+pub use postgres::PostgresDialect;
+
+// parts extracted from a date as a number
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DatePart {
+    Year,
+    Month,
+    //0-23
+    HourOfDay,
+    //1-7 counted from the first day of the week, given in days after monday
+    DayOfWeek { first_day: u32 },
+}
+
+// precision a timestamp is truncated to, the result stays a timestamp so it sorts chronologically
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeGrain {
+    Hour,
+    Day,
+    //first day of the week in days after monday
+    Week { first_day: u32 },
+    Month,
+    Quarter,
+    Year,
+}
+
+// aggregate functions of a metric
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Sum,
+    Avg,
+    Count,
+    Max,
+    Min,
+    Median,
+    //fraction between 0 and 1, continuous like postgres' percentile_cont
+    Percentile(f64),
+    Stddev,
+    Variance,
+    CountDistinct,
+    ApproxCountDistinct,
+}
+
+// where nulls go in an ordering
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NullsPosition {
+    First,
+    Last,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CastType {
+    Integer,
+    Float,
+    //exact, wide enough for the values of any decimal column
+    Decimal,
+    Text,
+    Date,
+    Timestamp,
+}
+
+pub trait Dialect: Send + Sync {
+    // name of the database in error messages
+    fn name(&self) -> &'static str;
+
+    fn quote_identifier(&self, identifier: &str) -> String;
+
+    fn quote_literal(&self, value: &str) -> String;
+
+    // placeholder for the bound parameter at `index` (starting at 1)
+    fn placeholder(&self, index: usize) -> String;
+
+    fn date_part(&self, part: DatePart, expr: &str) -> String;
+
+    fn date_trunc(&self, grain: TimeGrain, expr: &str) -> String;
+
+    // wall clock time of a stored timestamp in the IANA `timezone`, timestamps without a zone are utc
+    fn to_timezone(&self, expr: &str, timezone: &str, with_time_zone: bool) -> String;
+
+    // a utc timestamp value (`YYYY-MM-DD HH:MM:SS`) compared with a stored timestamp
+    fn utc_timestamp(&self, value: &str, with_time_zone: bool) -> String;
+
+    fn limit_offset(&self, limit: Option<i64>, offset: Option<i64>) -> String;
+
+    fn cast(&self, expr: &str, to: CastType) -> String;
+
+    // datatype of the SUM of an integer column, the database widens it so that it stays exact
+    fn integer_sum_datatype(&self, _field_datatype: &str) -> &'static str {
+        "bigint"
+    }
+
+    // `expr`, a column of the result, converted to something the database driver can read.
+    // None if it is read as it is
+    fn readable_column(&self, _expr: &str, _datatype: &str) -> Option<String> {
+        None
+    }
+
+    // equality that holds for two nulls as well
+    fn null_safe_equal(&self, left: &str, right: &str) -> String {
+        format!("{} IS NOT DISTINCT FROM {}", left, right)
+    }
+
+    // case insensitive LIKE, the pattern is already a placeholder or literal
+    fn ilike(&self, expr: &str, pattern: &str) -> String;
+
+    // the aggregate over `expr`, None if the database has no such function
+    fn aggregate(&self, aggregate: Aggregate, expr: &str) -> Option<String> {
+        standard_aggregate(aggregate, expr)
+    }
+
+    // the aggregate over the rows matching `condition` only, `distinct` applies to `expr`
+    fn filtered_aggregate(
+        &self,
+        aggregate: Aggregate,
+        expr: &str,
+        distinct: bool,
+        condition: &str,
+    ) -> Option<String> {
+        //the aggregates skip the nulls of the rows that don't match
+        let expr = format!("CASE WHEN {} THEN {} END", condition, expr);
+        match distinct {
+            true => self.aggregate(aggregate, &format!("DISTINCT {}", expr)),
+            false => self.aggregate(aggregate, &expr),
+        }
+    }
+
+    // a single order by entry, postgres and clickhouse support NULLS FIRST/LAST as is
+    fn order_by(&self, expr: &str, descending: bool, nulls: Option<NullsPosition>) -> String {
+        let direction = if descending { "desc" } else { "asc" };
+        match nulls {
+            Some(NullsPosition::First) => format!("{} {} NULLS FIRST", expr, direction),
+            Some(NullsPosition::Last) => format!("{} {} NULLS LAST", expr, direction),
+            None => format!("{} {}", expr, direction),
+        }
+    }
+
+    // `<function> OVER (...)`, the order entries are rendered by order_by and the frame is a
+    // `ROWS BETWEEN ..` clause
+    fn window(
+        &self,
+        function: &str,
+        partition_by: &[String],
+        order_by: &[String],
+        frame: Option<&str>,
+    ) -> String {
+        let mut over = Vec::new();
+        if !partition_by.is_empty() {
+            over.push(format!("PARTITION BY {}", partition_by.join(", ")));
+        }
+        if !order_by.is_empty() {
+            over.push(format!("ORDER BY {}", order_by.join(", ")));
+        }
+        over.extend(frame.map(str::to_string));
+        format!("{} OVER ({})", function, over.join(" "))
+    }
+
+    // quotes every part of a `table.column` reference
+    fn quote_field(&self, field: &str) -> String {
+        field
+            .split('.')
+            .map(|part| self.quote_identifier(part))
+            .collect::<Vec<String>>()
+            .join(".")
+    }
+}
+
+pub fn get_dialect(db_type: &str) -> Box<dyn Dialect> {
+    match db_type {
+        "postgres" => Box::new(PostgresDialect),
+        "clickhouse" => Box::new(ClickhouseDialect),
+        //the engine used to only emit mysql flavoured sql, keep that as the fallback
+        _ => Box::new(MySqlDialect),
+    }
+}
+
+// aggregates the databases share, stddev and variance are the sample ones
+pub fn standard_aggregate(aggregate: Aggregate, expr: &str) -> Option<String> {
+    let sql = match aggregate {
+        Aggregate::Sum => format!("SUM({})", expr),
+        Aggregate::Avg => format!("AVG({})", expr),
+        Aggregate::Count => format!("COUNT({})", expr),
+        Aggregate::Max => format!("MAX({})", expr),
+        Aggregate::Min => format!("MIN({})", expr),
+        Aggregate::Stddev => format!("STDDEV_SAMP({})", expr),
+        Aggregate::Variance => format!("VAR_SAMP({})", expr),
+        Aggregate::CountDistinct => format!("COUNT(DISTINCT {})", expr),
+        Aggregate::Median | Aggregate::Percentile(_) | Aggregate::ApproxCountDistinct => {
+            return None
+        }
+    };
+    Some(sql)
+}
+
+
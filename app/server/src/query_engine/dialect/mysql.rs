@@ -0,0 +1,115 @@
+use super::{standard_aggregate, Aggregate, CastType, DatePart, Dialect, NullsPosition, TimeGrain};
+
+pub struct MySqlDialect;
+
+impl Dialect for MySqlDialect {
+    fn name(&self) -> &'static str {
+        "MySQL"
+    }
+
+    fn quote_identifier(&self, identifier: &str) -> String {
+        format!("`{}`", identifier.replace('`', "``"))
+    }
+
+    fn quote_literal(&self, value: &str) -> String {
+        format!("'{}'", value.replace('\\', "\\\\").replace('\'', "''"))
+    }
+
+    fn placeholder(&self, _index: usize) -> String {
+        "?".to_string()
+    }
+
+    fn date_part(&self, part: DatePart, expr: &str) -> String {
+        match part {
+            DatePart::Year => format!("YEAR({})", expr),
+            DatePart::Month => format!("MONTH({})", expr),
+            DatePart::HourOfDay => format!("HOUR({})", expr),
+            //WEEKDAY is 0 for monday
+            DatePart::DayOfWeek { first_day } => {
+                format!("MOD(WEEKDAY({}) + {}, 7) + 1", expr, 7 - first_day)
+            }
+        }
+    }
+
+    fn date_trunc(&self, grain: TimeGrain, expr: &str) -> String {
+        let start = match grain {
+            TimeGrain::Hour => format!("DATE_FORMAT({}, '%Y-%m-%d %H:00:00')", expr),
+            TimeGrain::Day => format!("DATE({})", expr),
+            TimeGrain::Week { first_day } => format!(
+                "DATE_SUB(DATE({}), INTERVAL MOD(WEEKDAY({}) + {}, 7) DAY)",
+                expr,
+                expr,
+                7 - first_day
+            ),
+            TimeGrain::Month => format!("DATE_FORMAT({}, '%Y-%m-01')", expr),
+            TimeGrain::Quarter => format!(
+                "MAKEDATE(YEAR({}), 1) + INTERVAL (QUARTER({}) - 1) QUARTER",
+                expr, expr
+            ),
+            TimeGrain::Year => format!("MAKEDATE(YEAR({}), 1)", expr),
+        };
+        self.cast(&start, CastType::Timestamp)
+    }
+
+    //named zones need the server's time zone tables to be loaded
+    fn to_timezone(&self, expr: &str, timezone: &str, _with_time_zone: bool) -> String {
+        format!("CONVERT_TZ({}, '+00:00', {})", expr, self.quote_literal(timezone))
+    }
+
+    fn utc_timestamp(&self, value: &str, _with_time_zone: bool) -> String {
+        value.to_string()
+    }
+
+    fn limit_offset(&self, limit: Option<i64>, offset: Option<i64>) -> String {
+        match (limit, offset) {
+            (Some(limit), Some(offset)) => format!("LIMIT {} OFFSET {}", limit, offset),
+            (Some(limit), None) => format!("LIMIT {}", limit),
+            //mysql has no offset without a limit
+            (None, Some(offset)) => format!("LIMIT 18446744073709551615 OFFSET {}", offset),
+            (None, None) => String::new(),
+        }
+    }
+
+    fn cast(&self, expr: &str, to: CastType) -> String {
+        let sql_type = match to {
+            CastType::Integer => "SIGNED",
+            CastType::Float => "DOUBLE",
+            CastType::Decimal => "DECIMAL(65, 30)",
+            CastType::Text => "CHAR",
+            CastType::Date => "DATE",
+            CastType::Timestamp => "DATETIME",
+        };
+        format!("CAST({} AS {})", expr, sql_type)
+    }
+
+    fn integer_sum_datatype(&self, _field_datatype: &str) -> &'static str {
+        "decimal"
+    }
+
+    fn null_safe_equal(&self, left: &str, right: &str) -> String {
+        format!("{} <=> {}", left, right)
+    }
+
+    fn ilike(&self, expr: &str, pattern: &str) -> String {
+        format!("LOWER({}) LIKE LOWER({})", expr, pattern)
+    }
+
+    //median and percentiles have no aggregate function in mysql
+    fn aggregate(&self, aggregate: Aggregate, expr: &str) -> Option<String> {
+        match aggregate {
+            //there is no approximate count, the exact one gives the same answer
+            Aggregate::ApproxCountDistinct => standard_aggregate(Aggregate::CountDistinct, expr),
+            aggregate => standard_aggregate(aggregate, expr),
+        }
+    }
+
+    //no NULLS FIRST/LAST, sort on the null check first
+    fn order_by(&self, expr: &str, descending: bool, nulls: Option<NullsPosition>) -> String {
+        let direction = if descending { "desc" } else { "asc" };
+        match nulls {
+            Some(NullsPosition::First) => format!("({} IS NULL) desc, {} {}", expr, expr, direction),
+            Some(NullsPosition::Last) => format!("({} IS NULL) asc, {} {}", expr, expr, direction),
+            None => format!("{} {}", expr, direction),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use super::{standard_aggregate, Aggregate, CastType, DatePart, Dialect, TimeGrain};
+
+pub struct PostgresDialect;
+
+impl Dialect for PostgresDialect {
+    fn name(&self) -> &'static str {
+        "PostgreSQL"
+    }
+
+    fn quote_identifier(&self, identifier: &str) -> String {
+        format!("\"{}\"", identifier.replace('"', "\"\""))
+    }
+
+    fn quote_literal(&self, value: &str) -> String {
+        format!("'{}'", value.replace('\'', "''"))
+    }
+
+    fn placeholder(&self, index: usize) -> String {
+        format!("${}", index)
+    }
+
+    fn date_part(&self, part: DatePart, expr: &str) -> String {
+        let extract = |field: &str| {
+            //extract returns a numeric, keep the integer the other databases return
+            self.cast(&format!("EXTRACT({} FROM {})", field, expr), CastType::Integer)
+        };
+        match part {
+            DatePart::Year => extract("YEAR"),
+            DatePart::Month => extract("MONTH"),
+            DatePart::HourOfDay => extract("HOUR"),
+            //ISODOW is 1 for monday
+            DatePart::DayOfWeek { first_day } => {
+                format!("MOD({} + {}, 7) + 1", extract("ISODOW"), 6 - first_day)
+            }
+        }
+    }
+
+    fn date_trunc(&self, grain: TimeGrain, expr: &str) -> String {
+        let field = match grain {
+            TimeGrain::Hour => "hour",
+            TimeGrain::Day => "day",
+            //date_trunc weeks start on monday, shift other week starts onto it and back
+            TimeGrain::Week { first_day: 0 } => "week",
+            TimeGrain::Week { first_day } => {
+                let shift = 7 - first_day;
+                return format!(
+                    "DATE_TRUNC('week', {} + INTERVAL '{} days') - INTERVAL '{} days'",
+                    expr, shift, shift
+                );
+            }
+            TimeGrain::Month => "month",
+            TimeGrain::Quarter => "quarter",
+            TimeGrain::Year => "year",
+        };
+        format!("DATE_TRUNC('{}', {})", field, expr)
+    }
+
+    fn to_timezone(&self, expr: &str, timezone: &str, with_time_zone: bool) -> String {
+        if with_time_zone {
+            format!("({} AT TIME ZONE {})", expr, self.quote_literal(timezone))
+        } else {
+            format!(
+                "(({} AT TIME ZONE 'UTC') AT TIME ZONE {})",
+                expr,
+                self.quote_literal(timezone)
+            )
+        }
+    }
+
+    //a timestamptz is compared as an instant, independent of the session's time zone
+    fn utc_timestamp(&self, value: &str, with_time_zone: bool) -> String {
+        if with_time_zone {
+            format!("({} AT TIME ZONE 'UTC')", self.cast(value, CastType::Timestamp))
+        } else {
+            value.to_string()
+        }
+    }
+
+    fn limit_offset(&self, limit: Option<i64>, offset: Option<i64>) -> String {
+        let mut sql = Vec::new();
+        if let Some(limit) = limit {
+            sql.push(format!("LIMIT {}", limit));
+        }
+        if let Some(offset) = offset {
+            sql.push(format!("OFFSET {}", offset));
+        }
+        sql.join(" ")
+    }
+
+    fn cast(&self, expr: &str, to: CastType) -> String {
+        let sql_type = match to {
+            CastType::Integer => "BIGINT",
+            CastType::Float => "DOUBLE PRECISION",
+            CastType::Decimal => "NUMERIC",
+            CastType::Text => "TEXT",
+            CastType::Date => "DATE",
+            CastType::Timestamp => "TIMESTAMP",
+        };
+        format!("CAST({} AS {})", expr, sql_type)
+    }
+
+    //sums of bigints are numeric, of smaller integers bigint
+    fn integer_sum_datatype(&self, field_datatype: &str) -> &'static str {
+        match crate::query_engine::base_datatype(field_datatype).as_str() {
+            "bigint" | "int8" | "bigserial" => "numeric",
+            _ => "bigint",
+        }
+    }
+
+    fn ilike(&self, expr: &str, pattern: &str) -> String {
+        format!("{} ILIKE {}", self.cast(expr, CastType::Text), pattern)
+    }
+
+    fn aggregate(&self, aggregate: Aggregate, expr: &str) -> Option<String> {
+        let percentile = |fraction: f64| {
+            format!("PERCENTILE_CONT({}) WITHIN GROUP (ORDER BY {})", fraction, expr)
+        };
+        match aggregate {
+            Aggregate::Median => Some(percentile(0.5)),
+            Aggregate::Percentile(fraction) => Some(percentile(fraction)),
+            //there is no approximate count built in, the exact one gives the same answer
+            Aggregate::ApproxCountDistinct => standard_aggregate(Aggregate::CountDistinct, expr),
+            aggregate => standard_aggregate(aggregate, expr),
+        }
+    }
+
+    fn filtered_aggregate(
+        &self,
+        aggregate: Aggregate,
+        expr: &str,
+        distinct: bool,
+        condition: &str,
+    ) -> Option<String> {
+        let aggregate_sql = match distinct {
+            true => self.aggregate(aggregate, &format!("DISTINCT {}", expr)),
+            false => self.aggregate(aggregate, expr),
+        };
+        aggregate_sql.map(|sql| format!("{} FILTER (WHERE {})", sql, condition))
+    }
+}
@@ -0,0 +1,143 @@
+// src/query_engine/errors.rs
+
+use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
+use derive_more::Display;
+use serde_json::json;
+
+// Everything that can go wrong while turning a DataRequest into SQL. These are all caused
+// by the request itself, so they are reported back as 400s instead of reaching the database.
+#[derive(Debug, Display, PartialEq)]
+pub enum QueryBuildError {
+    #[display(fmt = "Request has no metrics or dimensions")]
+    EmptyRequest,
+
+    #[display(fmt = "Table '{}' is not registered", _0)]
+    UnknownTable(String),
+
+    #[display(fmt = "Column '{}' not found or invalid input format", _0)]
+    UnknownColumn(String),
+
+    #[display(fmt = "Metric '{}' is not part of the request", _0)]
+    UnknownMetric(String),
+
+    #[display(fmt = "Invalid name '{}', names must be plain identifiers", _0)]
+    InvalidName(String),
+
+    #[display(fmt = "Unsupported filter operator '{}' for field '{}'", operator, field)]
+    UnsupportedOperator { field: String, operator: String },
+
+    #[display(fmt = "Filter value for field '{}' does not fit operator '{}'", field, operator)]
+    InvalidFilterValue { field: String, operator: String },
+
+    #[display(fmt = "Unsupported aggregation '{}' for field '{}'", aggregation, field)]
+    UnsupportedAggregation { field: String, aggregation: String },
+
+    #[display(fmt = "Aggregation '{}' of field '{}' is not supported on {}", aggregation, field, database)]
+    AggregationNotSupported {
+        field: String,
+        aggregation: String,
+        database: String,
+    },
+
+    #[display(fmt = "Unsupported transformation '{}' for field '{}'", transformation, field)]
+    UnsupportedTransformation {
+        field: String,
+        transformation: String,
+    },
+
+    #[display(fmt = "No relationship found to join tables {:?}", _0)]
+    NoJoinPath(Vec<String>),
+
+    #[display(fmt = "Value '{}' does not match type {} of field '{}'", value, datatype, field)]
+    TypeMismatch {
+        field: String,
+        datatype: String,
+        value: String,
+    },
+
+    #[display(fmt = "Field '{}' is not selected, grouped results can only be ordered by their dimensions and metrics", _0)]
+    UngroupedOrderBy(String),
+
+    #[display(fmt = "Unknown time zone '{}'", _0)]
+    InvalidTimezone(String),
+
+    #[display(
+        fmt = "Time zone '{}' can't be used, the time zone tables of the MySQL server are not loaded",
+        _0
+    )]
+    TimezoneNotLoaded(String),
+
+    #[display(fmt = "Invalid pagination: {}", _0)]
+    InvalidPagination(String),
+
+    #[display(fmt = "Cursor is invalid or belongs to a different request")]
+    InvalidCursor,
+
+    #[display(fmt = "Invalid compare: {}", _0)]
+    InvalidCompare(String),
+
+    #[display(fmt = "Invalid window: {}", _0)]
+    InvalidWindow(String),
+
+    #[display(fmt = "Invalid formula: {}", _0)]
+    InvalidFormula(String),
+
+    #[display(fmt = "Invalid bins: {}", _0)]
+    InvalidBins(String),
+
+    #[display(fmt = "Invalid top_n: {}", _0)]
+    InvalidTopN(String),
+
+    #[display(fmt = "Invalid pivot: {}", _0)]
+    InvalidPivot(String),
+
+    #[display(fmt = "Invalid export: {}", _0)]
+    InvalidExport(String),
+}
+
+impl QueryBuildError {
+    // machine readable code sent along with the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            QueryBuildError::EmptyRequest => "empty_request",
+            QueryBuildError::UnknownTable(_) => "unknown_table",
+            QueryBuildError::UnknownColumn(_) => "unknown_column",
+            QueryBuildError::UnknownMetric(_) => "unknown_metric",
+            QueryBuildError::InvalidName(_) => "invalid_name",
+            QueryBuildError::UnsupportedOperator { .. } => "unsupported_operator",
+            QueryBuildError::InvalidFilterValue { .. } => "invalid_filter_value",
+            QueryBuildError::UnsupportedAggregation { .. } => "unsupported_aggregation",
+            QueryBuildError::AggregationNotSupported { .. } => "aggregation_not_supported",
+            QueryBuildError::UnsupportedTransformation { .. } => "unsupported_transformation",
+            QueryBuildError::NoJoinPath(_) => "no_join_path",
+            QueryBuildError::TypeMismatch { .. } => "type_mismatch",
+            QueryBuildError::UngroupedOrderBy(_) => "ungrouped_order_by",
+            QueryBuildError::InvalidTimezone(_) => "invalid_timezone",
+            QueryBuildError::TimezoneNotLoaded(_) => "timezone_not_loaded",
+            QueryBuildError::InvalidPagination(_) => "invalid_pagination",
+            QueryBuildError::InvalidCursor => "invalid_cursor",
+            QueryBuildError::InvalidCompare(_) => "invalid_compare",
+            QueryBuildError::InvalidWindow(_) => "invalid_window",
+            QueryBuildError::InvalidFormula(_) => "invalid_formula",
+            QueryBuildError::InvalidBins(_) => "invalid_bins",
+            QueryBuildError::InvalidTopN(_) => "invalid_top_n",
+            QueryBuildError::InvalidPivot(_) => "invalid_pivot",
+            QueryBuildError::InvalidExport(_) => "invalid_export",
+        }
+    }
+}
+
+impl ResponseError for QueryBuildError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({
+            "error": {
+                "code": self.code(),
+                "message": self.to_string(),
+            }
+        }))
+    }
+}
@@ -0,0 +1,26 @@
+use super::QueryBuildError;
+use crate::models::DataRequest;
+
+// the request an export runs: every row up to `max_rows` in the flat layout of /api. The
+// file has no room for pages, a pivoted matrix or the rows of an earlier period
+pub fn export_request(query: &DataRequest, max_rows: i32) -> Result<DataRequest, QueryBuildError> {
+    let invalid = |message: String| Err(QueryBuildError::InvalidExport(message));
+    if query.cursor.is_some() || query.page.is_some() || query.page_size.is_some() {
+        return invalid("exports can't be paginated, use limit and offset".to_string());
+    }
+    if query.pivot.is_some() {
+        return invalid("pivoted results can't be exported".to_string());
+    }
+    if query.compare.is_some() {
+        return invalid("compared results can't be exported".to_string());
+    }
+    if query.limit.is_some_and(|limit| limit > max_rows) {
+        return invalid(format!("exports are limited to {} rows", max_rows));
+    }
+    Ok(DataRequest {
+        limit: Some(query.limit.unwrap_or(max_rows)),
+        include_total: None,
+        row_format: None,
+        ..query.clone()
+    })
+}
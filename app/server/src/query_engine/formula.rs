@@ -0,0 +1,302 @@
+use super::dialect::{CastType, Dialect};
+use super::{metric_to_sql, DateSettings, QueryBuildError};
+use crate::models::Metric;
+use std::collections::HashMap;
+
+//characters of a formula
+const MAX_FORMULA_LENGTH: usize = 1000;
+//parentheses, minus signs and formulas referring to formulas nested into each other, the parser
+//recurses once per level
+const MAX_FORMULA_DEPTH: usize = 32;
+
+// a formula like `revenue / (orders + 1)` split into its parts
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(String),
+    Name(String),
+    Operator(char),
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Number(text) | Token::Name(text) => write!(f, "{}", text),
+            Token::Operator(operator) => write!(f, "{}", operator),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+        }
+    }
+}
+
+// the expression of a formula metric and whether it aggregates. Names refer to the other
+// metrics of the request, divisions by zero give null
+pub(super) fn formula_to_sql(
+    metric: &Metric,
+    metrics: &[Metric],
+    field_datatype_map: &HashMap<&String, &str>,
+    dates: &DateSettings,
+    dialect: &dyn Dialect,
+) -> Result<(String, bool), QueryBuildError> {
+    let name = match &metric.name {
+        Some(name) => name,
+        None => return Err(invalid("formula metrics need a name".to_string())),
+    };
+    if !metric.field.is_empty()
+        || metric.aggregate_operator.is_some()
+        || metric.distinct.is_some()
+        || metric.filters.is_some()
+    {
+        return Err(invalid(format!(
+            "'{}' has a formula, it can't have a field, aggregate_operator, distinct or filters",
+            name
+        )));
+    }
+    let context = Context {
+        metrics,
+        field_datatype_map,
+        dates,
+        dialect,
+    };
+    let mut resolving = Vec::new();
+    let expanded = expand(metric, &context, &mut resolving, 0)?;
+    Ok((expanded.sql, expanded.aggregate))
+}
+
+// what the names of a formula are resolved against
+struct Context<'a> {
+    metrics: &'a [Metric],
+    field_datatype_map: &'a HashMap<&'a String, &'a str>,
+    dates: &'a DateSettings,
+    dialect: &'a dyn Dialect,
+}
+
+// the sql of a formula and the kinds of metrics it refers to, directly or through other formulas
+struct Expanded {
+    sql: String,
+    aggregate: bool,
+    plain: bool,
+}
+
+fn invalid(message: String) -> QueryBuildError {
+    QueryBuildError::InvalidFormula(message)
+}
+
+//`resolving` holds the formulas being expanded, a name showing up twice is a cycle. `depth` is
+//the nesting the formula starts at
+fn expand(
+    metric: &Metric,
+    context: &Context,
+    resolving: &mut Vec<String>,
+    depth: usize,
+) -> Result<Expanded, QueryBuildError> {
+    let name = metric.name.clone().unwrap_or_default();
+    let formula = metric.formula.as_deref().unwrap_or_default();
+    if resolving.contains(&name) {
+        return Err(invalid(format!("'{}' refers to itself", name)));
+    }
+    if formula.chars().count() > MAX_FORMULA_LENGTH {
+        return Err(invalid(format!(
+            "'{}' is longer than {} characters",
+            name, MAX_FORMULA_LENGTH
+        )));
+    }
+    resolving.push(name);
+    let tokens = tokenize(formula)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+        context,
+        resolving,
+        depth,
+        aggregate: false,
+        plain: false,
+    };
+    let sql = parser.expression()?;
+    if let Some(token) = tokens.get(parser.position) {
+        return Err(invalid(format!("unexpected '{}' in '{}'", token, formula)));
+    }
+    //an aggregate next to a plain column is neither grouped nor aggregated, the database refuses it
+    if parser.aggregate && parser.plain {
+        return Err(invalid(format!(
+            "'{}' mixes aggregated metrics with metrics that aren't aggregated",
+            metric.name.as_deref().unwrap_or_default()
+        )));
+    }
+    let expanded = Expanded {
+        sql,
+        aggregate: parser.aggregate,
+        plain: parser.plain,
+    };
+    resolving.pop();
+    Ok(expanded)
+}
+
+fn tokenize(formula: &str) -> Result<Vec<Token>, QueryBuildError> {
+    let mut tokens = Vec::new();
+    let mut chars = formula.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                chars.next();
+            }
+            '+' | '-' | '*' | '/' => {
+                tokens.push(Token::Operator(c));
+                chars.next();
+            }
+            '(' => {
+                tokens.push(Token::Open);
+                chars.next();
+            }
+            ')' => {
+                tokens.push(Token::Close);
+                chars.next();
+            }
+            '0'..='9' | '.' => {
+                let mut number = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                    number.push(c);
+                    chars.next();
+                }
+                //the literal is written into the sql as is, it has to be a plain number
+                if number.parse::<f64>().is_err() {
+                    return Err(invalid(format!("'{}' is not a number", number)));
+                }
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some(&c) = chars
+                    .peek()
+                    .filter(|c| c.is_ascii_alphanumeric() || **c == '_')
+                {
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Name(name));
+            }
+            c => return Err(invalid(format!("unexpected '{}' in '{}'", c, formula))),
+        }
+    }
+    if tokens.is_empty() {
+        return Err(invalid("formula is empty".to_string()));
+    }
+    Ok(tokens)
+}
+
+// recursive descent over `expression := term (+|- term)*`, `term := factor (*|/ factor)*`
+// and `factor := -factor | number | name | (expression)`
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    context: &'a Context<'a>,
+    resolving: &'a mut Vec<String>,
+    depth: usize,
+    //set once a referenced metric is an aggregate
+    aggregate: bool,
+    //set once a referenced metric isn't
+    plain: bool,
+}
+
+impl Parser<'_> {
+    fn next_operator(&mut self, operators: &[char]) -> Option<char> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(operator)) if operators.contains(operator) => {
+                self.position += 1;
+                Some(*operator)
+            }
+            _ => None,
+        }
+    }
+
+    fn expression(&mut self) -> Result<String, QueryBuildError> {
+        let mut sql = self.term()?;
+        while let Some(operator) = self.next_operator(&['+', '-']) {
+            let right = self.term()?;
+            sql = format!("({} {} {})", sql, operator, right);
+        }
+        Ok(sql)
+    }
+
+    fn term(&mut self) -> Result<String, QueryBuildError> {
+        let mut sql = self.factor()?;
+        while let Some(operator) = self.next_operator(&['*', '/']) {
+            let right = self.factor()?;
+            sql = match operator {
+                //a zero divisor gives null, the cast keeps integer counts from dividing as integers
+                '/' => format!(
+                    "({} / NULLIF({}, 0))",
+                    self.context.dialect.cast(&sql, CastType::Float),
+                    right
+                ),
+                _ => format!("({} * {})", sql, right),
+            };
+        }
+        Ok(sql)
+    }
+
+    fn factor(&mut self) -> Result<String, QueryBuildError> {
+        if self.depth >= MAX_FORMULA_DEPTH {
+            return Err(invalid(format!(
+                "formulas nest at most {} levels deep",
+                MAX_FORMULA_DEPTH
+            )));
+        }
+        self.depth += 1;
+        let sql = self.nested_factor();
+        self.depth -= 1;
+        sql
+    }
+
+    fn nested_factor(&mut self) -> Result<String, QueryBuildError> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Operator('-')) => Ok(format!("(-{})", self.factor()?)),
+            Some(Token::Number(number)) => Ok(number),
+            Some(Token::Name(name)) => self.metric(&name),
+            Some(Token::Open) => {
+                let sql = self.expression()?;
+                match self.tokens.get(self.position) {
+                    Some(Token::Close) => {
+                        self.position += 1;
+                        Ok(sql)
+                    }
+                    _ => Err(invalid("missing ')'".to_string())),
+                }
+            }
+            Some(token) => Err(invalid(format!("unexpected '{}'", token))),
+            None => Err(invalid("formula ends early".to_string())),
+        }
+    }
+
+    fn metric(&mut self, name: &str) -> Result<String, QueryBuildError> {
+        let context = self.context;
+        let metric = context
+            .metrics
+            .iter()
+            .find(|metric| metric.name.as_deref() == Some(name))
+            .ok_or_else(|| QueryBuildError::UnknownMetric(name.to_string()))?;
+        if metric.window.is_some() {
+            return Err(invalid(format!(
+                "'{}' is a window metric, formulas can't refer to it",
+                name
+            )));
+        }
+        if metric.formula.is_some() {
+            let expanded = expand(metric, context, self.resolving, self.depth)?;
+            self.aggregate |= expanded.aggregate;
+            self.plain |= expanded.plain;
+            return Ok(expanded.sql);
+        }
+        self.aggregate |= metric.aggregate_operator.is_some();
+        self.plain |= metric.aggregate_operator.is_none();
+        metric_to_sql(
+            metric,
+            context.field_datatype_map,
+            context.dates,
+            context.dialect,
+        )
+    }
+}
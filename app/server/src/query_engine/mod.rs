@@ -1,104 +1,642 @@
 use crate::models;
-use models::{Dimension, Filter, Metric, Table, OrderBy, Order};
+pub mod dialect;
+mod bins;
+mod columns;
+mod compare;
+mod errors;
+pub mod export;
+mod formula;
+mod pagination;
+mod pivot;
+mod relative_dates;
+mod timezone;
+mod top_n;
+mod window;
+#[cfg(test)]
+mod tests;
+use dialect::{get_dialect, Aggregate, CastType, DatePart, Dialect, NullsPosition, TimeGrain};
+pub use columns::{describe_columns, rows_to_arrays};
+pub use compare::{compare_rows, CompareQuery};
+pub use errors::QueryBuildError;
+pub use pagination::paginate;
+pub use pivot::pivot_rows;
+pub use timezone::{localize_timestamps, row_localizer};
+use models::{
+    Bins, Dimension, Filter, FilterExpression, FilterValue, HavingFilter, Metric, MetricReference, Nulls,
+    Order, OrderBy, ResultColumn, ScalarValue, SqlParam, SqlQuery, Table, Weekday,
+};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use std::collections::HashMap;
+use bins::{bins_to_sql, quantiles_join_sql};
+use formula::formula_to_sql;
+use relative_dates::{range_to_sql, relative_range};
+use timezone::{bind_timestamp, parse_timezone, TimeBasis};
+use top_n::{ranking_sql, top_n_to_sql};
+use window::{window_datatype, window_to_sql};
 
-pub fn get_query(query: &models::DataRequest, tables: &Vec<Table>) -> String {
-    let tables_json = serde_json::to_string_pretty(&tables).unwrap();
+// collects the values bound to a query and hands out the placeholder for each of them
+pub struct QueryParams<'a> {
+    dialect: &'a dyn Dialect,
+    params: Vec<SqlParam>,
+    //write the values into the sql as escaped literals instead of binding them
+    inline: bool,
+}
 
-    //get the tables setup
-    let tables: Vec<Table> = tables.iter().cloned().collect();
+impl<'a> QueryParams<'a> {
+    pub fn new(dialect: &'a dyn Dialect) -> Self {
+        QueryParams {
+            dialect,
+            params: Vec::new(),
+            inline: false,
+        }
+    }
 
-    //fetch the columns requested by user
-    let mut filter_fields = Vec::new();
-    let mut metric_fields = Vec::new();
-    let mut dimension_fields = Vec::new();
-    let mut order_fields = Vec::new();
-    let mut all_fields: Vec<String> = Vec::new();
-    let mut metrics_sql = String::new();
-    let mut dimensions_sql = String::new();
-    let mut dimensions_group_sql = String::new();
-    let mut order_sql = String::new();
+    // for expressions that are repeated across the query, placeholders are positional and
+    // would have to be bound again for every copy
+    pub fn inline(dialect: &'a dyn Dialect) -> Self {
+        QueryParams {
+            inline: true,
+            ..QueryParams::new(dialect)
+        }
+    }
 
-    if let Some(metrics) = &query.metrics {
-        metrics_sql = metrics_to_sql(metrics);
-        metric_fields = metrics.iter().map(|metric| metric.field.clone()).collect();
-        all_fields.extend(metric_fields);
+    pub fn bind(&mut self, param: SqlParam) -> String {
+        if self.inline {
+            return match param {
+                SqlParam::String(value) => self.dialect.quote_literal(&value),
+                SqlParam::Int(value) => value.to_string(),
+                SqlParam::Float(value) => value.to_string(),
+            };
+        }
+        self.params.push(param);
+        self.dialect.placeholder(self.params.len())
+    }
+}
+
+// a selected dimension or metric
+struct SelectColumn {
+    //name of the column in the response
+    header: String,
+    //requested field, before its transformation or aggregation
+    field: String,
+    //expression as written in the select list, without its alias
+    sql: String,
+    alias: Option<String>,
+    //expression used when comparing the column in a condition
+    condition_sql: String,
+    //expression the column is ordered by when it isn't the value itself, set for binned dimensions
+    sort_sql: Option<String>,
+    //expression grouped by along with the column, set for top_n dimensions
+    group_sql: Option<String>,
+    datatype: String,
+    aggregate: bool,
+    //computed across the result rows, it can't be used in conditions
+    window: bool,
+    //set when the column holds timestamps and the request has a time zone
+    timestamps: Option<(Tz, TimeBasis)>,
+}
+
+// everything of a data query but its ordering and limit
+struct SelectQuery {
+    columns: Vec<SelectColumn>,
+    from_sql: String,
+    where_sql: String,
+    group_sql: String,
+    having_sql: String,
+}
+
+pub fn get_query(
+    query: &models::DataRequest,
+    tables: &[Table],
+    db_type: &str,
+) -> Result<SqlQuery, QueryBuildError> {
+    get_query_at(query, tables, db_type, Utc::now())
+}
+
+// get_query with relative date filters resolved against `now`. The queries of a request are
+// built with the same `now`, so that they agree on today around midnight
+pub fn get_query_at(
+    query: &models::DataRequest,
+    tables: &[Table],
+    db_type: &str,
+    now: DateTime<Utc>,
+) -> Result<SqlQuery, QueryBuildError> {
+    let dialect = get_dialect(db_type);
+    let dialect = dialect.as_ref();
+    let mut params = QueryParams::new(dialect);
+    pivot::check_pivot(query)?;
+    let (limit, offset) = pagination::page_bounds(query)?;
+    let select = select_to_sql(query, tables, &mut params, now)?;
+
+    //columns the driver can't read are converted by an outer select, the inner one names its
+    //columns by position like the count query
+    let position_alias = |position: usize| dialect.quote_identifier(&format!("column_{}", position + 1));
+    let readable_columns: Vec<Option<String>> = select
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(position, column)| dialect.readable_column(&position_alias(position), &column.datatype))
+        .collect();
+    let converted = readable_columns.iter().any(Option::is_some);
+    let columns_sql: Vec<String> = select
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(position, column)| match &column.alias {
+            _ if converted => format!("{} as {}", column.sql, position_alias(position)),
+            Some(alias) => format!("{} as {}", column.sql, dialect.quote_identifier(alias)),
+            None => column.sql.clone(),
+        })
+        .collect();
+    let orderby = match &query.cursor {
+        Some(_) => pagination::cursor_orderby(query),
+        None => query.orderby.clone().unwrap_or_default(),
     };
-    if let Some(dimensions) = &query.dimensions {
-        dimensions_sql = dimensions_to_sql(dimensions, false);
-        dimensions_group_sql = "group by ".to_string() + &dimensions_to_sql(dimensions, true);
-        dimension_fields = dimensions
-            .iter()
-            .map(|dimension| dimension.field.clone())
+    let order_sql = orderby_to_sql(&orderby, &select, dialect)?;
+    //cursor pages fetch one extra row to tell whether there is a next page
+    let limit_sql = match &query.cursor {
+        Some(_) => dialect.limit_offset(limit.map(|limit| limit + 1), None),
+        None => dialect.limit_offset(limit, offset),
+    };
+
+    let mut sql = format!(
+        "select {} from {} {} {} {} {} {}",
+        columns_sql.join(", "),
+        select.from_sql,
+        select.where_sql,
+        select.group_sql,
+        select.having_sql,
+        order_sql,
+        limit_sql
+    );
+    if converted {
+        //the outer select keeps the order of the rows
+        let readable_sql: Vec<String> = readable_columns
+            .into_iter()
+            .enumerate()
+            .map(|(position, readable)| readable.unwrap_or(position_alias(position)))
             .collect();
-        all_fields.extend(dimension_fields);
+        sql = format!("select {} from ({}) as result_rows", readable_sql.join(", "), sql);
+    }
+    let sql = format!("{} ;", sql);
+    let columns = select
+        .columns
+        .iter()
+        .map(|column| ResultColumn {
+            name: column.header.clone(),
+            field: column.field.clone(),
+            datatype: column.datatype.clone(),
+            local_time: column.timestamps.is_some_and(|(_, basis)| basis == TimeBasis::Local),
+        })
+        .collect();
+    Ok(SqlQuery {
+        sql,
+        params: params.params,
+        columns,
+    })
+}
+
+// counts the rows of the whole result for `include_total`, ignoring the requested page
+pub fn get_count_query(
+    query: &models::DataRequest,
+    tables: &[Table],
+    db_type: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<SqlQuery>, QueryBuildError> {
+    if !query.include_total.unwrap_or(false) {
+        return Ok(None);
+    }
+    let dialect = get_dialect(db_type);
+    let dialect = dialect.as_ref();
+    let mut params = QueryParams::new(dialect);
+    let count_request = models::DataRequest {
+        cursor: None,
+        ..query.clone()
     };
+    let select = select_to_sql(&count_request, tables, &mut params, now)?;
+
+    //columns are renamed by position, mysql rejects duplicate names inside the subquery
+    let columns_sql: Vec<String> = select
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(position, column)| {
+            format!(
+                "{} as {}",
+                column.sql,
+                dialect.quote_identifier(&format!("column_{}", position + 1))
+            )
+        })
+        .collect();
+    let sql = format!(
+        "select count(*) as {} from (select {} from {} {} {} {}) as counted_rows ;",
+        dialect.quote_identifier("total_rows"),
+        columns_sql.join(", "),
+        select.from_sql,
+        select.where_sql,
+        select.group_sql,
+        select.having_sql
+    );
+    Ok(Some(SqlQuery {
+        sql,
+        params: params.params,
+        columns: Vec::new(),
+    }))
+}
+
+// the same query over the earlier period of `compare`, at most `max_rows` of its rows. They are
+// merged by compare_rows
+pub fn get_compare_query(
+    query: &models::DataRequest,
+    tables: &[Table],
+    db_type: &str,
+    max_rows: i32,
+    now: DateTime<Utc>,
+) -> Result<Option<CompareQuery>, QueryBuildError> {
+    let compare = match &query.compare {
+        Some(compare) => compare,
+        None => return Ok(None),
+    };
+    let field_datatype = find_column_datatype(tables, &compare.field)
+        .ok_or_else(|| QueryBuildError::UnknownColumn(compare.field.clone()))?;
+    let (previous_request, shift, dates) =
+        compare::previous_request(query, compare, field_datatype, max_rows, now)?;
+    Ok(Some(CompareQuery {
+        sql_query: get_query_at(&previous_request, tables, db_type, now)?,
+        max_rows,
+        shift,
+        dates,
+    }))
+}
+
+fn select_to_sql(
+    query: &models::DataRequest,
+    tables: &[Table],
+    params: &mut QueryParams,
+    now: DateTime<Utc>,
+) -> Result<SelectQuery, QueryBuildError> {
+    let dialect = params.dialect;
+    let dates = DateSettings::from_request(query, now)?;
+    //fetch the columns requested by user
+    let mut all_fields: Vec<String> = Vec::new();
 
+    //aliases end up in the sql text as they are, so they have to be plain identifiers
+    let aliases = query
+        .metrics
+        .iter()
+        .flatten()
+        .filter_map(|metric| metric.name.as_ref())
+        .chain(
+            query
+                .dimensions
+                .iter()
+                .flatten()
+                .filter_map(|dimension| dimension.name.as_ref()),
+        );
+    for alias in aliases {
+        if !is_valid_identifier(alias) {
+            return Err(QueryBuildError::InvalidName(alias.clone()));
+        }
+    }
+
+    if let Some(dimensions) = &query.dimensions {
+        all_fields.extend(dimensions.iter().map(|dimension| dimension.field.clone()));
+    };
+    if let Some(metrics) = &query.metrics {
+        all_fields.extend(
+            metrics
+                .iter()
+                .filter(|metric| metric.formula.is_none())
+                .map(|metric| metric.field.clone()),
+        );
+    };
     if let Some(filters) = &query.filters {
-        filter_fields = filters
-            .iter()
-            .map(|filter| filter.dimension.field.clone())
-            .collect();
-        all_fields.extend(filter_fields);
+        all_fields.extend(
+            filters
+                .filters()
+                .iter()
+                .map(|filter| filter.dimension.field.clone()),
+        );
     };
-    if let Some(order) = &query.orderby{
-        order_fields = order.field.clone();
-        order_sql = format!("order by {} {}",order_fields.join(","),match order.order {
-            Order::asc => "asc",
-            Order::desc => "desc",
+    if let Some(having) = &query.having {
+        //metrics referenced by name are already part of the request's metrics
+        all_fields.extend(having.filters().iter().filter_map(|filter| match &filter.metric {
+            MetricReference::Metric(metric) if metric.formula.is_none() => Some(metric.field.clone()),
+            MetricReference::Metric(_) | MetricReference::Name(_) => None,
+        }));
+    }
+    //fields filtered by a metric's own filters
+    let having_metrics = query
+        .having
+        .iter()
+        .flat_map(|having| having.filters())
+        .filter_map(|filter| match &filter.metric {
+            MetricReference::Metric(metric) => Some(metric.as_ref()),
+            MetricReference::Name(_) => None,
         });
-        //not validating the columns of order by field (we can do that once we start using the name provided for validation)
-        // all_fields.extend(order_fields);
+    for metric in query.metrics.iter().flatten().chain(having_metrics) {
+        all_fields.extend(
+            metric
+                .filters
+                .iter()
+                .flat_map(|filters| filters.filters())
+                .map(|filter| filter.dimension.field.clone()),
+        );
     }
+    //ordering by a column that isn't selected, it still has to exist and its table has to be joined
+    let headers: Vec<&String> = query
+        .dimensions
+        .iter()
+        .flatten()
+        .map(|dimension| dimension.name.as_ref().unwrap_or(&dimension.field))
+        .chain(
+            query
+                .metrics
+                .iter()
+                .flatten()
+                .map(|metric| metric.name.as_ref().unwrap_or(&metric.field)),
+        )
+        .collect();
+    all_fields.extend(
+        query
+            .orderby
+            .iter()
+            .flatten()
+            .filter(|order| !headers.contains(&&order.field))
+            .map(|order| order.field.clone()),
+    );
+
     //check if the columns are present in the tables or not, if present create a hashmap as well to get the datatype
     let mut field_datatype_map: HashMap<&String, &str> = HashMap::new();
     for field in &all_fields {
-        match find_column_datatype(&tables, field) {
+        match find_column_datatype(tables, field) {
             Some(datatype) => {
                 log::info!("Column datatype: {}", datatype);
                 field_datatype_map.insert(field, datatype);
             }
-            None => return format!("Column : {} not found or invald input format", field),
+            None => {
+                //report the table if that is what's missing, the column otherwise
+                let table_name = field.split('.').next().unwrap_or("");
+                if field.contains('.') && !tables.iter().any(|table| table.name == table_name) {
+                    return Err(QueryBuildError::UnknownTable(table_name.to_string()));
+                }
+                return Err(QueryBuildError::UnknownColumn(field.clone()));
+            }
         }
     }
 
-    let filters_sql = if let Some(filters) = &query.filters {
-        if filters.len() > 0{
-            filters_to_sql(filters, &field_datatype_map)
+    //get all the table names requested by the user & process them
+    let required_table_names = extract_table_columns(all_fields.clone());
+    let join_sql = handle_required_table(tables.to_vec(), required_table_names, dialect)?;
+    let mut from_sql = join_sql.clone();
+    //quantile bins and top_n join subqueries over the filtered rows, the filter values are
+    //written into them rather than bound a second time
+    let subquery_where_sql = match &query.filters {
+        Some(filters) => filters_to_sql(
+            filters,
+            &field_datatype_map,
+            &dates,
+            &mut QueryParams::inline(dialect),
+        )?,
+        None => String::new(),
+    };
+
+    let mut columns = Vec::new();
+    for (position, dimension) in query.dimensions.iter().flatten().enumerate() {
+        let field_datatype = field_datatype_map[&dimension.field];
+        let (sql, sort_sql, group_sql) = match (&dimension.bins, &dimension.top_n) {
+            (Some(_), Some(_)) => {
+                return Err(QueryBuildError::InvalidTopN(format!(
+                    "bins and top_n can't be combined (dimension '{}')",
+                    dimension.field
+                )))
+            }
+            (Some(bins), None) => {
+                let edges_table = format!("bins_{}", position + 1);
+                if let Bins::Quantiles { count } = bins {
+                    let edges_sql = quantiles_join_sql(
+                        dimension,
+                        *count,
+                        &edges_table,
+                        &join_sql,
+                        &subquery_where_sql,
+                        dialect,
+                    )?;
+                    from_sql = format!("{} {}", from_sql, edges_sql);
+                }
+                let (sql, sort_sql) =
+                    bins_to_sql(dimension, bins, field_datatype, &edges_table, dialect)?;
+                (sql, Some(sort_sql), None)
+            }
+            (None, Some(top_n)) => {
+                let dimension_sql = dimension_to_sql(dimension, field_datatype, &dates, dialect)?;
+                let ranking_sql = ranking_sql(
+                    top_n,
+                    query.metrics.as_deref().unwrap_or(&[]),
+                    &field_datatype_map,
+                    &dates,
+                    dialect,
+                )?;
+                let (ranked_join_sql, sql, ranked_sql) = top_n_to_sql(
+                    top_n,
+                    &dimension.field,
+                    &dimension_sql,
+                    &ranking_sql,
+                    &format!("{} {}", join_sql, subquery_where_sql),
+                    &format!("top_{}", position + 1),
+                    dialect,
+                )?;
+                from_sql = format!("{} {}", from_sql, ranked_join_sql);
+                (sql, None, Some(ranked_sql))
+            }
+            (None, None) => (
+                dimension_to_sql(dimension, field_datatype, &dates, dialect)?,
+                None,
+                None,
+            ),
+        };
+        let datatype = dimension_datatype(dimension, field_datatype);
+        columns.push(SelectColumn {
+            header: dimension.name.clone().unwrap_or(dimension.field.clone()),
+            field: dimension.field.clone(),
+            condition_sql: sql.clone(),
+            sort_sql,
+            group_sql,
+            sql,
+            alias: dimension.name.clone(),
+            timestamps: dates.timestamps(&datatype, dimension_time_basis(dimension, field_datatype)),
+            datatype,
+            aggregate: false,
+            window: false,
+        });
+    }
+    //so far the columns are the dimensions
+    let group_sql = if columns.is_empty() {
+        String::new()
+    } else {
+        //bins are grouped by their sort key as well so that they can be ordered by it
+        let group_columns: Vec<&str> = columns
+            .iter()
+            .flat_map(|column| {
+                std::iter::once(&column.sql)
+                    .chain(&column.sort_sql)
+                    .chain(&column.group_sql)
+            })
+            .map(String::as_str)
+            .collect();
+        format!("group by {}", group_columns.join(", "))
+    };
+    let metrics = query.metrics.as_deref().unwrap_or(&[]);
+    for metric in metrics {
+        if metric.formula.is_some() {
+            let (sql, aggregate) =
+                formula_to_sql(metric, metrics, &field_datatype_map, &dates, dialect)?;
+            columns.push(SelectColumn {
+                header: metric.name.clone().unwrap_or_default(),
+                field: metric.field.clone(),
+                condition_sql: dialect.cast(&sql, CastType::Float),
+                sort_sql: None,
+                group_sql: None,
+                sql,
+                alias: metric.name.clone(),
+                datatype: FORMULA_DATATYPE.to_string(),
+                aggregate,
+                window: metric.window.is_some(),
+                timestamps: None,
+            });
+            continue;
         }
-        else {
-            String::new()
+        let field_datatype = field_datatype_map[&metric.field];
+        let datatype = metric_datatype(metric, field_datatype, dialect);
+        columns.push(SelectColumn {
+            header: metric.name.clone().unwrap_or(metric.field.clone()),
+            field: metric.field.clone(),
+            sql: metric_to_sql(metric, &field_datatype_map, &dates, dialect)?,
+            alias: metric.name.clone(),
+            condition_sql: metric_condition_sql(
+                metric,
+                &datatype,
+                &field_datatype_map,
+                &dates,
+                dialect,
+            )?,
+            sort_sql: None,
+            group_sql: None,
+            timestamps: dates.timestamps(&datatype, stored_time_basis(field_datatype)),
+            datatype,
+            aggregate: metric.aggregate_operator.is_some(),
+            window: metric.window.is_some(),
+        });
+    }
+    //windows are added once all columns are known, they may be ordered by any of them
+    let first_metric = columns.len() - query.metrics.as_ref().map_or(0, Vec::len);
+    for (position, metric) in query.metrics.iter().flatten().enumerate() {
+        if let Some(window) = &metric.window {
+            let column = &columns[first_metric + position];
+            let sql = window_to_sql(metric, window, &column.sql, &columns, dialect)?;
+            let column = &mut columns[first_metric + position];
+            column.condition_sql = sql.clone();
+            column.sql = sql;
         }
-    } else {
-        String::new()
+    }
+
+    //the cursor condition goes into the having clause as soon as it compares an aggregate,
+    //it is bound where it appears in the text since `?` placeholders are positional
+    let cursor_columns = match &query.cursor {
+        Some(_) => pagination::cursor_columns(&pagination::cursor_orderby(query), &columns)?,
+        None => Vec::new(),
+    };
+    let cursor_in_having = cursor_columns.iter().any(|(column, _)| column.aggregate);
+    let cursor_sql = |params: &mut QueryParams| match &query.cursor {
+        Some(cursor) => pagination::cursor_to_sql(cursor, &cursor_columns, params),
+        None => Ok(None),
     };
 
-    let limit_sql = if let Some(limit) = &query.limit{
-        format!("limit {}",limit) 
+    let mut where_sql = match &query.filters {
+        Some(filters) => filters_to_sql(filters, &field_datatype_map, &dates, params)?,
+        None => String::new(),
+    };
+    if !cursor_in_having {
+        if let Some(condition_sql) = cursor_sql(params)? {
+            where_sql = add_condition(where_sql, "where", &condition_sql);
+        }
     }
-    else{
-        String::new()   
+    let mut having_sql = match &query.having {
+        Some(having) => {
+            having_to_sql(having, metrics, &field_datatype_map, &dates, params)?
+        }
+        None => String::new(),
     };
-    //get all the table names requested by the user & process them
-    let required_table_names = extract_table_columns(all_fields);
-    let table_sql = handle_required_table(tables, required_table_names);
+    if cursor_in_having {
+        if let Some(condition_sql) = cursor_sql(params)? {
+            having_sql = add_condition(having_sql, "having", &condition_sql);
+        }
+    }
+
+    Ok(SelectQuery {
+        columns,
+        from_sql,
+        where_sql,
+        group_sql,
+        having_sql,
+    })
+}
 
-    //generate final mysql query
-    let mut comma = String::new();
-    if dimensions_sql != "" && metrics_sql != "" {
-        comma = ",".to_string();
+//appends a condition to a where/having clause that may still be empty
+fn add_condition(clause_sql: String, keyword: &str, condition_sql: &str) -> String {
+    if clause_sql.is_empty() {
+        format!("{} {}", keyword, condition_sql)
+    } else {
+        format!("{} and {}", clause_sql, condition_sql)
+    }
+}
+
+// orders by the selected columns (by alias or field) or by any registered column,
+// the latter are already validated and joined by select_to_sql
+fn orderby_to_sql(
+    orderby: &[OrderBy],
+    select: &SelectQuery,
+    dialect: &dyn Dialect,
+) -> Result<String, QueryBuildError> {
+    if orderby.is_empty() {
+        return Ok(String::new());
+    }
+    let grouped = !select.group_sql.is_empty() || select.columns.iter().any(|column| column.aggregate);
+    let mut sql_fields = Vec::new();
+    for order in orderby {
+        //the expression is repeated instead of using the alias so that it can be wrapped for nulls
+        let expression_sql = match select.columns.iter().find(|column| column.header == order.field) {
+            Some(column) => column.sort_sql.clone().unwrap_or(column.sql.clone()),
+            None if grouped => return Err(QueryBuildError::UngroupedOrderBy(order.field.clone())),
+            None => dialect.quote_field(&order.field),
+        };
+        let nulls = order.nulls.map(|nulls| match nulls {
+            Nulls::First => NullsPosition::First,
+            Nulls::Last => NullsPosition::Last,
+        });
+        sql_fields.push(dialect.order_by(&expression_sql, order.order == Order::desc, nulls));
+    }
+    Ok(format!("order by {}", sql_fields.join(", ")))
+}
+
+//names that are written into the sql text (aliases, order by fields) must be plain identifiers
+pub fn is_valid_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
     }
-    format!(
-        "select {} {} {} from {} {} {} {} {} ;",
-        dimensions_sql, comma, metrics_sql, table_sql, filters_sql, dimensions_group_sql,order_sql,limit_sql
-    )
 }
 
 //this function takes required tables and registered tables and provide the joined table
 pub fn handle_required_table(
     registered_table: Vec<Table>,
     required_table_names: Vec<String>,
-) -> String {
+    dialect: &dyn Dialect,
+) -> Result<String, QueryBuildError> {
     //from the tables vector filter the required tables to join
 
     //check if required tables are registered or not
@@ -108,9 +646,8 @@ pub fn handle_required_table(
         .cloned()
         .collect();
 
-    if !missing_tables.is_empty() {
-        // Throw an error indicating missing table names
-        panic!("Tables are missing: {:?}", missing_tables);
+    if let Some(missing_table) = missing_tables.first() {
+        return Err(QueryBuildError::UnknownTable(missing_table.clone()));
     }
 
     let table_needed: Vec<Table> = registered_table
@@ -119,11 +656,13 @@ pub fn handle_required_table(
         .cloned()
         .collect();
 
-    if let Some(query) = generate_join_query(&table_needed) {
-        query
-        // Execute the query using the MySQL client of your choice
-    } else {
-        format!("Unable to generate join query due to missing relationship.")
+    if table_needed.is_empty() {
+        return Err(QueryBuildError::EmptyRequest);
+    }
+
+    match generate_join_query(&table_needed, dialect) {
+        Some(query) => Ok(query),
+        None => Err(QueryBuildError::NoJoinPath(required_table_names)),
     }
 }
 
@@ -139,130 +678,631 @@ pub fn extract_table_columns(columns: Vec<String>) -> Vec<String> {
     table_list
 }
 
-// Function to convert metrics to SQL columns string.
-pub fn metrics_to_sql(metrics: &Vec<Metric>) -> String {
-    let mut sql_columns = Vec::new();
-    let valid_aggregations = ["sum", "avg", "count", "max", "min"];
+// the aggregate expression of a single metric, without its alias
+pub fn metric_to_sql(
+    metric: &Metric,
+    field_datatype_map: &HashMap<&String, &str>,
+    dates: &DateSettings,
+    dialect: &dyn Dialect,
+) -> Result<String, QueryBuildError> {
+    let field_sql = dialect.quote_field(&metric.field);
+    let condition_sql = metric_filters_to_sql(metric, field_datatype_map, dates, dialect)?;
+    let operator = match (&metric.aggregate_operator, &condition_sql) {
+        (Some(operator), _) => operator,
+        //without an aggregate the rows that don't match are null
+        (None, Some(condition_sql)) => {
+            return Ok(format!("CASE WHEN {} THEN {} END", condition_sql, field_sql))
+        }
+        (None, None) => return Ok(field_sql),
+    };
+    let unsupported = || QueryBuildError::UnsupportedAggregation {
+        field: metric.field.clone(),
+        aggregation: operator.clone(),
+    };
+    let aggregate = parse_aggregate(operator).ok_or_else(unsupported)?;
+    //distinct only changes the plain aggregates, the distinct counts already are
+    let distinct = match (metric.distinct.unwrap_or(false), aggregate) {
+        (true, Aggregate::Sum | Aggregate::Avg | Aggregate::Count | Aggregate::Max | Aggregate::Min) => {
+            true
+        }
+        (true, Aggregate::CountDistinct | Aggregate::ApproxCountDistinct) | (false, _) => false,
+        (true, _) => {
+            return Err(QueryBuildError::UnsupportedAggregation {
+                field: metric.field.clone(),
+                aggregation: format!("distinct {}", operator),
+            })
+        }
+    };
+    let metric_sql = match &condition_sql {
+        Some(condition_sql) => {
+            dialect.filtered_aggregate(aggregate, &field_sql, distinct, condition_sql)
+        }
+        None if distinct => dialect.aggregate(aggregate, &format!("DISTINCT {}", field_sql)),
+        None => dialect.aggregate(aggregate, &field_sql),
+    };
+    metric_sql.ok_or_else(|| QueryBuildError::AggregationNotSupported {
+        field: metric.field.clone(),
+        aggregation: operator.clone(),
+        database: dialect.name().to_string(),
+    })
+}
+
+// the condition of a metric's own filters. The metric is repeated in having, order by, windows
+// and formulas, so its values are written into the sql instead of being bound
+fn metric_filters_to_sql(
+    metric: &Metric,
+    field_datatype_map: &HashMap<&String, &str>,
+    dates: &DateSettings,
+    dialect: &dyn Dialect,
+) -> Result<Option<String>, QueryBuildError> {
+    let filters = match &metric.filters {
+        Some(filters) => filters,
+        None => return Ok(None),
+    };
+    let mut params = QueryParams::inline(dialect);
+    filter_expression_to_sql(filters, &mut |filter: &Filter| {
+        filter_to_sql(filter, field_datatype_map, dates, &mut params)
+    })
+}
 
-    for metric in metrics {
-        let distinct = metric.distinct.map(|dist| if dist { "DISTINCT".to_string() } else { String::new() }).unwrap_or(String::new());
-        
-        match &metric.aggregate_operator {
-            Some(operator) => {
-                let uppercase_aggregate = operator.to_uppercase();
-                let aggregate_str = operator.as_str();
-
-                if valid_aggregations.contains(&aggregate_str) {
-                    let aggregate_str_without_underscore = uppercase_aggregate.replace("_", " ");
-                    let column_sql = match &metric.name {
-                        
-                        Some(nm) => {
-                            format!("{}({}({})) as {}", aggregate_str_without_underscore,distinct, &metric.field, nm)
-                        }
-                        None => {
-                            format!("{}({}({}))", aggregate_str_without_underscore,distinct, &metric.field)
-                        }
-                    };
-
-                    sql_columns.push(column_sql);
-                } else {
-                    log::info!("Unknown aggregation function for column '{}'", metric.field);
-                }
-            }
-            None => {
-                let column_sql = format!("({})", metric.field);
-                sql_columns.push(column_sql);
-            }
+// `sum`, `median`, `p90` ... as requested in aggregate_operator
+fn parse_aggregate(operator: &str) -> Option<Aggregate> {
+    let aggregate = match operator {
+        "sum" => Aggregate::Sum,
+        "avg" => Aggregate::Avg,
+        "count" => Aggregate::Count,
+        "max" => Aggregate::Max,
+        "min" => Aggregate::Min,
+        "median" => Aggregate::Median,
+        "stddev" => Aggregate::Stddev,
+        "variance" => Aggregate::Variance,
+        "count_distinct" => Aggregate::CountDistinct,
+        "approx_count_distinct" => Aggregate::ApproxCountDistinct,
+        //p1 up to p99
+        _ => match operator.strip_prefix('p').map(str::parse::<u8>) {
+            Some(Ok(percent @ 1..=99)) => Aggregate::Percentile(f64::from(percent) / 100.0),
+            _ => return None,
+        },
+    };
+    Some(aggregate)
+}
+
+// datatype of a metric's value, used to bind having values
+fn metric_datatype(metric: &Metric, field_datatype: &str, dialect: &dyn Dialect) -> String {
+    let aggregate = metric.aggregate_operator.as_deref().and_then(parse_aggregate);
+    let datatype = match aggregate {
+        Some(Aggregate::Count | Aggregate::CountDistinct | Aggregate::ApproxCountDistinct) => {
+            "bigint".to_string()
+        }
+        //sums of decimals and integers stay exact
+        Some(Aggregate::Sum) if matches!(base_datatype(field_datatype).as_str(), "decimal" | "numeric") => {
+            field_datatype.to_string()
         }
+        Some(Aggregate::Sum) if value_kind(field_datatype) == ValueKind::Integer => {
+            dialect.integer_sum_datatype(field_datatype).to_string()
+        }
+        Some(
+            Aggregate::Sum
+            | Aggregate::Avg
+            | Aggregate::Median
+            | Aggregate::Percentile(_)
+            | Aggregate::Stddev
+            | Aggregate::Variance,
+        ) => "double".to_string(),
+        Some(Aggregate::Max | Aggregate::Min) | None => field_datatype.to_string(),
+    };
+    match &metric.window {
+        Some(window) => window_datatype(window, &datatype),
+        None => datatype,
     }
-    sql_columns.join(", ")
-}
-
-pub fn dimensions_to_sql(dimensions: &Vec<Dimension>, group: bool) -> String {
-    let mut sql_columns = Vec::new();
-    let valid_transformations = ["year", "month","date"];
-    for dimension in dimensions {
-        match &dimension.transformation {
-            Some(operator) => {
-                let uppercase_transformation = operator.to_uppercase();
-                let transformation_str = operator.as_str();
-                if valid_transformations.contains(&transformation_str) {
-                    let column_sql = match &dimension.name {
-                        Some(nm) => {
-                            if group {
-                                format!("{}({})", uppercase_transformation, &dimension.field)
-                            } else {
-                                format!(
-                                    "{}({}) as {}",
-                                    uppercase_transformation, &dimension.field, nm
-                                )
-                            }
-                        }
-                        None => {
-                            format!("{}({})", uppercase_transformation, &dimension.field)
-                        }
-                    };
-                    sql_columns.push(column_sql);
-                } else {
-                    log::info!(
-                        "Unknown aggregation function for column '{}'",
-                        dimension.field
-                    );
-                }
-            }
-            None => {
-                let column_sql = format!("({})", dimension.field);
-                sql_columns.push(column_sql);
+}
+
+//formulas are computed as floating point numbers
+const FORMULA_DATATYPE: &str = "double";
+
+// the aggregate as it is compared in conditions
+fn metric_condition_sql(
+    metric: &Metric,
+    datatype: &str,
+    field_datatype_map: &HashMap<&String, &str>,
+    dates: &DateSettings,
+    dialect: &dyn Dialect,
+) -> Result<String, QueryBuildError> {
+    let metric_sql = metric_to_sql(metric, field_datatype_map, dates, dialect)?;
+    if metric.aggregate_operator.is_some() && value_kind(datatype) == ValueKind::Float {
+        //sum of an integer column would otherwise make postgres expect an integer value
+        return Ok(dialect.cast(&metric_sql, CastType::Float));
+    }
+    Ok(metric_sql)
+}
+
+// request wide settings of the date transformations
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DateSettings {
+    pub week_start: Weekday,
+    pub timezone: Option<Tz>,
+    //relative date filters are resolved against this moment
+    pub now: DateTime<Utc>,
+}
+
+impl DateSettings {
+    // the settings of the request with relative dates resolved against `now`
+    pub fn from_request(
+        query: &models::DataRequest,
+        now: DateTime<Utc>,
+    ) -> Result<Self, QueryBuildError> {
+        Ok(DateSettings {
+            week_start: query.week_start.unwrap_or_default(),
+            timezone: query.timezone.as_deref().map(parse_timezone).transpose()?,
+            now,
+        })
+    }
+
+    // how values compared with a column of this datatype are bound, None unless it holds
+    // timestamps and the request has a time zone
+    fn timestamps(&self, datatype: &str, basis: TimeBasis) -> Option<(Tz, TimeBasis)> {
+        match temporal_kind(datatype) {
+            Some(TemporalKind::Timestamp | TemporalKind::TimestampTz) => {
+                self.timezone.map(|timezone| (timezone, basis))
             }
+            _ => None,
         }
     }
-    sql_columns.join(", ")
+}
+
+fn stored_time_basis(field_datatype: &str) -> TimeBasis {
+    TimeBasis::Stored {
+        with_time_zone: temporal_kind(field_datatype) == Some(TemporalKind::TimestampTz),
+    }
+}
+
+//transformed timestamps are shifted into the request's time zone before they are bucketed
+fn dimension_time_basis(dimension: &Dimension, field_datatype: &str) -> TimeBasis {
+    match dimension.transformation {
+        Some(_) => TimeBasis::Local,
+        None => stored_time_basis(field_datatype),
+    }
+}
+
+// what a dimension transformation does to its column
+enum Transformation {
+    //a number such as the year or the hour of the day
+    Part(DatePart),
+    //the start of the bucket, still a timestamp
+    Trunc(TimeGrain),
+    Date,
+}
+
+fn parse_transformation(name: &str, dates: &DateSettings) -> Option<Transformation> {
+    let first_day = dates.week_start.days_from_monday();
+    let transformation = match name {
+        "year" => Transformation::Part(DatePart::Year),
+        "month" => Transformation::Part(DatePart::Month),
+        "hour_of_day" => Transformation::Part(DatePart::HourOfDay),
+        "day_of_week" => Transformation::Part(DatePart::DayOfWeek { first_day }),
+        "date" => Transformation::Date,
+        "hour" | "date_trunc_hour" => Transformation::Trunc(TimeGrain::Hour),
+        "day" | "date_trunc_day" => Transformation::Trunc(TimeGrain::Day),
+        "week" | "date_trunc_week" => Transformation::Trunc(TimeGrain::Week { first_day }),
+        "quarter" | "date_trunc_quarter" => Transformation::Trunc(TimeGrain::Quarter),
+        "date_trunc_month" => Transformation::Trunc(TimeGrain::Month),
+        "date_trunc_year" => Transformation::Trunc(TimeGrain::Year),
+        _ => return None,
+    };
+    Some(transformation)
+}
+
+// datatype of a dimension's value after its transformation
+fn dimension_datatype(dimension: &Dimension, field_datatype: &str) -> String {
+    //date parts are compared as numbers rather than as the column's type
+    if dimension.bins.is_some() || dimension.top_n.is_some() {
+        return "text".to_string();
+    }
+    let transformation = dimension
+        .transformation
+        .as_deref()
+        .and_then(|name| parse_transformation(name, &DateSettings::default()));
+    match transformation {
+        Some(Transformation::Part(_)) => "int".to_string(),
+        Some(Transformation::Trunc(_)) => "timestamp".to_string(),
+        Some(Transformation::Date) => "date".to_string(),
+        None => field_datatype.to_string(),
+    }
+}
+
+// the dimension's expression, without its alias
+pub fn dimension_to_sql(
+    dimension: &Dimension,
+    field_datatype: &str,
+    dates: &DateSettings,
+    dialect: &dyn Dialect,
+) -> Result<String, QueryBuildError> {
+    let mut field_sql = dialect.quote_field(&dimension.field);
+    let operator = match &dimension.transformation {
+        Some(operator) => operator,
+        None => return Ok(field_sql),
+    };
+    let temporal = temporal_kind(field_datatype);
+    if let (Some(timezone), Some(TemporalKind::Timestamp | TemporalKind::TimestampTz)) =
+        (dates.timezone, temporal)
+    {
+        let with_time_zone = temporal == Some(TemporalKind::TimestampTz);
+        field_sql = dialect.to_timezone(&field_sql, timezone.name(), with_time_zone);
+    }
+    match parse_transformation(operator, dates) {
+        Some(Transformation::Part(part)) => Ok(dialect.date_part(part, &field_sql)),
+        Some(Transformation::Trunc(grain)) => Ok(dialect.date_trunc(grain, &field_sql)),
+        Some(Transformation::Date) => Ok(dialect.cast(&field_sql, CastType::Date)),
+        None => Err(QueryBuildError::UnsupportedTransformation {
+            field: dimension.field.clone(),
+            transformation: operator.clone(),
+        }),
+    }
 }
 
 pub fn filters_to_sql(
-    filters: &Vec<Filter>,
+    filters: &FilterExpression,
     field_datatype_map: &HashMap<&String, &str>,
-) -> String {
+    dates: &DateSettings,
+    params: &mut QueryParams,
+) -> Result<String, QueryBuildError> {
+    let filter_sql = filter_expression_to_sql(filters, &mut |filter: &Filter| {
+        filter_to_sql(filter, field_datatype_map, dates, params)
+    })?;
+    match filter_sql {
+        Some(filter_sql) => Ok(format!("where {}", filter_sql)),
+        None => Ok(String::new()),
+    }
+}
+
+// conditions on aggregated metrics, rendered after the group by
+pub fn having_to_sql(
+    having: &FilterExpression<HavingFilter>,
+    metrics: &[Metric],
+    field_datatype_map: &HashMap<&String, &str>,
+    dates: &DateSettings,
+    params: &mut QueryParams,
+) -> Result<String, QueryBuildError> {
+    let having_sql = filter_expression_to_sql(having, &mut |filter: &HavingFilter| {
+        //the aggregate is repeated instead of using the alias, postgres doesn't allow aliases in having
+        let metric = match &filter.metric {
+            MetricReference::Name(name) => metrics
+                .iter()
+                .find(|metric| metric.name.as_ref() == Some(name))
+                .ok_or_else(|| QueryBuildError::UnknownMetric(name.clone()))?,
+            MetricReference::Metric(metric) => metric,
+        };
+        //windows are computed after having, they can only be filtered in an outer query
+        if metric.window.is_some() {
+            return Err(QueryBuildError::InvalidWindow(format!(
+                "window metric '{}' can't be used in having",
+                metric.name.as_ref().unwrap_or(&metric.field)
+            )));
+        }
+        let label = metric.name.clone().unwrap_or(metric.field.clone());
+        let (metric_sql, datatype, timestamps) = if metric.formula.is_some() {
+            let (sql, _) =
+                formula_to_sql(metric, metrics, field_datatype_map, dates, params.dialect)?;
+            let sql = params.dialect.cast(&sql, CastType::Float);
+            (sql, FORMULA_DATATYPE.to_string(), None)
+        } else {
+            let field_datatype = field_datatype_map
+                .get(&metric.field)
+                .ok_or_else(|| QueryBuildError::UnknownColumn(metric.field.clone()))?;
+            let datatype = metric_datatype(metric, field_datatype, params.dialect);
+            let sql = metric_condition_sql(
+                metric,
+                &datatype,
+                field_datatype_map,
+                dates,
+                params.dialect,
+            )?;
+            let timestamps = dates.timestamps(&datatype, stored_time_basis(field_datatype));
+            (sql, datatype, timestamps)
+        };
+        if let Some(range) = relative_range(
+            &label,
+            &datatype,
+            &filter.filter_operator,
+            &filter.filter_value,
+            dates,
+        )? {
+            return range_to_sql(&label, &metric_sql, &datatype, range, timestamps, params);
+        }
+        condition_to_sql(
+            &label,
+            &metric_sql,
+            &datatype,
+            &filter.filter_operator,
+            &filter.filter_value,
+            timestamps,
+            params,
+        )
+    })?;
+    match having_sql {
+        Some(having_sql) => Ok(format!("having {}", having_sql)),
+        None => Ok(String::new()),
+    }
+}
+
+//renders a filter tree, groups are parenthesized and empty groups are left out (None)
+pub fn filter_expression_to_sql<T, F>(
+    expression: &FilterExpression<T>,
+    leaf_to_sql: &mut F,
+) -> Result<Option<String>, QueryBuildError>
+where
+    F: FnMut(&T) -> Result<String, QueryBuildError>,
+{
+    let (expressions, joiner) = match expression {
+        FilterExpression::Filter(filter) => return Ok(Some(leaf_to_sql(filter)?)),
+        FilterExpression::Not { not } => {
+            return Ok(filter_expression_to_sql(not, leaf_to_sql)?
+                .map(|filter_sql| format!("not ({})", filter_sql)))
+        }
+        FilterExpression::And { and: expressions } | FilterExpression::List(expressions) => {
+            (expressions, " and ")
+        }
+        FilterExpression::Or { or: expressions } => (expressions, " or "),
+    };
+
     let mut sql_filters = Vec::new();
-    let valid_operators = [">", "<", "="];
-    for filter in filters {
-        let mut datatype_field = String::new();
-        match field_datatype_map.get(&filter.dimension.field.to_string()) {
-            Some(datatype) => datatype_field = datatype.to_string(),
-            None => return format!("Field '{}' not found in the map", &filter.dimension.field),
-        }
-        // Choose the format string based on the datatype
-        // let format_string = match datatype_field.as_str() {
-        //     "varchar" => "{} {} \"{}\"",
-        //     "int" | "bigint" => "{} {} {}",
-        //     _ => panic!("Unsupported datatype"), // Add appropriate handling for other datatypes if needed
-        // };
-        if valid_operators.contains(&filter.filter_operator.as_str()) {
-            let filter_sql = match datatype_field.as_str() {
-                "varchar" | "datetime" => format!(
-                    "{} {} \"{}\"",
-                    dimensions_to_sql(&vec![filter.dimension.clone()], true),
-                    filter.filter_operator.to_uppercase(),
-                    filter.filter_value
-                ),
-                "int" | "bigint" | "float" => format!(
-                    "{} {} {}",
-                    dimensions_to_sql(&vec![filter.dimension.clone()], true),
-                    filter.filter_operator.to_uppercase(),
-                    filter.filter_value
-                ),
-                _ => panic!("Unsupported datatype"),
+    for expression in expressions {
+        if let Some(filter_sql) = filter_expression_to_sql(expression, leaf_to_sql)? {
+            sql_filters.push(filter_sql);
+        }
+    }
+    match sql_filters.len() {
+        0 => Ok(None),
+        1 => Ok(sql_filters.pop()),
+        _ => Ok(Some(format!("({})", sql_filters.join(joiner)))),
+    }
+}
+
+pub fn filter_to_sql(
+    filter: &Filter,
+    field_datatype_map: &HashMap<&String, &str>,
+    dates: &DateSettings,
+    params: &mut QueryParams,
+) -> Result<String, QueryBuildError> {
+    let field_datatype = match field_datatype_map.get(&filter.dimension.field) {
+        Some(datatype) => *datatype,
+        None => return Err(QueryBuildError::UnknownColumn(filter.dimension.field.clone())),
+    };
+    if filter.dimension.bins.is_some() {
+        return Err(QueryBuildError::InvalidBins(format!(
+            "filters compare the field itself, they can't have bins (dimension '{}')",
+            filter.dimension.field
+        )));
+    }
+    if filter.dimension.top_n.is_some() {
+        return Err(QueryBuildError::InvalidTopN(format!(
+            "filters compare the field itself, they can't have top_n (dimension '{}')",
+            filter.dimension.field
+        )));
+    }
+    let datatype_field = dimension_datatype(&filter.dimension, field_datatype);
+    let field_sql = dimension_to_sql(&filter.dimension, field_datatype, dates, params.dialect)?;
+    //untransformed timestamps are compared as stored, the bounds are converted instead
+    let timestamps = dates.timestamps(
+        &datatype_field,
+        dimension_time_basis(&filter.dimension, field_datatype),
+    );
+    if let Some(range) = relative_range(
+        &filter.dimension.field,
+        &datatype_field,
+        &filter.filter_operator,
+        &filter.filter_value,
+        dates,
+    )? {
+        return range_to_sql(
+            &filter.dimension.field,
+            &field_sql,
+            &datatype_field,
+            range,
+            timestamps,
+            params,
+        );
+    }
+    condition_to_sql(
+        &filter.dimension.field,
+        &field_sql,
+        &datatype_field,
+        &filter.filter_operator,
+        &filter.filter_value,
+        timestamps,
+        params,
+    )
+}
+
+// `<expression> <operator> <value>` for both where and having conditions,
+// `field` only names the expression in errors
+fn condition_to_sql(
+    field: &str,
+    field_sql: &str,
+    datatype_field: &str,
+    filter_operator: &str,
+    filter_value: &Option<FilterValue>,
+    timestamps: Option<(Tz, TimeBasis)>,
+    params: &mut QueryParams,
+) -> Result<String, QueryBuildError> {
+    let operator = filter_operator.to_lowercase();
+    let invalid_value = || QueryBuildError::InvalidFilterValue {
+        field: field.to_string(),
+        operator: filter_operator.to_string(),
+    };
+    let bind = |value: &ScalarValue, params: &mut QueryParams| {
+        bind_scalar(field, datatype_field, value, timestamps, params)
+    };
+
+    let filter_sql = match (operator.as_str(), filter_value) {
+        ("=" | "!=" | "<>" | ">" | "<" | ">=" | "<=", Some(FilterValue::Scalar(value))) => {
+            let sql_operator = if operator == "!=" { "<>" } else { operator.as_str() };
+            format!("{} {} {}", field_sql, sql_operator, bind(value, params)?)
+        }
+        ("in" | "not_in", Some(FilterValue::List(values))) if !values.is_empty() => {
+            let mut placeholders = Vec::new();
+            for value in values {
+                placeholders.push(bind(value, params)?);
+            }
+            let sql_operator = if operator == "in" { "IN" } else { "NOT IN" };
+            format!("{} {} ({})", field_sql, sql_operator, placeholders.join(", "))
+        }
+        ("between", Some(FilterValue::Range { from, to })) => format!(
+            "{} BETWEEN {} AND {}",
+            field_sql,
+            bind(from, params)?,
+            bind(to, params)?
+        ),
+        ("between", Some(FilterValue::List(values))) if values.len() == 2 => format!(
+            "{} BETWEEN {} AND {}",
+            field_sql,
+            bind(&values[0], params)?,
+            bind(&values[1], params)?
+        ),
+        //search box operators, matched case insensitively
+        ("contains" | "starts_with" | "ends_with", Some(FilterValue::Scalar(value))) => {
+            let escaped = escape_like_pattern(&value.to_string());
+            let pattern = match operator.as_str() {
+                "contains" => format!("%{}%", escaped),
+                "starts_with" => format!("{}%", escaped),
+                _ => format!("%{}", escaped),
             };
+            let placeholder = params.bind(SqlParam::String(pattern));
+            params.dialect.ilike(field_sql, &placeholder)
+        }
+        ("is_null", _) => format!("{} IS NULL", field_sql),
+        ("is_not_null", _) => format!("{} IS NOT NULL", field_sql),
+        (
+            "=" | "!=" | "<>" | ">" | "<" | ">=" | "<=" | "in" | "not_in" | "between" | "contains"
+            | "starts_with" | "ends_with",
+            _,
+        ) => return Err(invalid_value()),
+        _ => {
+            return Err(QueryBuildError::UnsupportedOperator {
+                field: field.to_string(),
+                operator: filter_operator.to_string(),
+            })
+        }
+    };
+    Ok(filter_sql)
+}
 
-            sql_filters.push(filter_sql)
-        } else {
-            log::info!(
-                "Unknown filter operator for column '{}'",
-                filter.dimension.field
-            );
+// the placeholder for a value compared with an expression of the given datatype, timestamps
+// are moved from the request's time zone to the basis of the expression
+fn bind_scalar(
+    field: &str,
+    datatype: &str,
+    value: &ScalarValue,
+    timestamps: Option<(Tz, TimeBasis)>,
+    params: &mut QueryParams,
+) -> Result<String, QueryBuildError> {
+    if let (Some((timezone, basis)), ScalarValue::String(text)) = (timestamps, value) {
+        if let Some(value_sql) = bind_timestamp(text, timezone, basis, params) {
+            return Ok(value_sql);
+        }
+    }
+    let param = scalar_to_param(datatype, value).ok_or_else(|| QueryBuildError::TypeMismatch {
+        field: field.to_string(),
+        datatype: datatype.to_string(),
+        value: value.to_string(),
+    })?;
+    Ok(params.bind(param))
+}
+
+//LIKE wildcards typed by the user are matched literally
+fn escape_like_pattern(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// how values of a column are parsed before they are bound
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    Integer,
+    Float,
+    Boolean,
+    Text,
+}
+
+//lowercased type without clickhouse wrappers, length and precision
+pub fn base_datatype(datatype: &str) -> String {
+    let mut datatype = datatype.trim().to_lowercase();
+    //Nullable(..) and LowCardinality(..) wrap the actual clickhouse type
+    for wrapper in ["nullable(", "lowcardinality("] {
+        if datatype.starts_with(wrapper) && datatype.ends_with(')') {
+            datatype = datatype[wrapper.len()..datatype.len() - 1].to_string();
+        }
+    }
+    //drop length and precision, e.g. varchar(255) or decimal(10,2)
+    datatype.split('(').next().unwrap_or("").trim().to_string()
+}
+
+//maps the datatype registered for a column (as reported by mysql, postgres or clickhouse)
+pub fn value_kind(datatype: &str) -> ValueKind {
+    match base_datatype(datatype).as_str() {
+        "int" | "integer" | "bigint" | "smallint" | "tinyint" | "mediumint" | "int2" | "int4"
+        | "int8" | "uint8" | "uint16" | "uint32" | "uint64" | "int16" | "int32" | "int64"
+        | "serial" | "bigserial" => ValueKind::Integer,
+        "float" | "double" | "real" | "double precision" | "numeric" | "decimal" | "float4"
+        | "float8" | "float32" | "float64" => ValueKind::Float,
+        "boolean" | "bool" => ValueKind::Boolean,
+        _ => ValueKind::Text,
+    }
+}
+
+// how a column stores points in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemporalKind {
+    Date,
+    Timestamp,
+    TimestampTz,
+}
+
+pub fn temporal_kind(datatype: &str) -> Option<TemporalKind> {
+    match base_datatype(datatype).as_str() {
+        "date" | "date32" => Some(TemporalKind::Date),
+        "datetime" | "datetime64" | "timestamp" | "timestamp without time zone" => {
+            Some(TemporalKind::Timestamp)
+        }
+        "timestamptz" | "timestamp with time zone" => Some(TemporalKind::TimestampTz),
+        _ => None,
+    }
+}
+
+//converts a user supplied filter value into a bound parameter of the column's type,
+//types that aren't numeric are bound as strings and parsed by the database
+fn scalar_to_param(datatype: &str, value: &ScalarValue) -> Option<SqlParam> {
+    match (value_kind(datatype), value) {
+        (ValueKind::Integer | ValueKind::Boolean, ScalarValue::Bool(flag)) => {
+            Some(SqlParam::Int(*flag as i64))
+        }
+        (ValueKind::Integer, ScalarValue::Number(number)) => number.as_i64().map(SqlParam::Int),
+        (ValueKind::Integer, ScalarValue::String(text)) => {
+            text.trim().parse::<i64>().ok().map(SqlParam::Int)
+        }
+        (ValueKind::Float, ScalarValue::Number(number)) => number.as_f64().map(SqlParam::Float),
+        (ValueKind::Float, ScalarValue::String(text)) => text
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|number| number.is_finite())
+            .map(SqlParam::Float),
+        (ValueKind::Float, ScalarValue::Bool(_)) => None,
+        (ValueKind::Boolean, ScalarValue::Number(number)) => match number.as_i64() {
+            Some(flag @ (0 | 1)) => Some(SqlParam::Int(flag)),
+            _ => None,
+        },
+        (ValueKind::Boolean, ScalarValue::String(text)) => {
+            match text.trim().to_lowercase().as_str() {
+                "true" | "t" | "1" => Some(SqlParam::Int(1)),
+                "false" | "f" | "0" => Some(SqlParam::Int(0)),
+                _ => None,
+            }
         }
+        (ValueKind::Text, value) => Some(SqlParam::String(value.to_string())),
     }
-    format!("where {}", sql_filters.join(" and "))
 }
 
 fn find_relationship<'a>(
@@ -274,12 +1314,12 @@ fn find_relationship<'a>(
         .find(|relationship| relationship.contains_key(child_table))
 }
 
-fn generate_join_query(tables: &[Table]) -> Option<String> {
+fn generate_join_query(tables: &[Table], dialect: &dyn Dialect) -> Option<String> {
     if tables.is_empty() {
         return None;
     }
 
-    let mut query = format!("{}", tables[0].name);
+    let mut query = dialect.quote_identifier(&tables[0].name);
 
     for i in 1..tables.len() {
         let table = &tables[i];
@@ -294,13 +1334,20 @@ fn generate_join_query(tables: &[Table]) -> Option<String> {
                     .map(|(_, (parent_col, child_col))| {
                         format!(
                             "{}.{} = {}.{}",
-                            prev_table.name, parent_col, table.name, child_col
+                            dialect.quote_identifier(&prev_table.name),
+                            dialect.quote_identifier(parent_col),
+                            dialect.quote_identifier(&table.name),
+                            dialect.quote_identifier(child_col)
                         )
                     })
                     .collect::<Vec<String>>()
                     .join(" AND ");
 
-                query += &format!(" JOIN {} ON {}", table.name, join_condition);
+                query += &format!(
+                    " JOIN {} ON {}",
+                    dialect.quote_identifier(&table.name),
+                    join_condition
+                );
                 join_found = true;
                 // break;
             }
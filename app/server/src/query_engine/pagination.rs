@@ -0,0 +1,230 @@
+use super::dialect::CastType;
+use super::{base_datatype, bind_scalar, QueryBuildError, QueryParams, SelectColumn};
+use crate::models::{
+    AttributeValue, DataRequest, DataResponse, Order, OrderBy, ScalarValue, SqlParam,
+};
+
+// limit and offset of the requested page, page/page_size take the place of limit/offset
+pub fn page_bounds(query: &DataRequest) -> Result<(Option<i64>, Option<i64>), QueryBuildError> {
+    let invalid = |message: &str| Err(QueryBuildError::InvalidPagination(message.to_string()));
+
+    if query.limit.is_some_and(|limit| limit < 0) {
+        return invalid("limit can't be negative");
+    }
+    if query.offset.is_some_and(|offset| offset < 0) {
+        return invalid("offset can't be negative");
+    }
+    if query.page_size.is_some_and(|page_size| page_size < 1) {
+        return invalid("page_size has to be at least 1");
+    }
+    if query.page.is_some_and(|page| page < 1) {
+        return invalid("pages start at 1");
+    }
+    if query.page.is_some() && query.page_size.is_none() {
+        return invalid("page needs a page_size");
+    }
+    if query.page.is_some() && query.offset.is_some() {
+        return invalid("use either page or offset");
+    }
+    if query.cursor.is_some() {
+        if query.page.is_some() || query.offset.is_some() {
+            return invalid("cursor can't be combined with page or offset");
+        }
+        if page_size(query).is_none() {
+            return invalid("cursor needs a page_size or limit");
+        }
+        //raw rows have no key that tells apart the rows tying on the orderby fields
+        if query.dimensions.as_ref().is_none_or(Vec::is_empty) {
+            return invalid("cursor needs dimensions, the rows are continued after their values");
+        }
+    }
+
+    let limit = page_size(query);
+    let offset = match (query.page, query.page_size) {
+        (Some(page), Some(page_size)) => match (page - 1).checked_mul(i64::from(page_size)) {
+            Some(offset) => Some(offset),
+            None => return invalid("page is too large"),
+        },
+        _ => query.offset,
+    };
+    Ok((limit, offset))
+}
+
+fn page_size(query: &DataRequest) -> Option<i64> {
+    query.page_size.or(query.limit).map(i64::from)
+}
+
+// the orderby of a cursor request followed by the dimensions it leaves out. Rows are grouped by
+// their dimensions, without them the rows that tie with the last row of a page on the orderby
+// fields would be skipped by the next page
+pub(super) fn cursor_orderby(query: &DataRequest) -> Vec<OrderBy> {
+    let mut orderby = query.orderby.clone().unwrap_or_default();
+    for dimension in query.dimensions.iter().flatten() {
+        let header = dimension.name.clone().unwrap_or(dimension.field.clone());
+        if !orderby.iter().any(|order| order.field == header) {
+            orderby.push(OrderBy {
+                field: header,
+                order: Order::asc,
+                nulls: None,
+            });
+        }
+    }
+    orderby
+}
+
+// the selected columns of cursor_orderby, the cursor holds their values of the last row
+pub(super) fn cursor_columns<'a>(
+    orderby: &[OrderBy],
+    columns: &'a [SelectColumn],
+) -> Result<Vec<(&'a SelectColumn, Order)>, QueryBuildError> {
+    if orderby.is_empty() {
+        return Err(QueryBuildError::InvalidPagination(
+            "cursor needs orderby fields or dimensions".to_string(),
+        ));
+    }
+    let mut key_columns = Vec::new();
+    for order in orderby {
+        if order.nulls.is_some() {
+            return Err(QueryBuildError::InvalidPagination(format!(
+                "orderby field '{}' sets nulls, cursor pagination needs non null orderby fields",
+                order.field
+            )));
+        }
+        match columns.iter().find(|column| column.header == order.field) {
+            Some(column) if column.window => {
+                return Err(QueryBuildError::InvalidPagination(format!(
+                    "orderby field '{}' is a window metric, it can't be compared in a cursor",
+                    order.field
+                )))
+            }
+            Some(column) if column.sort_sql.is_some() => {
+                return Err(QueryBuildError::InvalidPagination(format!(
+                    "field '{}' is binned, it can't be compared in a cursor",
+                    order.field
+                )))
+            }
+            Some(column) => key_columns.push((column, order.order)),
+            None => {
+                return Err(QueryBuildError::InvalidPagination(format!(
+                    "orderby field '{}' is not a selected dimension or metric",
+                    order.field
+                )))
+            }
+        }
+    }
+    Ok(key_columns)
+}
+
+// keyset condition that continues after the cursor, `(a > ?) or (a = ? and b < ?)` ...
+pub(super) fn cursor_to_sql(
+    cursor: &str,
+    key_columns: &[(&SelectColumn, Order)],
+    params: &mut QueryParams,
+) -> Result<Option<String>, QueryBuildError> {
+    //empty cursor is the first page
+    if cursor.is_empty() {
+        return Ok(None);
+    }
+
+    let values = decode_cursor(cursor)?;
+    if values.len() != key_columns.len() {
+        return Err(QueryBuildError::InvalidCursor);
+    }
+    let mut key_values = Vec::new();
+    for ((column, _), value) in key_columns.iter().zip(&values) {
+        let value = value.as_ref().ok_or_else(|| {
+            QueryBuildError::InvalidPagination(format!(
+                "field '{}' is null in the last row, cursor pagination needs non null orderby fields and dimensions",
+                column.header
+            ))
+        })?;
+        key_values.push(value);
+    }
+    //values are bound once per comparison, `?` placeholders can't be reused
+    let bind = |position: usize, params: &mut QueryParams| {
+        let (column, _) = key_columns[position];
+        if is_decimal(column) {
+            return bind_decimal(key_values[position], params);
+        }
+        bind_scalar(
+            &column.header,
+            &column.datatype,
+            key_values[position],
+            column.timestamps,
+            params,
+        )
+        .map_err(|_| QueryBuildError::InvalidCursor)
+    };
+
+    let mut alternatives = Vec::new();
+    for position in 0..key_columns.len() {
+        let mut conditions = Vec::new();
+        for (previous, (column, _)) in key_columns.iter().enumerate().take(position) {
+            let value_sql = bind(previous, params)?;
+            conditions.push(format!("{} = {}", key_sql(column), value_sql));
+        }
+        let (column, order) = key_columns[position];
+        let operator = match order {
+            Order::asc => ">",
+            Order::desc => "<",
+        };
+        let value_sql = bind(position, params)?;
+        conditions.push(format!("{} {} {}", key_sql(column), operator, value_sql));
+        alternatives.push(format!("({})", conditions.join(" and ")));
+    }
+    Ok(Some(format!("({})", alternatives.join(" or "))))
+}
+
+// decimals are compared as exact decimals, the condition sql of metrics compares them as doubles
+fn is_decimal(column: &SelectColumn) -> bool {
+    matches!(base_datatype(&column.datatype).as_str(), "decimal" | "numeric")
+}
+
+fn key_sql(column: &SelectColumn) -> &str {
+    match is_decimal(column) {
+        true => &column.sql,
+        false => &column.condition_sql,
+    }
+}
+
+// decimals come back as strings, they are bound as text and converted by the database so that
+// they don't go through a double
+fn bind_decimal(value: &ScalarValue, params: &mut QueryParams) -> Result<String, QueryBuildError> {
+    let text = match value {
+        ScalarValue::String(text) if text.trim().parse::<f64>().is_ok() => text.trim().to_string(),
+        ScalarValue::Number(number) => number.to_string(),
+        _ => return Err(QueryBuildError::InvalidCursor),
+    };
+    let placeholder = params.bind(SqlParam::String(text));
+    Ok(params.dialect.cast(&placeholder, CastType::Decimal))
+}
+
+// cuts the extra row fetched for cursor pages and points next_cursor at the last row
+pub fn paginate(query: &DataRequest, mut response: DataResponse) -> DataResponse {
+    let page_size = match (&query.cursor, page_size(query)) {
+        (Some(_), Some(page_size)) => page_size as usize,
+        _ => return response,
+    };
+    if response.data.len() <= page_size {
+        return response;
+    }
+    response.data.truncate(page_size);
+    if let Some(row) = response.data.last() {
+        let values: Vec<&AttributeValue> = cursor_orderby(query)
+            .iter()
+            .map(|order| row.get(&order.field).unwrap_or(&AttributeValue::NULL))
+            .collect();
+        response.next_cursor = Some(encode_cursor(&values));
+    }
+    response
+}
+
+//cursors are the hex encoded json array of the orderby values, opaque to the client
+fn encode_cursor(values: &[&AttributeValue]) -> String {
+    hex::encode(serde_json::to_string(values).unwrap_or_default())
+}
+
+fn decode_cursor(cursor: &str) -> Result<Vec<Option<ScalarValue>>, QueryBuildError> {
+    let bytes = hex::decode(cursor).map_err(|_| QueryBuildError::InvalidCursor)?;
+    serde_json::from_slice(&bytes).map_err(|_| QueryBuildError::InvalidCursor)
+}
@@ -0,0 +1,132 @@
+use super::QueryBuildError;
+use crate::models::{AttributeValue, DataRequest, DataResponse, PivotTable};
+use std::collections::HashMap;
+
+//columns kept when the request doesn't set max_columns, and the most it may ask for
+const DEFAULT_MAX_COLUMNS: usize = 50;
+const MAX_COLUMNS: usize = 500;
+
+pub(super) fn check_pivot(query: &DataRequest) -> Result<(), QueryBuildError> {
+    let pivot = match &query.pivot {
+        Some(pivot) => pivot,
+        None => return Ok(()),
+    };
+    let invalid = |message: String| Err(QueryBuildError::InvalidPivot(message));
+    let is_dimension =
+        query.dimensions.iter().flatten().any(|dimension| {
+            dimension.name.as_ref().unwrap_or(&dimension.field) == &pivot.dimension
+        });
+    if !is_dimension {
+        return invalid(format!(
+            "'{}' is not a dimension of the request",
+            pivot.dimension
+        ));
+    }
+    if query.metrics.as_deref().unwrap_or(&[]).is_empty() {
+        return invalid("pivot needs metrics".to_string());
+    }
+    //a page would cut the matrix at an arbitrary row of the unpivoted result
+    if query.cursor.is_some() || query.page.is_some() || query.page_size.is_some() {
+        return invalid("pivoted results can't be paginated".to_string());
+    }
+    if pivot
+        .max_columns
+        .is_some_and(|max_columns| !(1..=MAX_COLUMNS).contains(&max_columns))
+    {
+        return invalid(format!(
+            "max_columns has to be between 1 and {}",
+            MAX_COLUMNS
+        ));
+    }
+    Ok(())
+}
+
+// turns the rows of a pivot request into DataResponse.pivot, every column other than the
+// dimensions becomes a matrix of values. Row and column keys keep the order of the rows
+pub fn pivot_rows(query: &DataRequest, response: &mut DataResponse) {
+    let pivot = match &query.pivot {
+        Some(pivot) => pivot,
+        None => return,
+    };
+    let max_columns = pivot.max_columns.unwrap_or(DEFAULT_MAX_COLUMNS);
+    let dimension_headers: Vec<String> = query
+        .dimensions
+        .iter()
+        .flatten()
+        .map(|dimension| dimension.name.clone().unwrap_or(dimension.field.clone()))
+        .collect();
+    let row_dimensions: Vec<String> = dimension_headers
+        .iter()
+        .filter(|header| **header != pivot.dimension)
+        .cloned()
+        .collect();
+    let value_of = |row: &HashMap<String, AttributeValue>, header: &String| {
+        row.get(header).cloned().unwrap_or(AttributeValue::NULL)
+    };
+    //metrics and whatever was derived from them, such as the compare columns
+    let mut value_headers: Vec<String> = response
+        .data
+        .iter()
+        .flat_map(|row| row.keys())
+        .filter(|header| !dimension_headers.contains(header))
+        .cloned()
+        .collect();
+    value_headers.sort();
+    value_headers.dedup();
+
+    let mut rows: Vec<Vec<AttributeValue>> = Vec::new();
+    let mut columns: Vec<AttributeValue> = Vec::new();
+    let mut row_positions: HashMap<String, usize> = HashMap::new();
+    let mut column_positions: HashMap<String, usize> = HashMap::new();
+    let mut cells: Vec<(usize, usize, &HashMap<String, AttributeValue>)> = Vec::new();
+    let mut truncated = false;
+    for row in &response.data {
+        let column = value_of(row, &pivot.dimension);
+        let column_key = serde_json::to_string(&column).unwrap_or_default();
+        let column_position = match column_positions.get(&column_key) {
+            Some(position) => *position,
+            None if columns.len() < max_columns => {
+                column_positions.insert(column_key, columns.len());
+                columns.push(column);
+                columns.len() - 1
+            }
+            None => {
+                truncated = true;
+                continue;
+            }
+        };
+        let row_key: Vec<AttributeValue> = row_dimensions
+            .iter()
+            .map(|header| value_of(row, header))
+            .collect();
+        let row_position = *row_positions
+            .entry(serde_json::to_string(&row_key).unwrap_or_default())
+            .or_insert_with(|| {
+                rows.push(row_key);
+                rows.len() - 1
+            });
+        cells.push((row_position, column_position, row));
+    }
+
+    let mut values: HashMap<String, Vec<Vec<AttributeValue>>> = value_headers
+        .iter()
+        .map(|header| {
+            let empty_row = vec![AttributeValue::NULL; columns.len()];
+            (header.clone(), vec![empty_row; rows.len()])
+        })
+        .collect();
+    for (row_position, column_position, row) in cells {
+        for (header, matrix) in values.iter_mut() {
+            matrix[row_position][column_position] = value_of(row, header);
+        }
+    }
+    response.data = Vec::new();
+    response.pivot = Some(PivotTable {
+        row_dimensions,
+        column_dimension: pivot.dimension.clone(),
+        rows,
+        columns,
+        values,
+        truncated,
+    });
+}
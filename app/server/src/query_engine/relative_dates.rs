@@ -0,0 +1,172 @@
+use super::timezone::TimeBasis;
+use super::{bind_scalar, temporal_kind, DateSettings, QueryBuildError, QueryParams, TemporalKind};
+use crate::models::{FilterValue, ScalarValue};
+use chrono::{Datelike, Days, Months, NaiveDate, NaiveTime};
+use chrono_tz::Tz;
+
+#[derive(Debug, Clone, Copy)]
+enum Period {
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+}
+
+// which periods of the calendar a relative operator covers
+#[derive(Debug, Clone, Copy)]
+enum Range {
+    //the whole current period, `this_month`
+    Current,
+    //from the start of the current period through today, `mtd`
+    ToDate,
+    //the given number of complete periods before the current one, `yesterday`, `previous_month`
+    Previous(u32),
+    //the given number of periods up to and including the current one through today,
+    //`last_n_days: 7` is today and the 6 days before it
+    Last(u32),
+}
+
+// the days [start, end) of a relative date operator such as `last_n_days` or `ytd`, resolved
+// against today in the request's time zone, None for any other operator
+pub(super) fn relative_range(
+    field: &str,
+    datatype: &str,
+    filter_operator: &str,
+    filter_value: &Option<FilterValue>,
+    dates: &DateSettings,
+) -> Result<Option<(NaiveDate, NaiveDate)>, QueryBuildError> {
+    let range = relative_days(field, filter_operator, filter_value, dates)?;
+    if range.is_some() && temporal_kind(datatype).is_none() {
+        return Err(QueryBuildError::UnsupportedOperator {
+            field: field.to_string(),
+            operator: filter_operator.to_string(),
+        });
+    }
+    Ok(range)
+}
+
+// relative_range without checking the type of the filtered expression
+pub(super) fn relative_days(
+    field: &str,
+    filter_operator: &str,
+    filter_value: &Option<FilterValue>,
+    dates: &DateSettings,
+) -> Result<Option<(NaiveDate, NaiveDate)>, QueryBuildError> {
+    let invalid_value = || QueryBuildError::InvalidFilterValue {
+        field: field.to_string(),
+        operator: filter_operator.to_string(),
+    };
+    let operator = filter_operator.to_lowercase();
+    let count = || match filter_value {
+        Some(FilterValue::Scalar(ScalarValue::Number(number))) => number
+            .as_u64()
+            .filter(|count| *count >= 1)
+            .and_then(|count| u32::try_from(count).ok())
+            .ok_or_else(invalid_value),
+        _ => Err(invalid_value()),
+    };
+
+    let (period, range) = match operator.as_str() {
+        "today" => (Period::Day, Range::Current),
+        "yesterday" => (Period::Day, Range::Previous(1)),
+        "this_week" => (Period::Week, Range::Current),
+        "this_month" => (Period::Month, Range::Current),
+        "this_quarter" => (Period::Quarter, Range::Current),
+        "this_year" => (Period::Year, Range::Current),
+        "previous_week" => (Period::Week, Range::Previous(1)),
+        "previous_month" => (Period::Month, Range::Previous(1)),
+        "previous_quarter" => (Period::Quarter, Range::Previous(1)),
+        "previous_year" => (Period::Year, Range::Previous(1)),
+        "wtd" => (Period::Week, Range::ToDate),
+        "mtd" => (Period::Month, Range::ToDate),
+        "qtd" => (Period::Quarter, Range::ToDate),
+        "ytd" => (Period::Year, Range::ToDate),
+        "last_n_days" => (Period::Day, Range::Last(count()?)),
+        "last_n_weeks" => (Period::Week, Range::Last(count()?)),
+        "last_n_months" => (Period::Month, Range::Last(count()?)),
+        "last_n_quarters" => (Period::Quarter, Range::Last(count()?)),
+        "last_n_years" => (Period::Year, Range::Last(count()?)),
+        _ => return Ok(None),
+    };
+    if !operator.starts_with("last_n_") && filter_value.is_some() {
+        return Err(invalid_value());
+    }
+
+    let today = match dates.timezone {
+        Some(timezone) => dates.now.with_timezone(&timezone).date_naive(),
+        None => dates.now.date_naive(),
+    };
+    let start = period_start(today, period, dates.week_start.days_from_monday());
+    let tomorrow = today.checked_add_days(Days::new(1));
+    let bounds = match range {
+        Range::Current => Some(start).zip(shift(start, period, 1)),
+        Range::ToDate => Some(start).zip(tomorrow),
+        Range::Previous(count) => shift(start, period, -i64::from(count)).zip(Some(start)),
+        Range::Last(count) => shift(start, period, 1 - i64::from(count)).zip(tomorrow),
+    };
+    bounds.map(Some).ok_or_else(invalid_value)
+}
+
+// `<expression> >= <start> and <expression> < <end>`, the days start at midnight of the request's time zone
+pub(super) fn range_to_sql(
+    field: &str,
+    field_sql: &str,
+    datatype: &str,
+    (start, end): (NaiveDate, NaiveDate),
+    timestamps: Option<(Tz, TimeBasis)>,
+    params: &mut QueryParams,
+) -> Result<String, QueryBuildError> {
+    let bind = |day: NaiveDate, params: &mut QueryParams| {
+        let value = ScalarValue::String(day_start_text(day, datatype));
+        bind_scalar(field, datatype, &value, timestamps, params)
+    };
+    let start_sql = bind(start, params)?;
+    let end_sql = bind(end, params)?;
+    Ok(format!(
+        "({} >= {} and {} < {})",
+        field_sql, start_sql, field_sql, end_sql
+    ))
+}
+
+// midnight of the day as a value compared with an expression of the datatype
+pub(super) fn day_start_text(day: NaiveDate, datatype: &str) -> String {
+    let format = match temporal_kind(datatype) {
+        Some(TemporalKind::Date) => "%Y-%m-%d",
+        _ => "%Y-%m-%d %H:%M:%S",
+    };
+    day.and_time(NaiveTime::MIN).format(format).to_string()
+}
+
+fn period_start(day: NaiveDate, period: Period, first_day: u32) -> NaiveDate {
+    match period {
+        Period::Day => day,
+        Period::Week => {
+            let days_into_week = (day.weekday().num_days_from_monday() + 7 - first_day) % 7;
+            day - Days::new(u64::from(days_into_week))
+        }
+        Period::Month => day.with_day(1).unwrap_or(day),
+        Period::Quarter => {
+            NaiveDate::from_ymd_opt(day.year(), (day.month() - 1) / 3 * 3 + 1, 1).unwrap_or(day)
+        }
+        Period::Year => NaiveDate::from_ymd_opt(day.year(), 1, 1).unwrap_or(day),
+    }
+}
+
+//moves a period start by whole periods, None if it leaves the calendar
+fn shift(start: NaiveDate, period: Period, periods: i64) -> Option<NaiveDate> {
+    let (unit, months) = match period {
+        Period::Day => (1, false),
+        Period::Week => (7, false),
+        Period::Month => (1, true),
+        Period::Quarter => (3, true),
+        Period::Year => (12, true),
+    };
+    let amount = u32::try_from(periods.unsigned_abs().checked_mul(unit)?).ok()?;
+    match (months, periods >= 0) {
+        (false, true) => start.checked_add_days(Days::new(u64::from(amount))),
+        (false, false) => start.checked_sub_days(Days::new(u64::from(amount))),
+        (true, true) => start.checked_add_months(Months::new(amount)),
+        (true, false) => start.checked_sub_months(Months::new(amount)),
+    }
+}
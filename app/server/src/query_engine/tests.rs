@@ -0,0 +1,1059 @@
+use super::{
+    compare_rows, describe_columns, get_compare_query, get_query, get_query_at,
+    localize_timestamps, paginate, QueryBuildError,
+};
+use crate::models::{
+    AttributeValue, BatchRequest, ColumnType, DataRequest, DataResponse, SqlParam, SqlQuery, Table,
+};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+//a value with everything that could end a literal, start a placeholder or a comment
+const VALUE: &str = "O'Reilly \\' ? $1 -- ;";
+
+const DIALECTS: [&str; 3] = ["postgres", "mysql", "clickhouse"];
+
+fn tables() -> Vec<Table> {
+    serde_json::from_value(json!([
+        {
+            "name": "orders",
+            "columns": [
+                {"name": "id", "datatype": "int"},
+                {"name": "product_id", "datatype": "bigint"},
+                {"name": "total", "datatype": "float"},
+                {"name": "created_at", "datatype": "timestamp"},
+                {"name": "amount", "datatype": "decimal(38,10)"}
+            ],
+            "relationships": [{"products": ["product_id", "id"]}]
+        },
+        {
+            "name": "products",
+            "columns": [
+                {"name": "id", "datatype": "bigint"},
+                {"name": "category", "datatype": "varchar"},
+                {"name": "price", "datatype": "float"}
+            ],
+            "relationships": []
+        }
+    ]))
+    .unwrap()
+}
+
+fn query(db_type: &str, request: serde_json::Value) -> SqlQuery {
+    build(db_type, request).unwrap()
+}
+
+fn build(db_type: &str, request: serde_json::Value) -> Result<SqlQuery, QueryBuildError> {
+    let request: DataRequest = serde_json::from_value(request).unwrap();
+    get_query(&request, &tables(), db_type)
+}
+
+// the sql of the request in every dialect, in the order of DIALECTS
+fn assert_sql(request: serde_json::Value, expected: [&str; 3]) {
+    for (db_type, expected) in DIALECTS.into_iter().zip(expected) {
+        assert_eq!(query(db_type, request.clone()).sql, expected, "{}", db_type);
+    }
+}
+
+fn category_filter() -> serde_json::Value {
+    json!({
+        "dimension": {"field": "products.category"},
+        "filter_operator": "=",
+        "filter_value": VALUE
+    })
+}
+
+// splits the sql into its quoted literals, decoded the way the database reads them, and
+// everything outside of them. Postgres has standard conforming strings, the others take
+// backslash escapes
+fn split_literals(db_type: &str, sql: &str) -> (Vec<String>, String) {
+    let mut literals = Vec::new();
+    let mut outside = String::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\'' {
+            outside.push(c);
+            continue;
+        }
+        let mut literal = String::new();
+        loop {
+            match chars.next() {
+                Some('\\') if db_type != "postgres" => literal.extend(chars.next()),
+                Some('\'') if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    literal.push('\'');
+                }
+                Some('\'') => break,
+                Some(c) => literal.push(c),
+                None => panic!("unterminated literal in {}", sql),
+            }
+        }
+        literals.push(literal);
+    }
+    (literals, outside)
+}
+
+// every literal holding the value decodes to exactly the value, and none of it leaks out
+fn assert_escaped(db_type: &str, sql: &str, expected_literals: usize) {
+    let (literals, outside) = split_literals(db_type, sql);
+    let values: Vec<&String> = literals
+        .iter()
+        .filter(|literal| literal.contains("Reilly"))
+        .collect();
+    assert_eq!(values.len(), expected_literals, "[{}] {}", db_type, sql);
+    assert!(
+        values.iter().all(|literal| *literal == VALUE),
+        "[{}] {}",
+        db_type,
+        sql
+    );
+    assert!(!outside.contains("Reilly"), "[{}] {}", db_type, sql);
+}
+
+#[test]
+fn filter_values_are_bound() {
+    for db_type in DIALECTS {
+        let sql_query = query(
+            db_type,
+            json!({
+                "metrics": [{"field": "orders.total", "aggregate_operator": "sum"}],
+                "filters": [category_filter()]
+            }),
+        );
+        assert!(
+            !sql_query.sql.contains("Reilly"),
+            "[{}] {}",
+            db_type,
+            sql_query.sql
+        );
+        assert!(
+            matches!(sql_query.params.as_slice(), [SqlParam::String(value)] if value == VALUE),
+            "[{}] {:?}",
+            db_type,
+            sql_query.params
+        );
+        let placeholder = if db_type == "postgres" { "= $1" } else { "= ?" };
+        assert!(
+            sql_query.sql.contains(placeholder),
+            "[{}] {}",
+            db_type,
+            sql_query.sql
+        );
+    }
+}
+
+#[test]
+fn metric_filter_values_are_escaped() {
+    for db_type in DIALECTS {
+        let sql_query = query(
+            db_type,
+            json!({
+                "metrics": [{
+                    "field": "orders.total",
+                    "aggregate_operator": "sum",
+                    "filters": [category_filter()]
+                }]
+            }),
+        );
+        assert!(sql_query.params.is_empty());
+        assert_escaped(db_type, &sql_query.sql, 1);
+    }
+}
+
+#[test]
+fn top_n_subquery_values_are_escaped() {
+    for db_type in DIALECTS {
+        let sql_query = query(
+            db_type,
+            json!({
+                "metrics": [{"field": "orders.total", "aggregate_operator": "sum", "name": "revenue"}],
+                "dimensions": [{"field": "products.category", "top_n": {"count": 3, "by": "revenue"}}],
+                "filters": [category_filter()]
+            }),
+        );
+        //bound in the outer query, written into the ranking subquery
+        assert_eq!(sql_query.params.len(), 1);
+        assert_escaped(db_type, &sql_query.sql, 1);
+    }
+}
+
+#[test]
+fn top_n_matches_nulls_and_keeps_the_rest_apart() {
+    let expected = [
+        ("postgres", "IS NOT DISTINCT FROM \"top_1\".\"value\"", "\"top_1\".\"ranked\""),
+        ("mysql", "<=> `top_1`.`value`", "`top_1`.`ranked`"),
+        ("clickhouse", ", `top_1`.`value`)", "`top_1`.`ranked`"),
+    ];
+    for (db_type, join_sql, ranked_sql) in expected {
+        let sql_query = query(
+            db_type,
+            json!({
+                "metrics": [{"field": "orders.total", "aggregate_operator": "sum", "name": "revenue"}],
+                "dimensions": [{"field": "products.category", "top_n": {"count": 3, "by": "revenue"}}]
+            }),
+        );
+        assert!(sql_query.sql.contains(join_sql), "{}", sql_query.sql);
+        //a top value named like other_label isn't merged with the rest
+        let group_sql = &sql_query.sql[sql_query.sql.rfind("group by").unwrap()..];
+        assert!(group_sql.contains(ranked_sql), "{}", sql_query.sql);
+    }
+}
+
+#[test]
+fn quantile_subquery_values_are_escaped() {
+    //mysql has no percentile aggregate, quantile bins are rejected there
+    for db_type in ["postgres", "clickhouse"] {
+        let sql_query = query(
+            db_type,
+            json!({
+                "metrics": [{"field": "orders.id", "aggregate_operator": "count"}],
+                "dimensions": [{"field": "products.price", "bins": {"type": "quantiles", "count": 4}}],
+                "filters": [category_filter()]
+            }),
+        );
+        assert_eq!(sql_query.params.len(), 1);
+        assert_escaped(db_type, &sql_query.sql, 1);
+    }
+}
+#[test]
+fn cursor_pages_break_ties_on_the_dimensions() {
+    let mut request = json!({
+        "metrics": [{"field": "orders.total", "aggregate_operator": "sum", "name": "revenue"}],
+        "dimensions": [{"field": "products.category"}, {"field": "products.price", "name": "price"}],
+        "orderby": [{"field": "revenue", "order": "desc"}],
+        "page_size": 2,
+        "cursor": ""
+    });
+    let data_request: DataRequest = serde_json::from_value(request.clone()).unwrap();
+    let row = |revenue: i64, category: &str, price: i64| {
+        [
+            ("revenue".to_string(), AttributeValue::Int(revenue)),
+            (
+                "products.category".to_string(),
+                AttributeValue::String(category.to_string()),
+            ),
+            ("price".to_string(), AttributeValue::Int(price)),
+        ]
+        .into_iter()
+        .collect()
+    };
+    //the second and third rows tie on revenue
+    let rows = vec![row(10, "a", 1), row(5, "b", 2), row(5, "c", 3)];
+    let response = paginate(&data_request, DataResponse::new(rows));
+    request["cursor"] = json!(response.next_cursor.unwrap());
+
+    let sql_query = query("postgres", request);
+    assert!(sql_query.sql.contains(
+        r#"order by SUM("orders"."total") desc, "products"."category" asc, "products"."price" asc"#
+    ));
+    assert!(sql_query.sql.contains(
+        r#"(CAST(SUM("orders"."total") AS DOUBLE PRECISION) = $2 and "products"."category" > $3)"#
+    ));
+    assert!(matches!(
+        sql_query.params.as_slice(),
+        [
+            SqlParam::Float(_),
+            SqlParam::Float(_),
+            SqlParam::String(category),
+            SqlParam::Float(_),
+            SqlParam::String(_),
+            SqlParam::Float(price)
+        ] if category == "b" && *price == 2.0
+    ));
+}
+
+#[test]
+fn large_pages_are_rejected() {
+    let request: DataRequest = serde_json::from_value(json!({
+        "metrics": [{"field": "orders.total", "aggregate_operator": "sum"}],
+        "page": i64::MAX,
+        "page_size": 100
+    }))
+    .unwrap();
+    assert!(matches!(
+        get_query(&request, &tables(), "postgres"),
+        Err(QueryBuildError::InvalidPagination(_))
+    ));
+}
+
+#[test]
+fn compare_matches_monthly_buckets_with_the_previous_year() {
+    let request: DataRequest = serde_json::from_value(json!({
+        "metrics": [{"field": "orders.total", "aggregate_operator": "sum", "name": "revenue"}],
+        "dimensions": [{"field": "orders.created_at", "transformation": "date_trunc_month", "name": "month"}],
+        "filters": [{
+            "dimension": {"field": "orders.created_at"},
+            "filter_operator": "between",
+            "filter_value": {"from": "2024-01-01", "to": "2024-12-31"}
+        }],
+        "compare": {"field": "orders.created_at", "period": "previous_year"}
+    }))
+    .unwrap();
+    let previous_query = get_compare_query(&request, &tables(), "postgres", 1000, Utc::now())
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        previous_query.sql_query.params.as_slice(),
+        [SqlParam::String(from), SqlParam::String(to)] if from == "2023-01-01" && to == "2023-12-31"
+    ));
+
+    let row = |month: &str, revenue: i64| {
+        [
+            (
+                "month".to_string(),
+                AttributeValue::Timestamp(month.to_string()),
+            ),
+            ("revenue".to_string(), AttributeValue::Int(revenue)),
+        ]
+        .into_iter()
+        .collect()
+    };
+    let mut response = DataResponse::new(vec![
+        row("2024-03-01 00:00:00", 15),
+        row("2024-04-01 00:00:00", 8),
+    ]);
+    let previous = DataResponse::new(vec![
+        row("2023-02-01 00:00:00", 7),
+        row("2023-03-01 00:00:00", 10),
+    ]);
+    compare_rows(&request, &mut response, &previous, &previous_query);
+    assert!(matches!(
+        response.data[0]["revenue_previous"],
+        AttributeValue::Int(10)
+    ));
+    assert!(
+        matches!(response.data[0]["revenue_change"], AttributeValue::Float(change) if change == 5.0)
+    );
+    assert!(matches!(
+        response.data[1]["revenue_previous"],
+        AttributeValue::NULL
+    ));
+}
+
+#[test]
+fn compared_periods_are_capped_and_bound_like_the_requested_one() {
+    let request: DataRequest = serde_json::from_value(json!({
+        "metrics": [{"field": "orders.total", "aggregate_operator": "sum", "name": "revenue"}],
+        "dimensions": [{"field": "products.category"}],
+        "filters": [{
+            "dimension": {"field": "orders.created_at"},
+            "filter_operator": "last_n_months",
+            "filter_value": 1
+        }],
+        "compare": {"field": "orders.created_at", "period": "previous_period"}
+    }))
+    .unwrap();
+    let requested = get_query(&request, &tables(), "postgres").unwrap();
+    let previous_query = get_compare_query(&request, &tables(), "postgres", 1000, Utc::now())
+        .unwrap()
+        .unwrap();
+    assert!(previous_query.sql_query.sql.contains("LIMIT 1000"));
+    //both periods are bound as timestamps of the same format
+    let bounds = |query: &SqlQuery| -> Vec<usize> {
+        query
+            .params
+            .iter()
+            .filter_map(|param| match param {
+                SqlParam::String(bound) => Some(bound.len()),
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(bounds(&requested), vec![19, 19]);
+    assert_eq!(bounds(&previous_query.sql_query), bounds(&requested));
+}
+
+// the bounds bound for a relative date filter on orders.created_at at `now`
+fn relative_bounds(
+    operator: &str,
+    value: Option<serde_json::Value>,
+    extra: serde_json::Value,
+    now: &str,
+) -> Vec<String> {
+    let mut request = json!({
+        "metrics": [{"field": "orders.id", "aggregate_operator": "count"}],
+        "filters": [{"dimension": {"field": "orders.created_at"}, "filter_operator": operator}]
+    });
+    if let Some(value) = value {
+        request["filters"][0]["filter_value"] = value;
+    }
+    for (key, value) in extra.as_object().unwrap() {
+        request[key] = value.clone();
+    }
+    let request: DataRequest = serde_json::from_value(request).unwrap();
+    let now = DateTime::parse_from_rfc3339(now).unwrap().with_timezone(&Utc);
+    get_query_at(&request, &tables(), "mysql", now)
+        .unwrap()
+        .params
+        .into_iter()
+        .map(|param| match param {
+            SqlParam::String(bound) => bound,
+            param => panic!("{:?}", param),
+        })
+        .collect()
+}
+
+#[test]
+fn relative_dates_are_resolved_against_now() {
+    //a sunday, weeks start on monday
+    let now = "2026-10-18T15:00:00Z";
+    let expected = [
+        ("today", None, "2026-10-18", "2026-10-19"),
+        ("yesterday", None, "2026-10-17", "2026-10-18"),
+        ("this_week", None, "2026-10-12", "2026-10-19"),
+        ("this_month", None, "2026-10-01", "2026-11-01"),
+        ("this_quarter", None, "2026-10-01", "2027-01-01"),
+        ("this_year", None, "2026-01-01", "2027-01-01"),
+        ("previous_week", None, "2026-10-05", "2026-10-12"),
+        ("previous_month", None, "2026-09-01", "2026-10-01"),
+        ("previous_quarter", None, "2026-07-01", "2026-10-01"),
+        ("previous_year", None, "2025-01-01", "2026-01-01"),
+        ("wtd", None, "2026-10-12", "2026-10-19"),
+        ("mtd", None, "2026-10-01", "2026-10-19"),
+        ("qtd", None, "2026-10-01", "2026-10-19"),
+        ("ytd", None, "2026-01-01", "2026-10-19"),
+        //the last periods include the current one through today
+        ("last_n_days", Some(7), "2026-10-12", "2026-10-19"),
+        ("last_n_weeks", Some(2), "2026-10-05", "2026-10-19"),
+        ("last_n_months", Some(3), "2026-08-01", "2026-10-19"),
+        ("last_n_quarters", Some(2), "2026-07-01", "2026-10-19"),
+        ("last_n_years", Some(2), "2025-01-01", "2026-10-19"),
+    ];
+    for (operator, count, start, end) in expected {
+        assert_eq!(
+            relative_bounds(operator, count.map(|count| json!(count)), json!({}), now),
+            [format!("{} 00:00:00", start), format!("{} 00:00:00", end)],
+            "{}",
+            operator
+        );
+    }
+}
+
+#[test]
+fn relative_dates_follow_the_week_start_and_time_zone() {
+    assert_eq!(
+        relative_bounds("this_week", None, json!({"week_start": "sunday"}), "2026-10-18T15:00:00Z"),
+        ["2026-10-18 00:00:00", "2026-10-25 00:00:00"]
+    );
+    //already monday in tokyo, the bounds are its midnights in utc
+    assert_eq!(
+        relative_bounds("today", None, json!({"timezone": "Asia/Tokyo"}), "2026-10-18T20:00:00Z"),
+        ["2026-10-18 15:00:00", "2026-10-19 15:00:00"]
+    );
+    let request: DataRequest = serde_json::from_value(json!({
+        "metrics": [{"field": "orders.id", "aggregate_operator": "count"}],
+        "filters": [{"dimension": {"field": "orders.created_at"}, "filter_operator": "last_n_days", "filter_value": 0}]
+    }))
+    .unwrap();
+    assert!(matches!(
+        get_query(&request, &tables(), "mysql"),
+        Err(QueryBuildError::InvalidFilterValue { .. })
+    ));
+}
+
+#[test]
+fn window_metrics_sql() {
+    assert_sql(
+        json!({
+            "metrics": [
+                {"field": "orders.total", "aggregate_operator": "sum", "name": "revenue"},
+                {"field": "orders.total", "aggregate_operator": "sum", "name": "running",
+                    "window": {"type": "running_total", "order_by": [{"field": "category"}]}},
+                {"field": "orders.total", "aggregate_operator": "sum", "name": "moving",
+                    "window": {"type": "moving_average", "size": 3,
+                        "order_by": [{"field": "category", "order": "desc", "nulls": "last"}]}},
+                {"field": "orders.id", "aggregate_operator": "count", "name": "ranked",
+                    "window": {"type": "dense_rank"}},
+                {"field": "orders.total", "aggregate_operator": "sum", "name": "share",
+                    "window": {"type": "percent_of_total"}}
+            ],
+            "dimensions": [{"field": "products.category", "name": "category"}]
+        }),
+        [
+        r#"select "products"."category" as "category", SUM("orders"."total") as "revenue", SUM(SUM("orders"."total")) OVER (ORDER BY "products"."category" asc ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW) as "running", AVG(SUM("orders"."total")) OVER (ORDER BY "products"."category" desc NULLS LAST ROWS BETWEEN 2 PRECEDING AND CURRENT ROW) as "moving", DENSE_RANK() OVER (ORDER BY COUNT("orders"."id") desc) as "ranked", SUM("orders"."total") * 100.0 / NULLIF(SUM(SUM("orders"."total")) OVER (), 0) as "share" from "orders" JOIN "products" ON "orders"."product_id" = "products"."id"  group by "products"."category"    ;"#,
+        r#"select `products`.`category` as `category`, SUM(`orders`.`total`) as `revenue`, SUM(SUM(`orders`.`total`)) OVER (ORDER BY `products`.`category` asc ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW) as `running`, AVG(SUM(`orders`.`total`)) OVER (ORDER BY (`products`.`category` IS NULL) asc, `products`.`category` desc ROWS BETWEEN 2 PRECEDING AND CURRENT ROW) as `moving`, DENSE_RANK() OVER (ORDER BY COUNT(`orders`.`id`) desc) as `ranked`, SUM(`orders`.`total`) * 100.0 / NULLIF(SUM(SUM(`orders`.`total`)) OVER (), 0) as `share` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id`  group by `products`.`category`    ;"#,
+        r#"select CAST(toString(`column_1`) AS Nullable(String)), CAST(toString(`column_2`) AS Nullable(String)), CAST(toString(`column_3`) AS Nullable(String)), CAST(toString(`column_4`) AS Nullable(String)), `column_5`, CAST(toString(`column_6`) AS Nullable(String)) from (select `products`.`category` as `column_1`, SUM(`orders`.`total`) as `column_2`, SUM(SUM(`orders`.`total`)) OVER (ORDER BY `products`.`category` asc ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW) as `column_3`, AVG(SUM(`orders`.`total`)) OVER (ORDER BY `products`.`category` desc NULLS LAST ROWS BETWEEN 2 PRECEDING AND CURRENT ROW) as `column_4`, DENSE_RANK() OVER (ORDER BY COUNT(`orders`.`id`) desc) as `column_5`, SUM(`orders`.`total`) * 100.0 / NULLIF(SUM(SUM(`orders`.`total`)) OVER (), 0) as `column_6` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id`  group by `products`.`category`   ) as result_rows ;"#,
+        ],
+    );
+}
+
+#[test]
+fn windows_without_their_settings_are_rejected() {
+    let window_request = |window: serde_json::Value| {
+        json!({
+            "metrics": [{"field": "orders.total", "aggregate_operator": "sum", "name": "revenue", "window": window}],
+            "dimensions": [{"field": "products.category", "name": "category"}]
+        })
+    };
+    for window in [
+        json!({"type": "running_total"}),
+        json!({"type": "moving_average", "order_by": [{"field": "category"}]}),
+        json!({"type": "rank", "size": 3}),
+        json!({"type": "rank", "partition_by": ["revenue"]}),
+    ] {
+        assert!(
+            matches!(
+                build("postgres", window_request(window.clone())),
+                Err(QueryBuildError::InvalidWindow(_))
+            ),
+            "{}",
+            window
+        );
+    }
+}
+
+#[test]
+fn fixed_width_and_boundary_bins_sql() {
+    assert_sql(
+        json!({
+            "metrics": [{"field": "orders.id", "aggregate_operator": "count", "name": "orders"}],
+            "dimensions": [{"field": "products.price", "name": "price",
+                "bins": {"type": "fixed_width", "width": 50}}],
+            "orderby": [{"field": "price"}]
+        }),
+        [
+        r#"select CASE WHEN "products"."price" IS NOT NULL THEN CONCAT(CAST((FLOOR(CAST("products"."price" AS DOUBLE PRECISION) / 50) * 50) AS TEXT), ' - ', CAST(((FLOOR(CAST("products"."price" AS DOUBLE PRECISION) / 50) * 50) + 50) AS TEXT)) END as "price", COUNT("orders"."id") as "orders" from "orders" JOIN "products" ON "orders"."product_id" = "products"."id"  group by CASE WHEN "products"."price" IS NOT NULL THEN CONCAT(CAST((FLOOR(CAST("products"."price" AS DOUBLE PRECISION) / 50) * 50) AS TEXT), ' - ', CAST(((FLOOR(CAST("products"."price" AS DOUBLE PRECISION) / 50) * 50) + 50) AS TEXT)) END, (FLOOR(CAST("products"."price" AS DOUBLE PRECISION) / 50) * 50)  order by (FLOOR(CAST("products"."price" AS DOUBLE PRECISION) / 50) * 50) asc  ;"#,
+        r#"select CASE WHEN `products`.`price` IS NOT NULL THEN CONCAT(CAST((FLOOR(CAST(`products`.`price` AS DOUBLE) / 50) * 50) AS CHAR), ' - ', CAST(((FLOOR(CAST(`products`.`price` AS DOUBLE) / 50) * 50) + 50) AS CHAR)) END as `price`, COUNT(`orders`.`id`) as `orders` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id`  group by CASE WHEN `products`.`price` IS NOT NULL THEN CONCAT(CAST((FLOOR(CAST(`products`.`price` AS DOUBLE) / 50) * 50) AS CHAR), ' - ', CAST(((FLOOR(CAST(`products`.`price` AS DOUBLE) / 50) * 50) + 50) AS CHAR)) END, (FLOOR(CAST(`products`.`price` AS DOUBLE) / 50) * 50)  order by (FLOOR(CAST(`products`.`price` AS DOUBLE) / 50) * 50) asc  ;"#,
+        r#"select CASE WHEN `products`.`price` IS NOT NULL THEN CONCAT(toString((FLOOR(toFloat64(`products`.`price`) / 50) * 50)), ' - ', toString(((FLOOR(toFloat64(`products`.`price`) / 50) * 50) + 50))) END as `price`, COUNT(`orders`.`id`) as `orders` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id`  group by CASE WHEN `products`.`price` IS NOT NULL THEN CONCAT(toString((FLOOR(toFloat64(`products`.`price`) / 50) * 50)), ' - ', toString(((FLOOR(toFloat64(`products`.`price`) / 50) * 50) + 50))) END, (FLOOR(toFloat64(`products`.`price`) / 50) * 50)  order by (FLOOR(toFloat64(`products`.`price`) / 50) * 50) asc  ;"#,
+        ],
+    );
+    assert_sql(
+        json!({
+            "metrics": [{"field": "orders.id", "aggregate_operator": "count", "name": "orders"}],
+            "dimensions": [{"field": "orders.id", "name": "size",
+                "bins": {"type": "boundaries", "boundaries": [10, 100]}}]
+        }),
+        [
+        r#"select CASE WHEN "orders"."id" IS NULL THEN NULL WHEN "orders"."id" < 10 THEN '< 10' WHEN "orders"."id" < 100 THEN '10 - 100' ELSE '>= 100' END as "size", COUNT("orders"."id") as "orders" from "orders"  group by CASE WHEN "orders"."id" IS NULL THEN NULL WHEN "orders"."id" < 10 THEN '< 10' WHEN "orders"."id" < 100 THEN '10 - 100' ELSE '>= 100' END, CASE WHEN "orders"."id" IS NULL THEN NULL WHEN "orders"."id" < 10 THEN 0 WHEN "orders"."id" < 100 THEN 1 ELSE 2 END    ;"#,
+        r#"select CASE WHEN `orders`.`id` IS NULL THEN NULL WHEN `orders`.`id` < 10 THEN '< 10' WHEN `orders`.`id` < 100 THEN '10 - 100' ELSE '>= 100' END as `size`, COUNT(`orders`.`id`) as `orders` from `orders`  group by CASE WHEN `orders`.`id` IS NULL THEN NULL WHEN `orders`.`id` < 10 THEN '< 10' WHEN `orders`.`id` < 100 THEN '10 - 100' ELSE '>= 100' END, CASE WHEN `orders`.`id` IS NULL THEN NULL WHEN `orders`.`id` < 10 THEN 0 WHEN `orders`.`id` < 100 THEN 1 ELSE 2 END    ;"#,
+        r#"select CASE WHEN `orders`.`id` IS NULL THEN NULL WHEN `orders`.`id` < 10 THEN '< 10' WHEN `orders`.`id` < 100 THEN '10 - 100' ELSE '>= 100' END as `size`, COUNT(`orders`.`id`) as `orders` from `orders`  group by CASE WHEN `orders`.`id` IS NULL THEN NULL WHEN `orders`.`id` < 10 THEN '< 10' WHEN `orders`.`id` < 100 THEN '10 - 100' ELSE '>= 100' END, CASE WHEN `orders`.`id` IS NULL THEN NULL WHEN `orders`.`id` < 10 THEN 0 WHEN `orders`.`id` < 100 THEN 1 ELSE 2 END    ;"#,
+        ],
+    );
+}
+
+#[test]
+fn quantile_bins_sql() {
+    let request = json!({
+        "metrics": [{"field": "orders.id", "aggregate_operator": "count", "name": "orders"}],
+        "dimensions": [{"field": "products.price", "name": "price",
+            "bins": {"type": "quantiles", "count": 4}}]
+    });
+    assert_eq!(
+        query("postgres", request.clone()).sql,
+        r#"select CASE WHEN "products"."price" IS NULL THEN NULL WHEN "products"."price" < "bins_1"."q_1" THEN CONCAT(CAST("bins_1"."q_0" AS TEXT), ' - ', CAST("bins_1"."q_1" AS TEXT)) WHEN "products"."price" < "bins_1"."q_2" THEN CONCAT(CAST("bins_1"."q_1" AS TEXT), ' - ', CAST("bins_1"."q_2" AS TEXT)) WHEN "products"."price" < "bins_1"."q_3" THEN CONCAT(CAST("bins_1"."q_2" AS TEXT), ' - ', CAST("bins_1"."q_3" AS TEXT)) ELSE CONCAT(CAST("bins_1"."q_3" AS TEXT), ' - ', CAST("bins_1"."q_4" AS TEXT)) END as "price", COUNT("orders"."id") as "orders" from "orders" JOIN "products" ON "orders"."product_id" = "products"."id" CROSS JOIN (select MIN("products"."price") as "q_0", PERCENTILE_CONT(0.25) WITHIN GROUP (ORDER BY "products"."price") as "q_1", PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY "products"."price") as "q_2", PERCENTILE_CONT(0.75) WITHIN GROUP (ORDER BY "products"."price") as "q_3", MAX("products"."price") as "q_4" from "orders" JOIN "products" ON "orders"."product_id" = "products"."id" ) as "bins_1"  group by CASE WHEN "products"."price" IS NULL THEN NULL WHEN "products"."price" < "bins_1"."q_1" THEN CONCAT(CAST("bins_1"."q_0" AS TEXT), ' - ', CAST("bins_1"."q_1" AS TEXT)) WHEN "products"."price" < "bins_1"."q_2" THEN CONCAT(CAST("bins_1"."q_1" AS TEXT), ' - ', CAST("bins_1"."q_2" AS TEXT)) WHEN "products"."price" < "bins_1"."q_3" THEN CONCAT(CAST("bins_1"."q_2" AS TEXT), ' - ', CAST("bins_1"."q_3" AS TEXT)) ELSE CONCAT(CAST("bins_1"."q_3" AS TEXT), ' - ', CAST("bins_1"."q_4" AS TEXT)) END, CASE WHEN "products"."price" IS NULL THEN NULL WHEN "products"."price" < "bins_1"."q_1" THEN 0 WHEN "products"."price" < "bins_1"."q_2" THEN 1 WHEN "products"."price" < "bins_1"."q_3" THEN 2 ELSE 3 END    ;"#
+    );
+    assert_eq!(
+        query("clickhouse", request.clone()).sql,
+        r#"select CASE WHEN `products`.`price` IS NULL THEN NULL WHEN `products`.`price` < `bins_1`.`q_1` THEN CONCAT(toString(`bins_1`.`q_0`), ' - ', toString(`bins_1`.`q_1`)) WHEN `products`.`price` < `bins_1`.`q_2` THEN CONCAT(toString(`bins_1`.`q_1`), ' - ', toString(`bins_1`.`q_2`)) WHEN `products`.`price` < `bins_1`.`q_3` THEN CONCAT(toString(`bins_1`.`q_2`), ' - ', toString(`bins_1`.`q_3`)) ELSE CONCAT(toString(`bins_1`.`q_3`), ' - ', toString(`bins_1`.`q_4`)) END as `price`, COUNT(`orders`.`id`) as `orders` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id` CROSS JOIN (select MIN(`products`.`price`) as `q_0`, quantile(0.25)(`products`.`price`) as `q_1`, quantile(0.5)(`products`.`price`) as `q_2`, quantile(0.75)(`products`.`price`) as `q_3`, MAX(`products`.`price`) as `q_4` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id` ) as `bins_1`  group by CASE WHEN `products`.`price` IS NULL THEN NULL WHEN `products`.`price` < `bins_1`.`q_1` THEN CONCAT(toString(`bins_1`.`q_0`), ' - ', toString(`bins_1`.`q_1`)) WHEN `products`.`price` < `bins_1`.`q_2` THEN CONCAT(toString(`bins_1`.`q_1`), ' - ', toString(`bins_1`.`q_2`)) WHEN `products`.`price` < `bins_1`.`q_3` THEN CONCAT(toString(`bins_1`.`q_2`), ' - ', toString(`bins_1`.`q_3`)) ELSE CONCAT(toString(`bins_1`.`q_3`), ' - ', toString(`bins_1`.`q_4`)) END, CASE WHEN `products`.`price` IS NULL THEN NULL WHEN `products`.`price` < `bins_1`.`q_1` THEN 0 WHEN `products`.`price` < `bins_1`.`q_2` THEN 1 WHEN `products`.`price` < `bins_1`.`q_3` THEN 2 ELSE 3 END    ;"#
+    );
+    //mysql has no percentile aggregate
+    assert!(build("mysql", request).is_err());
+}
+
+#[test]
+fn invalid_bins_are_rejected() {
+    for (field, bins) in [
+        ("products.price", json!({"type": "fixed_width", "width": 0})),
+        ("products.price", json!({"type": "boundaries", "boundaries": [10, 5]})),
+        ("products.price", json!({"type": "quantiles", "count": 1})),
+        ("products.category", json!({"type": "fixed_width", "width": 5})),
+    ] {
+        let request = json!({
+            "metrics": [{"field": "orders.id", "aggregate_operator": "count"}],
+            "dimensions": [{"field": field, "bins": bins}]
+        });
+        assert!(
+            matches!(build("postgres", request), Err(QueryBuildError::InvalidBins(_))),
+            "{}",
+            bins
+        );
+    }
+}
+
+fn pivot_request() -> serde_json::Value {
+    json!({
+        "metrics": [{"field": "orders.total", "aggregate_operator": "sum", "name": "revenue"}],
+        "dimensions": [
+            {"field": "products.category", "name": "category"},
+            {"field": "orders.created_at", "transformation": "date_trunc_month", "name": "month"}
+        ],
+        "orderby": [{"field": "month"}],
+        "pivot": {"dimension": "month", "max_columns": 2}
+    })
+}
+
+#[test]
+fn pivots_query_the_unpivoted_rows() {
+    assert_sql(
+        pivot_request(),
+        [
+        r#"select "products"."category" as "category", DATE_TRUNC('month', "orders"."created_at") as "month", SUM("orders"."total") as "revenue" from "orders" JOIN "products" ON "orders"."product_id" = "products"."id"  group by "products"."category", DATE_TRUNC('month', "orders"."created_at")  order by DATE_TRUNC('month', "orders"."created_at") asc  ;"#,
+        r#"select `products`.`category` as `category`, CAST(DATE_FORMAT(`orders`.`created_at`, '%Y-%m-01') AS DATETIME) as `month`, SUM(`orders`.`total`) as `revenue` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id`  group by `products`.`category`, CAST(DATE_FORMAT(`orders`.`created_at`, '%Y-%m-01') AS DATETIME)  order by CAST(DATE_FORMAT(`orders`.`created_at`, '%Y-%m-01') AS DATETIME) asc  ;"#,
+        r#"select CAST(toString(`column_1`) AS Nullable(String)), CAST(toString(`column_2`) AS Nullable(String)), CAST(toString(`column_3`) AS Nullable(String)) from (select `products`.`category` as `column_1`, toDateTime(toStartOfMonth(`orders`.`created_at`)) as `column_2`, SUM(`orders`.`total`) as `column_3` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id`  group by `products`.`category`, toDateTime(toStartOfMonth(`orders`.`created_at`))  order by toDateTime(toStartOfMonth(`orders`.`created_at`)) asc ) as result_rows ;"#,
+        ],
+    );
+}
+
+#[test]
+fn pivot_rows_become_a_matrix() {
+    let request: DataRequest = serde_json::from_value(pivot_request()).unwrap();
+    let row = |category: Option<&str>, month: &str, revenue: i64| {
+        [
+            (
+                "category".to_string(),
+                category.map_or(AttributeValue::NULL, |category| {
+                    AttributeValue::String(category.to_string())
+                }),
+            ),
+            ("month".to_string(), AttributeValue::Timestamp(month.to_string())),
+            ("revenue".to_string(), AttributeValue::Int(revenue)),
+        ]
+        .into_iter()
+        .collect()
+    };
+    let mut response = DataResponse::new(vec![
+        row(Some("books"), "2024-01-01 00:00:00", 1),
+        row(None, "2024-01-01 00:00:00", 2),
+        row(Some("games"), "2024-02-01 00:00:00", 3),
+        row(Some("books"), "2024-02-01 00:00:00", 4),
+        //past max_columns
+        row(Some("books"), "2024-03-01 00:00:00", 5),
+    ]);
+    super::pivot_rows(&request, &mut response);
+
+    assert!(response.data.is_empty());
+    let pivot = serde_json::to_value(response.pivot.unwrap()).unwrap();
+    assert_eq!(
+        pivot,
+        json!({
+            "row_dimensions": ["category"],
+            "column_dimension": "month",
+            "rows": [["books"], [null], ["games"]],
+            "columns": ["2024-01-01 00:00:00", "2024-02-01 00:00:00"],
+            "values": {"revenue": [[1, 4], [2, null], [null, 3]]},
+            "truncated": true
+        })
+    );
+}
+
+#[test]
+fn invalid_pivots_are_rejected() {
+    let mut requests = Vec::new();
+    let mut request = pivot_request();
+    request["pivot"]["dimension"] = json!("revenue");
+    requests.push(request);
+    let mut request = pivot_request();
+    request["pivot"]["max_columns"] = json!(0);
+    requests.push(request);
+    let mut request = pivot_request();
+    request["page_size"] = json!(10);
+    requests.push(request);
+    let mut request = pivot_request();
+    request["metrics"] = json!([]);
+    requests.push(request);
+    for request in requests {
+        assert!(
+            matches!(
+                build("postgres", request.clone()),
+                Err(QueryBuildError::InvalidPivot(_))
+            ),
+            "{}",
+            request
+        );
+    }
+}
+
+#[test]
+fn timezone_sql_in_every_dialect() {
+    //02:30 on the 31st of march doesn't exist in Berlin and on the 27th of october it exists twice
+    let request = json!({
+        "metrics": [{"field": "orders.id", "aggregate_operator": "count", "name": "orders"}],
+        "dimensions": [
+            {"field": "orders.created_at", "transformation": "date_trunc_day", "name": "day"},
+            {"field": "orders.created_at", "name": "created_at"}
+        ],
+        "filters": [
+            {"dimension": {"field": "orders.created_at"}, "filter_operator": ">=", "filter_value": "2024-03-31 02:30:00"},
+            {"dimension": {"field": "orders.created_at"}, "filter_operator": "<", "filter_value": "2024-10-27 02:30:00"}
+        ],
+        "timezone": "Europe/Berlin"
+    });
+    assert_sql(
+        request.clone(),
+        [
+            r#"select DATE_TRUNC('day', (("orders"."created_at" AT TIME ZONE 'UTC') AT TIME ZONE 'Europe/Berlin')) as "day", "orders"."created_at" as "created_at", COUNT("orders"."id") as "orders" from "orders" where ("orders"."created_at" >= $1 and "orders"."created_at" < $2) group by DATE_TRUNC('day', (("orders"."created_at" AT TIME ZONE 'UTC') AT TIME ZONE 'Europe/Berlin')), "orders"."created_at"    ;"#,
+            r#"select CAST(DATE(CONVERT_TZ(`orders`.`created_at`, '+00:00', 'Europe/Berlin')) AS DATETIME) as `day`, `orders`.`created_at` as `created_at`, COUNT(`orders`.`id`) as `orders` from `orders` where (`orders`.`created_at` >= ? and `orders`.`created_at` < ?) group by CAST(DATE(CONVERT_TZ(`orders`.`created_at`, '+00:00', 'Europe/Berlin')) AS DATETIME), `orders`.`created_at`    ;"#,
+            r#"select CAST(toString(`column_1`) AS Nullable(String)), CAST(toString(`column_2`) AS Nullable(String)), `column_3` from (select toStartOfDay(toTimeZone(`orders`.`created_at`, 'Europe/Berlin')) as `column_1`, `orders`.`created_at` as `column_2`, COUNT(`orders`.`id`) as `column_3` from `orders` where (`orders`.`created_at` >= toDateTime(?, 'UTC') and `orders`.`created_at` < toDateTime(?, 'UTC')) group by toStartOfDay(toTimeZone(`orders`.`created_at`, 'Europe/Berlin')), `orders`.`created_at`   ) as result_rows ;"#,
+        ],
+    );
+    //the time in the gap moves past it, the one in the fold is the first of the two
+    for db_type in DIALECTS {
+        let params = query(db_type, request.clone()).params;
+        assert!(
+            matches!(
+                params.as_slice(),
+                [SqlParam::String(start), SqlParam::String(end)]
+                    if start == "2024-03-31 01:30:00" && end == "2024-10-27 00:30:00"
+            ),
+            "{}: {:?}",
+            db_type,
+            params
+        );
+    }
+}
+
+// the hour buckets and raw timestamps of the rows, as localize_timestamps gives them back
+fn localized(rows: &[(&str, &str)]) -> Vec<(String, String)> {
+    let request = json!({
+        "metrics": [],
+        "dimensions": [
+            {"field": "orders.created_at", "transformation": "date_trunc_hour", "name": "hour"},
+            {"field": "orders.created_at", "name": "created_at"}
+        ],
+        "timezone": "Europe/Berlin"
+    });
+    let sql_query = query("postgres", request.clone());
+    let data_request: DataRequest = serde_json::from_value(request).unwrap();
+    let rows = rows
+        .iter()
+        .map(|(hour, created_at)| {
+            [
+                ("hour".to_string(), AttributeValue::Timestamp(hour.to_string())),
+                ("created_at".to_string(), AttributeValue::Timestamp(created_at.to_string())),
+            ]
+            .into_iter()
+            .collect()
+        })
+        .collect();
+    let response = localize_timestamps(&data_request, &sql_query.columns, DataResponse::new(rows));
+    let text = |value: Option<&AttributeValue>| match value {
+        Some(AttributeValue::Timestamp(text)) => text.clone(),
+        value => panic!("not a timestamp: {:?}", value),
+    };
+    response
+        .data
+        .iter()
+        .map(|row| (text(row.get("hour")), text(row.get("created_at"))))
+        .collect()
+}
+
+#[test]
+fn localized_timestamps_get_the_offset_of_the_request_time_zone() {
+    //buckets are berlin wall clock times, raw timestamps are stored in utc
+    assert_eq!(
+        localized(&[
+            ("2024-01-15 09:00:00", "2024-01-15 08:30:00"),
+            ("2024-07-15 09:00:00", "2024-07-15 07:30:00.250"),
+            ("2024-07-15 09:00:00", "2024-07-15T07:30:00Z"),
+        ]),
+        [
+            ("2024-01-15T09:00:00+01:00", "2024-01-15T09:30:00+01:00"),
+            ("2024-07-15T09:00:00+02:00", "2024-07-15T09:30:00.250+02:00"),
+            ("2024-07-15T09:00:00+02:00", "2024-07-15T09:30:00+02:00"),
+        ]
+        .map(|(hour, created_at)| (hour.to_string(), created_at.to_string()))
+    );
+}
+
+#[test]
+fn localized_timestamps_across_dst_changes() {
+    assert_eq!(
+        localized(&[
+            //02:30 doesn't exist on the day clocks go forward, it's read an hour later
+            ("2024-03-31 02:30:00", "2024-03-31 00:30:00"),
+            ("2024-03-31 03:00:00", "2024-03-31 01:30:00"),
+            //02:30 exists twice on the day clocks go back, a bucket is the first of them
+            ("2024-10-27 02:30:00", "2024-10-27 00:30:00"),
+            ("2024-10-27 02:30:00", "2024-10-27 01:30:00"),
+        ]),
+        [
+            ("2024-03-31T03:30:00+02:00", "2024-03-31T01:30:00+01:00"),
+            ("2024-03-31T03:00:00+02:00", "2024-03-31T03:30:00+02:00"),
+            ("2024-10-27T02:30:00+02:00", "2024-10-27T02:30:00+02:00"),
+            ("2024-10-27T02:30:00+02:00", "2024-10-27T02:30:00+01:00"),
+        ]
+        .map(|(hour, created_at)| (hour.to_string(), created_at.to_string()))
+    );
+}
+
+#[test]
+fn batch_sql_in_every_dialect() {
+    //the widgets of a dashboard page, each query is built on its own as if sent to /api
+    let batch: BatchRequest = serde_json::from_value(json!({
+        "queries": [
+            {"id": "revenue", "query": {
+                "metrics": [{"field": "orders.total", "aggregate_operator": "sum", "name": "revenue"}],
+                "dimensions": [],
+                "filters": [{"dimension": {"field": "orders.created_at"}, "filter_operator": ">=", "filter_value": "2024-01-01"}]
+            }},
+            {"id": "top_categories", "query": {
+                "metrics": [{"field": "orders.id", "aggregate_operator": "count", "name": "orders"}],
+                "dimensions": [{"field": "products.category", "name": "category"}],
+                "orderby": [{"field": "orders", "order": "desc"}],
+                "limit": 5
+            }},
+            {"id": "broken", "query": {
+                "metrics": [{"field": "orders.missing", "aggregate_operator": "sum", "name": "missing"}],
+                "dimensions": []
+            }}
+        ]
+    }))
+    .unwrap();
+    let expected = [
+        (
+            "revenue",
+            [
+                r#"select SUM("orders"."total") as "revenue" from "orders" where "orders"."created_at" >= $1     ;"#,
+                r#"select SUM(`orders`.`total`) as `revenue` from `orders` where `orders`.`created_at` >= ?     ;"#,
+                r#"select CAST(toString(`column_1`) AS Nullable(String)) from (select SUM(`orders`.`total`) as `column_1` from `orders` where `orders`.`created_at` >= ?    ) as result_rows ;"#,
+            ],
+        ),
+        (
+            "top_categories",
+            [
+                r#"select "products"."category" as "category", COUNT("orders"."id") as "orders" from "orders" JOIN "products" ON "orders"."product_id" = "products"."id"  group by "products"."category"  order by COUNT("orders"."id") desc LIMIT 5 ;"#,
+                r#"select `products`.`category` as `category`, COUNT(`orders`.`id`) as `orders` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id`  group by `products`.`category`  order by COUNT(`orders`.`id`) desc LIMIT 5 ;"#,
+                r#"select CAST(toString(`column_1`) AS Nullable(String)), `column_2` from (select `products`.`category` as `column_1`, COUNT(`orders`.`id`) as `column_2` from `orders` JOIN `products` ON `orders`.`product_id` = `products`.`id`  group by `products`.`category`  order by COUNT(`orders`.`id`) desc LIMIT 5) as result_rows ;"#,
+            ],
+        ),
+    ];
+    for (dialect, db_type) in DIALECTS.into_iter().enumerate() {
+        let results: Vec<(&str, Result<SqlQuery, QueryBuildError>)> = batch
+            .queries
+            .iter()
+            .map(|batch_query| {
+                let sql_query = get_query(&batch_query.query, &tables(), db_type);
+                (batch_query.id.as_str(), sql_query)
+            })
+            .collect();
+        assert_eq!(results.len(), 3, "{}", db_type);
+        for ((id, sql_query), (expected_id, expected)) in results.iter().zip(&expected) {
+            assert_eq!(id, expected_id, "{}", db_type);
+            assert_eq!(
+                sql_query.as_ref().unwrap().sql,
+                expected[dialect],
+                "{} [{}]",
+                id,
+                db_type
+            );
+        }
+        //a query that doesn't build doesn't take the others with it
+        assert!(
+            matches!(results[2], ("broken", Err(QueryBuildError::UnknownColumn(_)))),
+            "{}",
+            db_type
+        );
+    }
+}
+
+fn formula_request(formulas: serde_json::Value) -> serde_json::Value {
+    let mut metrics = vec![
+        json!({"field": "orders.total", "aggregate_operator": "sum", "name": "revenue"}),
+        json!({"field": "orders.id", "aggregate_operator": "count", "name": "orders"}),
+    ];
+    metrics.extend(formulas.as_array().unwrap().iter().cloned());
+    json!({"metrics": metrics})
+}
+
+#[test]
+fn formula_divisions_by_zero_give_null() {
+    let sql_query = query(
+        "postgres",
+        formula_request(json!([{"name": "average", "formula": "revenue / orders"}])),
+    );
+    assert!(
+        sql_query.sql.contains(
+            r#"(CAST(SUM("orders"."total") AS DOUBLE PRECISION) / NULLIF(COUNT("orders"."id"), 0))"#
+        ),
+        "{}",
+        sql_query.sql
+    );
+}
+
+#[test]
+fn formulas_referring_to_themselves_are_rejected() {
+    let cycles = [
+        json!([{"name": "a", "formula": "a + 1"}]),
+        json!([{"name": "a", "formula": "b * 2"}, {"name": "b", "formula": "revenue / a"}]),
+    ];
+    for formulas in cycles {
+        assert!(matches!(
+            build("postgres", formula_request(formulas)),
+            Err(QueryBuildError::InvalidFormula(message)) if message.contains("refers to itself")
+        ));
+    }
+}
+
+#[test]
+fn formula_length_and_nesting_are_capped() {
+    let nested = |depth: usize| format!("{}revenue{}", "(".repeat(depth), ")".repeat(depth));
+    let formulas = [
+        format!("{}revenue{}", "(".repeat(100_000), ")".repeat(100_000)),
+        format!("{}revenue", "-".repeat(100_000)),
+        nested(40),
+        format!("{}revenue", "-".repeat(40)),
+    ];
+    for formula in formulas {
+        assert!(matches!(
+            build("postgres", formula_request(json!([{"name": "deep", "formula": formula}]))),
+            Err(QueryBuildError::InvalidFormula(_))
+        ));
+    }
+    //formulas referring to formulas add up their nesting
+    let chained = json!([
+        {"name": "inner", "formula": nested(20)},
+        {"name": "outer", "formula": format!("{}inner{}", "(".repeat(20), ")".repeat(20))}
+    ]);
+    assert!(matches!(
+        build("postgres", formula_request(chained)),
+        Err(QueryBuildError::InvalidFormula(_))
+    ));
+    assert!(build(
+        "postgres",
+        formula_request(json!([{"name": "deep", "formula": nested(20)}]))
+    )
+    .is_ok());
+}
+
+#[test]
+fn formulas_mixing_aggregates_and_plain_columns_are_rejected() {
+    let plain = json!({"field": "orders.total", "name": "amount"});
+    let mixed = [
+        json!([plain, {"name": "share", "formula": "revenue / amount"}]),
+        json!([
+            plain,
+            {"name": "double_amount", "formula": "amount * 2"},
+            {"name": "share", "formula": "revenue / double_amount"}
+        ]),
+    ];
+    for formulas in mixed {
+        assert!(matches!(
+            build("postgres", formula_request(formulas)),
+            Err(QueryBuildError::InvalidFormula(message)) if message.contains("'share' mixes")
+        ));
+    }
+    let constant = json!([
+        {"name": "two", "formula": "1 + 1"},
+        {"name": "doubled", "formula": "revenue * two"}
+    ]);
+    assert!(build("postgres", formula_request(constant)).is_ok());
+}
+
+#[test]
+fn sums_of_integers_stay_exact() {
+    //orders.id is an int, orders.product_id a bigint
+    let request = json!({
+        "metrics": [
+            {"field": "orders.id", "aggregate_operator": "sum", "name": "ids"},
+            {"field": "orders.product_id", "aggregate_operator": "sum", "name": "products"},
+            {"field": "orders.id", "aggregate_operator": "avg", "name": "average"}
+        ]
+    });
+    let expected = [
+        ("postgres", ColumnType::Integer, ColumnType::Decimal),
+        ("mysql", ColumnType::Decimal, ColumnType::Decimal),
+        ("clickhouse", ColumnType::Integer, ColumnType::Integer),
+    ];
+    for (db_type, ids_type, products_type) in expected {
+        let sql_query = query(db_type, request.clone());
+        let data_request: DataRequest = serde_json::from_value(request.clone()).unwrap();
+        //what the drivers return for values past the exact range of a double
+        let row = [
+            ("ids".to_string(), AttributeValue::Int(9_007_199_254_740_993)),
+            (
+                "products".to_string(),
+                AttributeValue::Decimal("9007199254740993".to_string()),
+            ),
+            ("average".to_string(), AttributeValue::Decimal("2.5".to_string())),
+        ]
+        .into_iter()
+        .collect();
+        let mut response = DataResponse::new(vec![row]);
+        describe_columns(&data_request, &sql_query.columns, &mut response);
+        let types: Vec<ColumnType> = response
+            .columns
+            .iter()
+            .map(|column| column.datatype)
+            .collect();
+        assert_eq!(
+            types,
+            [ids_type, products_type, ColumnType::Float],
+            "[{}]",
+            db_type
+        );
+        for name in ["ids", "products"] {
+            let text = match &response.data[0][name] {
+                AttributeValue::Int(number) => number.to_string(),
+                AttributeValue::Decimal(text) => text.clone(),
+                value => panic!("[{}] {} is {:?}", db_type, name, value),
+            };
+            assert_eq!(text, "9007199254740993", "[{}] {}", db_type, name);
+        }
+    }
+}
+
+#[test]
+fn cursor_pages_need_dimensions() {
+    //raw rows ordered by a column that isn't unique
+    let request = json!({
+        "metrics": [{"field": "orders.total"}, {"field": "orders.created_at"}],
+        "orderby": [{"field": "orders.created_at", "order": "asc"}],
+        "page_size": 10,
+        "cursor": ""
+    });
+    assert!(matches!(
+        build("postgres", request),
+        Err(QueryBuildError::InvalidPagination(_))
+    ));
+}
+
+#[test]
+fn decimal_cursor_values_stay_exact() {
+    //more digits than a double holds
+    let amount = "12345678901234567.0000000001";
+    let mut request = json!({
+        "metrics": [{"field": "orders.amount", "aggregate_operator": "sum", "name": "revenue"}],
+        "dimensions": [{"field": "products.category"}],
+        "orderby": [{"field": "revenue", "order": "desc"}],
+        "page_size": 1,
+        "cursor": ""
+    });
+    let data_request: DataRequest = serde_json::from_value(request.clone()).unwrap();
+    let row = |revenue: &str, category: &str| {
+        [
+            ("revenue".to_string(), AttributeValue::Decimal(revenue.to_string())),
+            (
+                "products.category".to_string(),
+                AttributeValue::String(category.to_string()),
+            ),
+        ]
+        .into_iter()
+        .collect()
+    };
+    let response = paginate(
+        &data_request,
+        DataResponse::new(vec![row(amount, "a"), row("1.5", "b")]),
+    );
+    request["cursor"] = json!(response.next_cursor.unwrap());
+
+    let casts = [
+        ("postgres", r#"SUM("orders"."amount") < CAST($1 AS NUMERIC)"#),
+        ("mysql", "SUM(`orders`.`amount`) < CAST(? AS DECIMAL(65, 30))"),
+        ("clickhouse", "SUM(`orders`.`amount`) < toDecimal256(?, 30)"),
+    ];
+    for (db_type, comparison) in casts {
+        let sql_query = query(db_type, request.clone());
+        assert!(
+            sql_query.sql.contains(comparison),
+            "[{}] {}",
+            db_type,
+            sql_query.sql
+        );
+        assert!(
+            matches!(sql_query.params.first(), Some(SqlParam::String(value)) if value == amount),
+            "[{}] {:?}",
+            db_type,
+            sql_query.params
+        );
+    }
+}
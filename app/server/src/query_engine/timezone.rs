@@ -0,0 +1,137 @@
+use super::{temporal_kind, QueryBuildError, QueryParams, TemporalKind};
+use crate::models::{AttributeValue, DataRequest, DataResponse, ResultColumn, SqlParam};
+use chrono::{DateTime, Duration, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+
+const NAIVE_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+pub fn parse_timezone(name: &str) -> Result<Tz, QueryBuildError> {
+    name.parse::<Tz>()
+        .map_err(|_| QueryBuildError::InvalidTimezone(name.to_string()))
+}
+
+// what a timestamp expression holds once a request time zone is set
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeBasis {
+    //the stored value, timestamps without a zone are utc
+    Stored { with_time_zone: bool },
+    //wall clock time of the request's time zone
+    Local,
+}
+
+// binds a user supplied timestamp against an expression of the given basis,
+// None if the value isn't a date or timestamp
+pub(super) fn bind_timestamp(
+    value: &str,
+    timezone: Tz,
+    basis: TimeBasis,
+    params: &mut QueryParams,
+) -> Option<String> {
+    let instant = parse_user_timestamp(value, timezone)?;
+    let sql = match basis {
+        TimeBasis::Stored { with_time_zone } => {
+            let utc = instant.with_timezone(&Utc).format(NAIVE_FORMAT).to_string();
+            let placeholder = params.bind(SqlParam::String(utc));
+            params.dialect.utc_timestamp(&placeholder, with_time_zone)
+        }
+        TimeBasis::Local => {
+            let local = instant.naive_local().format(NAIVE_FORMAT).to_string();
+            params.bind(SqlParam::String(local))
+        }
+    };
+    Some(sql)
+}
+
+// with an offset the value is an instant, otherwise a wall clock time of the request's zone
+fn parse_user_timestamp(value: &str, timezone: Tz) -> Option<DateTime<Tz>> {
+    let value = value.trim();
+    if let Ok(instant) = DateTime::parse_from_rfc3339(value) {
+        return Some(instant.with_timezone(&timezone));
+    }
+    local_to_instant(parse_naive(value)?, timezone)
+}
+
+pub(super) fn parse_naive(value: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f"))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+}
+
+//wall clock times repeated by a dst change take the first one, skipped ones move past the gap
+pub(super) fn local_to_instant(naive: NaiveDateTime, timezone: Tz) -> Option<DateTime<Tz>> {
+    match timezone.from_local_datetime(&naive) {
+        LocalResult::Single(instant) => Some(instant),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest),
+        LocalResult::None => timezone
+            .from_local_datetime(&(naive + Duration::hours(1)))
+            .earliest(),
+    }
+}
+
+// formats the timestamp columns of a response as ISO-8601 with the offset of the request's time zone
+pub fn localize_timestamps(
+    query: &DataRequest,
+    columns: &[ResultColumn],
+    mut response: DataResponse,
+) -> DataResponse {
+    if let Some(localize_row) = row_localizer(query, columns) {
+        response.data.iter_mut().for_each(localize_row);
+    }
+    response
+}
+
+// what localize_timestamps does to every row, for rows that are handled one at a time. None when
+// the request has no time zone or no timestamp columns
+pub fn row_localizer<'a>(
+    query: &DataRequest,
+    columns: &'a [ResultColumn],
+) -> Option<impl Fn(&mut HashMap<String, AttributeValue>) + 'a> {
+    let timezone = match query.timezone.as_deref().map(parse_timezone) {
+        Some(Ok(timezone)) => timezone,
+        _ => return None,
+    };
+    let timestamp_columns: Vec<&ResultColumn> = columns
+        .iter()
+        .filter(|column| {
+            matches!(
+                temporal_kind(&column.datatype),
+                Some(TemporalKind::Timestamp | TemporalKind::TimestampTz)
+            )
+        })
+        .collect();
+    if timestamp_columns.is_empty() {
+        return None;
+    }
+    Some(move |row: &mut HashMap<String, AttributeValue>| {
+        for column in &timestamp_columns {
+            if let Some(AttributeValue::String(value) | AttributeValue::Timestamp(value)) =
+                row.get_mut(&column.name)
+            {
+                if let Some(localized) = localize(value, timezone, column.local_time) {
+                    *value = localized;
+                }
+            }
+        }
+    })
+}
+
+fn localize(value: &str, timezone: Tz, local_time: bool) -> Option<String> {
+    let instant = match DateTime::parse_from_rfc3339(value) {
+        Ok(instant) => instant.with_timezone(&timezone),
+        Err(_) => {
+            let naive = parse_naive(value)?;
+            if local_time {
+                local_to_instant(naive, timezone)?
+            } else {
+                Utc.from_utc_datetime(&naive).with_timezone(&timezone)
+            }
+        }
+    };
+    Some(instant.format("%Y-%m-%dT%H:%M:%S%.f%:z").to_string())
+}
@@ -0,0 +1,90 @@
+use super::dialect::{CastType, Dialect, NullsPosition};
+use super::formula::formula_to_sql;
+use super::{metric_to_sql, DateSettings, QueryBuildError};
+use crate::models::{Metric, TopN};
+use std::collections::HashMap;
+
+// `LEFT JOIN (select <dimension> as value, 1 as ranked .. limit <count>) as <ranked_table> ON ..`,
+// the dimension's label, the ranked values as text and `other_label` for the rest, and the flag
+// of the ranked values. Grouping by the flag as well keeps a ranked value equal to `other_label`
+// apart from the rest. A null can be one of the ranked values, it is matched null safe and
+// stays null. `source_sql` is the from and where clause the values are ranked over
+pub(super) fn top_n_to_sql(
+    top_n: &TopN,
+    field: &str,
+    dimension_sql: &str,
+    ranking_sql: &str,
+    source_sql: &str,
+    ranked_table: &str,
+    dialect: &dyn Dialect,
+) -> Result<(String, String, String), QueryBuildError> {
+    if top_n.count == 0 {
+        return Err(QueryBuildError::InvalidTopN(format!(
+            "count has to be at least 1 (dimension '{}')",
+            field
+        )));
+    }
+    let table = dialect.quote_identifier(ranked_table);
+    let value = dialect.quote_identifier("value");
+    //clickhouse fills the columns of unmatched rows with defaults rather than nulls
+    let ranked = dialect.quote_identifier("ranked");
+    //ties are broken by the value so that the same values make the cut every time
+    let order_sql = [
+        dialect.order_by(ranking_sql, true, Some(NullsPosition::Last)),
+        dialect.order_by(dimension_sql, false, None),
+    ];
+    let join_sql = format!(
+        "LEFT JOIN (select {} as {}, 1 as {} from {} group by {} order by {} {}) as {} ON {}",
+        dimension_sql,
+        value,
+        ranked,
+        source_sql,
+        dimension_sql,
+        order_sql.join(", "),
+        dialect.limit_offset(Some(i64::from(top_n.count)), None),
+        table,
+        dialect.null_safe_equal(dimension_sql, &format!("{}.{}", table, value))
+    );
+    let ranked_sql = format!("{}.{}", table, ranked);
+    let label_sql = format!(
+        "CASE WHEN {} = 1 THEN {} ELSE {} END",
+        ranked_sql,
+        dialect.cast(dimension_sql, CastType::Text),
+        dialect.quote_literal(&top_n.other_label)
+    );
+    Ok((join_sql, label_sql, ranked_sql))
+}
+
+// the aggregate the values are ranked by, `by` names a metric of the request
+pub(super) fn ranking_sql(
+    top_n: &TopN,
+    metrics: &[Metric],
+    field_datatype_map: &HashMap<&String, &str>,
+    dates: &DateSettings,
+    dialect: &dyn Dialect,
+) -> Result<String, QueryBuildError> {
+    let metric = metrics
+        .iter()
+        .find(|metric| metric.name.as_ref().unwrap_or(&metric.field) == &top_n.by)
+        .ok_or_else(|| QueryBuildError::UnknownMetric(top_n.by.clone()))?;
+    let invalid = |message: &str| {
+        Err(QueryBuildError::InvalidTopN(format!(
+            "'{}' {}",
+            top_n.by, message
+        )))
+    };
+    if metric.window.is_some() {
+        return invalid("is a window metric, values can't be ranked by it");
+    }
+    let (sql, aggregate) = match &metric.formula {
+        Some(_) => formula_to_sql(metric, metrics, field_datatype_map, dates, dialect)?,
+        None => (
+            metric_to_sql(metric, field_datatype_map, dates, dialect)?,
+            metric.aggregate_operator.is_some(),
+        ),
+    };
+    if !aggregate {
+        return invalid("has no aggregate_operator, values can't be ranked by it");
+    }
+    Ok(sql)
+}
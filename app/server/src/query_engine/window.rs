@@ -0,0 +1,109 @@
+use super::dialect::{Dialect, NullsPosition};
+use super::{QueryBuildError, SelectColumn};
+use crate::models::{Metric, Nulls, Order, Window, WindowType};
+
+// the window expression of a metric, `expression_sql` is the metric without its window.
+// partition_by and order_by refer to the other selected columns by header
+pub(super) fn window_to_sql(
+    metric: &Metric,
+    window: &Window,
+    expression_sql: &str,
+    columns: &[SelectColumn],
+    dialect: &dyn Dialect,
+) -> Result<String, QueryBuildError> {
+    let header = metric.name.as_ref().unwrap_or(&metric.field);
+    let invalid =
+        |message: String| QueryBuildError::InvalidWindow(format!("{} (metric '{}')", message, header));
+    let column = |field: &String| {
+        columns
+            .iter()
+            .find(|column| !column.window && column.header == *field)
+            .ok_or_else(|| invalid(format!("'{}' is not a selected dimension or metric", field)))
+    };
+
+    let partition_by = window
+        .partition_by
+        .iter()
+        .map(|field| column(field).map(|column| column.sql.clone()))
+        .collect::<Result<Vec<String>, _>>()?;
+    let mut order_by = Vec::new();
+    for order in &window.order_by {
+        let nulls = order.nulls.map(|nulls| match nulls {
+            Nulls::First => NullsPosition::First,
+            Nulls::Last => NullsPosition::Last,
+        });
+        //binned dimensions are ordered by their bins rather than their labels
+        let column = column(&order.field)?;
+        order_by.push(dialect.order_by(
+            column.sort_sql.as_ref().unwrap_or(&column.sql),
+            order.order == Order::desc,
+            nulls,
+        ));
+    }
+    let ordered = matches!(
+        window.window_type,
+        WindowType::RunningTotal | WindowType::MovingAverage
+    );
+    if ordered && order_by.is_empty() {
+        return Err(invalid("order_by is required for the window".to_string()));
+    }
+    if window.size.is_some() && window.window_type != WindowType::MovingAverage {
+        return Err(invalid("size only applies to moving_average".to_string()));
+    }
+
+    let window_sql = match window.window_type {
+        WindowType::RunningTotal => {
+            let function = format!("SUM({})", expression_sql);
+            let frame = "ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW";
+            dialect.window(&function, &partition_by, &order_by, Some(frame))
+        }
+        WindowType::Rank | WindowType::DenseRank => {
+            //ranks the biggest value first unless told otherwise
+            if order_by.is_empty() {
+                order_by.push(dialect.order_by(expression_sql, true, None));
+            }
+            let function = match window.window_type {
+                WindowType::Rank => "RANK()",
+                _ => "DENSE_RANK()",
+            };
+            dialect.window(function, &partition_by, &order_by, None)
+        }
+        WindowType::MovingAverage => {
+            let size = match window.size {
+                Some(size) if size >= 1 => size,
+                _ => {
+                    return Err(invalid(
+                        "moving_average needs a size of at least 1".to_string(),
+                    ))
+                }
+            };
+            let function = format!("AVG({})", expression_sql);
+            let frame = format!("ROWS BETWEEN {} PRECEDING AND CURRENT ROW", size - 1);
+            dialect.window(&function, &partition_by, &order_by, Some(&frame))
+        }
+        WindowType::PercentOfTotal => {
+            if !order_by.is_empty() {
+                return Err(invalid(
+                    "percent_of_total doesn't take an order_by".to_string(),
+                ));
+            }
+            let total = dialect.window(
+                &format!("SUM({})", expression_sql),
+                &partition_by,
+                &[],
+                None,
+            );
+            format!("{} * 100.0 / NULLIF({}, 0)", expression_sql, total)
+        }
+    };
+    Ok(window_sql)
+}
+
+// datatype of a window metric's value, `datatype` is the one of the metric without its window
+pub(super) fn window_datatype(window: &Window, datatype: &str) -> String {
+    match window.window_type {
+        WindowType::RunningTotal => datatype.to_string(),
+        WindowType::Rank | WindowType::DenseRank => "bigint".to_string(),
+        WindowType::MovingAverage | WindowType::PercentOfTotal => "double".to_string(),
+    }
+}